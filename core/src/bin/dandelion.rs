@@ -1,21 +1,63 @@
-use anyhow::Context;
-use dandelion::{config::Engine, Result};
+use anyhow::{anyhow, bail, ensure, Context};
+use dandelion::{
+    config::{Engine, Worker},
+    Result,
+};
 use std::{
     env,
-    fs::read_to_string,
+    fs::metadata,
     path::{Path, PathBuf},
+    pin::pin,
+    rc::Rc,
+    thread,
+    time::Duration,
 };
 use structopt::StructOpt;
+use tokio::{
+    runtime,
+    sync::broadcast::{self, error::RecvError, Receiver, Sender},
+    task::LocalSet,
+};
+use tracing::{error, info};
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "dandelion", about = "CLI version of the dandelion client")]
 struct Opt {
     #[structopt(parse(from_os_str))]
     input: Option<PathBuf>,
+
+    /// Directories to look up config modules in when they aren't next to the
+    /// config file, before `$SNAP_COMMON` and `$HOME/.dandelion`.
+    #[structopt(long, parse(from_os_str))]
+    include: Vec<PathBuf>,
+
+    /// Reload the config when the file changes. It's also reloaded on SIGHUP
+    /// and from the admin API.
+    #[structopt(long)]
+    watch: bool,
+
+    /// Number of threads running the engine. Each one loads the config on its
+    /// own and they accept on the same ports with SO_REUSEPORT.
+    #[structopt(long, default_value = "1")]
+    workers: usize,
+}
+
+#[derive(Debug, Clone)]
+struct ConfigFile {
+    path: PathBuf,
+    include: Vec<PathBuf>,
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
+// Sent to every worker.
+#[derive(Debug, Clone, Copy)]
+enum Control {
+    Reload,
+    Shutdown,
+}
+
+fn main() -> Result<()> {
     flexi_logger::Logger::try_with_env_or_str("warn,dandelion_core=info,dandelion_config=info")
         .unwrap()
         .start()
@@ -24,7 +66,7 @@ async fn main() -> Result<()> {
     #[cfg(not(target_os = "windows"))]
     {
         use fdlimit::{raise_fd_limit, Outcome};
-        use tracing::{info, warn};
+        use tracing::warn;
 
         match raise_fd_limit() {
             Ok(Outcome::LimitRaised { to, from: _ }) => info!("Raised fd limit to {}", to),
@@ -38,22 +80,223 @@ async fn main() -> Result<()> {
         .map_err(|_| anyhow::anyhow!("Failed to install aws lc provider"))?;
 
     let opt: Opt = Opt::from_args();
+    ensure!(opt.workers > 0, "There must be at least one worker");
+    let config = ConfigFile {
+        path: config_path(&opt)?,
+        include: include_dirs(&opt),
+    };
+
+    let (control, _) = broadcast::channel(16);
+    let workers = (1..opt.workers)
+        .map(|index| {
+            let worker = Worker {
+                index,
+                count: opt.workers,
+            };
+            let (config, control, receiver) =
+                (config.clone(), control.clone(), control.subscribe());
+
+            thread::Builder::new()
+                .name(format!("worker-{}", index))
+                .spawn(move || run_worker(worker, config, false, control, receiver))
+                .context("Failed to start worker thread")
+        })
+        .collect::<Result<Vec<_>>>()?;
 
-    fn load_config_from_env(env: &str, path: &str) -> Result<String> {
-        Ok(read_to_string(Path::new(&env::var(env)?).join(path))?)
+    // Signals and file changes are handled by the primary worker, on this
+    // thread.
+    let receiver = control.subscribe();
+    let primary = Worker {
+        index: 0,
+        count: opt.workers,
+    };
+    let mut result = run_worker(primary, config, opt.watch, control, receiver);
+
+    for worker in workers {
+        let joined = worker
+            .join()
+            .unwrap_or_else(|_| Err(anyhow!("Worker thread panicked")));
+        result = result.and(joined);
     }
 
-    let code = match opt.input {
-        Some(path) => read_to_string(&path)
-            .with_context(|| format!("Failed to load config file {}", path.to_string_lossy()))?,
-        None => load_config_from_env("SNAP_COMMON", "./config.rn")
-            .or_else(|_| load_config_from_env("HOME", "./.dandelion/config.rn"))
-            .context(
-                "Failed to load config from $SNAP_COMMON/config.rn or $HOME/.dandelion/config.rn",
-            )?,
+    result
+}
+
+fn run_worker(
+    worker: Worker,
+    config: ConfigFile,
+    watching: bool,
+    control: Sender<Control>,
+    mut receiver: Receiver<Control>,
+) -> Result<()> {
+    worker.enter();
+
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    // Connections, and everything spawned by the config, are tasks local to
+    // this thread since Rune values are not `Send`.
+    let result = runtime.block_on(LocalSet::new().run_until(async {
+        let engine = Rc::new(Engine::load_config_file(&config.path, &config.include).await?);
+
+        // Reloads asked for from the admin API go to every worker.
+        let reload = engine.reload_signal();
+        let reloading = control.clone();
+        tokio::task::spawn_local(async move {
+            loop {
+                reload.notified().await;
+                let _ = reloading.send(Control::Reload);
+            }
+        });
+
+        if worker.is_primary() {
+            handle_signals(control.clone())?;
+
+            if watching {
+                tokio::task::spawn_local(watch(engine.clone(), control.clone()));
+            }
+        }
+
+        let mut running = pin!(engine.clone().run());
+
+        loop {
+            tokio::select! {
+                // A listener failing exits right away, a signal drains first.
+                result = &mut running => return result,
+                command = receiver.recv() => match command {
+                    Ok(Control::Reload) => {
+                        info!("Reloading config from {}", config.path.display());
+
+                        match engine.reload_file(&config.path, &config.include).await {
+                            Ok(()) => info!("Reloaded config"),
+                            Err(err) => error!(
+                                "Failed to reload config, keeping the current one: {:?}",
+                                err
+                            ),
+                        }
+                    }
+                    Ok(Control::Shutdown) | Err(RecvError::Closed) => {
+                        if !engine.shutdown().await {
+                            bail!("Some connections were closed before they finished");
+                        }
+
+                        info!("All connections finished");
+                        return Ok(());
+                    }
+                    // Only the oldest commands are dropped, a shutdown is never
+                    // missed.
+                    Err(RecvError::Lagged(_)) => {}
+                },
+            }
+        }
+    }));
+
+    // One worker stopping takes the others down with it.
+    if result.is_err() {
+        let _ = control.send(Control::Shutdown);
+    }
+
+    result.with_context(|| format!("Worker {} failed", worker.index))
+}
+
+fn handle_signals(control: Sender<Control>) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        let reload = control.clone();
+        tokio::task::spawn_local(async move {
+            while hangup.recv().await.is_some() {
+                info!("Received SIGHUP");
+                let _ = reload.send(Control::Reload);
+            }
+        });
+    }
+
+    tokio::task::spawn_local(async move {
+        match shutdown_signal().await {
+            Ok(signal) => info!("Received {}, shutting down", signal),
+            Err(err) => error!("Failed to wait for signals, shutting down: {:?}", err),
+        }
+
+        let _ = control.send(Control::Shutdown);
+    });
+
+    Ok(())
+}
+
+async fn shutdown_signal() -> Result<&'static str> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result.map(|_| "SIGINT"),
+            _ = terminate.recv() => Ok("SIGTERM"),
+        }
+        .map_err(Into::into)
+    }
+
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await?;
+        Ok("Ctrl-C")
+    }
+}
+
+// Polls the modification times, which works the same on every platform. The
+// modules loaded by the config are watched as well.
+async fn watch(engine: Rc<Engine>, control: Sender<Control>) {
+    let modified = |path: &Path| metadata(path).and_then(|m| m.modified()).ok();
+    let snapshot = || {
+        engine
+            .config_files()
+            .into_iter()
+            .map(|path| {
+                let modified = modified(&path);
+                (path, modified)
+            })
+            .collect::<Vec<_>>()
     };
 
-    let engine = Engine::load_config(code).await?;
+    let mut last = snapshot();
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Some((path, _)) = last.iter().find(|(path, last)| modified(path) != *last) {
+            info!("{} changed", path.display());
+            let _ = control.send(Control::Reload);
+        }
+        last = snapshot();
+    }
+}
+
+fn config_path(opt: &Opt) -> Result<PathBuf> {
+    fn path_from_env(env: &str, path: &str) -> Option<PathBuf> {
+        Some(Path::new(&env::var(env).ok()?).join(path)).filter(|path| path.is_file())
+    }
+
+    match &opt.input {
+        Some(path) => Ok(path.clone()),
+        None => path_from_env("SNAP_COMMON", "./config.rn")
+            .or_else(|| path_from_env("HOME", "./.dandelion/config.rn"))
+            .context(
+                "Failed to load config from $SNAP_COMMON/config.rn or $HOME/.dandelion/config.rn",
+            ),
+    }
+}
 
-    engine.run().await
+fn include_dirs(opt: &Opt) -> Vec<PathBuf> {
+    opt.include
+        .iter()
+        .cloned()
+        .chain(env::var_os("SNAP_COMMON").map(PathBuf::from))
+        .chain(env::var_os("HOME").map(|home| Path::new(&home).join(".dandelion")))
+        .filter(|dir| dir.is_dir())
+        .collect()
 }
@@ -0,0 +1,135 @@
+use super::{geoip::refresh_geoip, tun::fake_dns_mappings, Engine};
+use crate::{
+    core::connector::happy_eyeballs::{remembered_addresses, Transport},
+    Result,
+};
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{
+    body::Incoming,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    server::conn::http1::Builder,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use serde_json::json;
+use std::rc::Rc;
+use subtle::ConstantTimeEq;
+use tokio::net::TcpListener;
+
+fn respond(status: StatusCode) -> Result<Response<Full<Bytes>>> {
+    Ok(Response::builder().status(status).body(Full::default())?)
+}
+
+fn respond_json(status: StatusCode, body: &impl Serialize) -> Result<Response<Full<Bytes>>> {
+    Ok(Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::new(serde_json::to_vec(body)?.into()))?)
+}
+
+async fn handle(engine: &Engine, request: Request<Incoming>) -> Result<Response<Full<Bytes>>> {
+    // The token is read for every request so reloading the config changes it.
+    let authorized = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| {
+            engine
+                .loaded()
+                .admin
+                .as_ref()
+                // Constant time, so the token can't be guessed byte by byte
+                // from how long the comparison takes.
+                .is_some_and(|(_, wanted)| bool::from(token.as_bytes().ct_eq(wanted.as_bytes())))
+        });
+    if !authorized {
+        return respond(StatusCode::UNAUTHORIZED);
+    }
+
+    let path = request.uri().path();
+
+    match (request.method(), path) {
+        (&Method::GET, "/connections") => respond_json(StatusCode::OK, &engine.connections.list()),
+        (&Method::DELETE, _) if path.starts_with("/connections/") => {
+            match path["/connections/".len()..].parse() {
+                Ok(id) if engine.connections.close(id) => respond(StatusCode::NO_CONTENT),
+                _ => respond(StatusCode::NOT_FOUND),
+            }
+        }
+        // Hickory has no way to list what it has cached, so only the caches
+        // of our own are shown.
+        (&Method::GET, "/dns") => respond_json(
+            StatusCode::OK,
+            &json!({
+                "fake_dns": fake_dns_mappings()
+                    .into_iter()
+                    .map(|(name, ip)| json!({"name": name, "ip": ip}))
+                    .collect::<Vec<_>>(),
+                "last_working": remembered_addresses()
+                    .into_iter()
+                    .map(|(host, port, transport, resolver, ip, age)| {
+                        let transport = match transport {
+                            Transport::Tcp {
+                                interface,
+                                bind_ip,
+                                mark,
+                            } => json!({
+                                "tcp": {"interface": interface, "bind_ip": bind_ip, "mark": mark}
+                            }),
+                            Transport::Quic => json!("quic"),
+                        };
+
+                        json!({
+                            "host": host,
+                            "port": port,
+                            "transport": transport,
+                            "resolver": resolver,
+                            "ip": ip,
+                            "age_ms": age.as_millis() as u64,
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            }),
+        ),
+        (&Method::POST, "/reload") => {
+            engine.reload.notify_one();
+            respond(StatusCode::ACCEPTED)
+        }
+        (&Method::POST, "/geoip/refresh") => match refresh_geoip().await {
+            Ok(refreshed) => respond_json(StatusCode::OK, &json!({ "refreshed": refreshed })),
+            Err(e) => respond_json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &json!({ "error": format!("{:#}", e) }),
+            ),
+        },
+        _ => respond(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Serves the admin API, every request must carry `Authorization: Bearer <token>`.
+pub async fn serve(engine: Rc<Engine>, listener: Rc<TcpListener>) -> Result<()> {
+    loop {
+        let (io, _) = listener.accept().await?;
+
+        let engine = engine.clone();
+
+        tokio::task::spawn_local(async move {
+            if let Err(e) = Builder::new()
+                .serve_connection(
+                    TokioIo::new(io),
+                    service_fn(|request| {
+                        let engine = engine.clone();
+                        async move { handle(&engine, request).await }
+                    }),
+                )
+                .await
+            {
+                tracing::debug!("Failed to serve admin API: {:?}", e);
+            }
+        });
+    }
+}
@@ -1,36 +1,103 @@
+#[cfg(unix)]
+use crate::core::{
+    connector::ssh::{connect as ssh_connect, create_ssh_connection, SshConnection},
+    ssh::client::{Auth, Credential},
+};
 use crate::{
     core::{
+        acceptor::Rejection,
         connector::{
             block::connect as block_connect,
             http::connect as http_connect,
+            http2::{
+                connect as http2_connect, create_http2_connection, ConnectMethod, Http2Connection,
+            },
+            quic::datagram as quic_datagram,
             quic::{connect as quic_connect, create_quic_connection, QuicConnection},
             simplex::connect as simplex_connect,
-            socks5::connect as socks5_connect,
-            tcp::connect as tcp_connect,
+            socks5::{associate as socks5_associate, connect as socks5_connect},
+            speed::race as speed_race,
+            tcp::{
+                connect as tcp_connect, connect_with_options as tcp_connect_with_options,
+                Keepalive, TcpOptions as CoreTcpOptions,
+            },
             tls::connect as tls_connect,
+            udp::bind as udp_bind,
         },
+        datagram::Datagram,
         endpoint::Endpoint,
         io::Io,
+        quic::client::QuicOptions as CoreQuicOptions,
         simplex::Config,
     },
     Result,
 };
-use rune::{runtime::Ref, Any, Module, Value};
-use std::{fmt::Debug, net::IpAddr, rc::Rc};
+use anyhow::{ensure, Context};
+use rune::{
+    runtime::{Function, Future, Ref},
+    Any, Module, TypeHash, Value,
+};
+use std::{cell::RefCell, fmt::Debug, net::IpAddr, rc::Rc, time::Duration};
+use tokio::time::timeout;
 
 use crate::config::{engine::resolver::ResolverWrapper, rune::create_wrapper};
 
 create_wrapper!(IoWrapper, Io, Box);
+create_wrapper!(DatagramWrapper, Rc<dyn Datagram>);
 create_wrapper!(QuicConnectionWrapper, Rc<QuicConnection>);
-
-#[derive(Debug, Any)]
+create_wrapper!(Http2ConnectionWrapper, Rc<Http2Connection>);
+create_wrapper!(TcpOptions, CoreTcpOptions);
+create_wrapper!(QuicOptions, CoreQuicOptions);
+create_wrapper!(Reject, Rejection);
+#[cfg(unix)]
+create_wrapper!(SshConnectionWrapper, Rc<SshConnection>);
+#[cfg(unix)]
+create_wrapper!(SshAuth, Auth);
+
+#[derive(Debug, Clone, Any)]
 pub struct ConnectRequest {
     endpoint: Endpoint,
+    udp: bool,
+    // Shared by the clones so it can be read after the handler returns.
+    chain: Rc<RefCell<Option<String>>>,
 }
 
 impl ConnectRequest {
     pub fn new(endpoint: Endpoint) -> Self {
-        Self { endpoint }
+        Self {
+            endpoint,
+            udp: false,
+            chain: Rc::default(),
+        }
+    }
+
+    // The first packet of a UDP flow, the handler returns a datagram instead.
+    pub fn new_udp(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            udp: true,
+            chain: Rc::default(),
+        }
+    }
+
+    // Passes the request to a handler of the script. The request isn't `Send`,
+    // so it's called on this thread instead of with `async_send_call`.
+    pub async fn call(self, handler: &Function) -> Result<IoWrapper> {
+        let mut value: Value = handler.call((self,)).into_result()?;
+        if value.type_hash() == Future::HASH {
+            value = value.into_future()?.await.into_result()?;
+        }
+
+        rune::from_value::<Result<IoWrapper>>(value)?
+    }
+
+    // How the handler described the route it picked, for the access log.
+    pub fn chain(&self) -> Option<String> {
+        self.chain.borrow().clone()
+    }
+
+    pub fn target(&self) -> &Endpoint {
+        &self.endpoint
     }
 }
 
@@ -41,11 +108,117 @@ pub async fn new_tcp(endpoint: Ref<str>, resolver: ResolverWrapper) -> Result<Io
         .into())
 }
 
+#[rune::function(path = new_tcp_with_options_async)]
+pub async fn new_tcp_with_options(
+    endpoint: Ref<str>,
+    resolver: ResolverWrapper,
+    options: Ref<TcpOptions>,
+) -> Result<IoWrapper> {
+    Ok(
+        tcp_connect_with_options(&endpoint.parse()?, resolver.into_inner(), options.inner())
+            .await?
+            .into(),
+    )
+}
+
+impl TcpOptions {
+    #[rune::function(path = Self::new)]
+    pub fn new() -> Self {
+        CoreTcpOptions::default().into()
+    }
+
+    #[rune::function]
+    pub fn set_interface(&mut self, interface: &str) {
+        self.0.interface = Some(interface.to_owned());
+    }
+
+    #[rune::function]
+    pub fn set_bind_ip(&mut self, ip: &str) -> Result<()> {
+        self.0.bind_ip = Some(ip.parse()?);
+
+        Ok(())
+    }
+
+    #[rune::function]
+    pub fn set_mark(&mut self, mark: u32) {
+        self.0.mark = Some(mark);
+    }
+
+    #[rune::function]
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.0.nodelay = nodelay;
+    }
+
+    #[rune::function]
+    pub fn set_keepalive(&mut self, time_secs: u64, interval_secs: u64) {
+        self.0.keepalive = Some(Keepalive {
+            time: Duration::from_secs(time_secs),
+            interval: Duration::from_secs(interval_secs),
+        });
+    }
+
+    #[rune::function]
+    pub fn disable_keepalive(&mut self) {
+        self.0.keepalive = None;
+    }
+
+    #[rune::function]
+    pub fn set_send_buffer_size(&mut self, size: usize) {
+        self.0.send_buffer_size = Some(size);
+    }
+
+    #[rune::function]
+    pub fn set_recv_buffer_size(&mut self, size: usize) {
+        self.0.recv_buffer_size = Some(size);
+    }
+
+    #[rune::function]
+    pub fn set_family(&mut self, family: &str) -> Result<()> {
+        self.0.staggering.family = family.parse()?;
+
+        Ok(())
+    }
+
+    #[rune::function]
+    pub fn set_resolution_delay(&mut self, delay_ms: u64) {
+        self.0.staggering.resolution_delay = Duration::from_millis(delay_ms);
+    }
+
+    #[rune::function]
+    pub fn set_attempt_delay(&mut self, delay_ms: u64) {
+        self.0.staggering.attempt_delay = Duration::from_millis(delay_ms);
+    }
+
+    #[rune::function]
+    pub fn set_connect_timeout(&mut self, timeout_ms: u64) {
+        self.0.connect_timeout = Some(Duration::from_millis(timeout_ms));
+    }
+}
+
 #[rune::function(path = new_quic_connection_async)]
 pub async fn new_quic_connection(
     server: Ref<str>,
     resolver: ResolverWrapper,
     alpn: Value,
+) -> Result<QuicConnectionWrapper> {
+    quic_connection(server, resolver, alpn, CoreQuicOptions::default()).await
+}
+
+#[rune::function(path = new_quic_connection_with_options_async)]
+pub async fn new_quic_connection_with_options(
+    server: Ref<str>,
+    resolver: ResolverWrapper,
+    alpn: Value,
+    options: Ref<QuicOptions>,
+) -> Result<QuicConnectionWrapper> {
+    quic_connection(server, resolver, alpn, options.inner().clone()).await
+}
+
+async fn quic_connection(
+    server: Ref<str>,
+    resolver: ResolverWrapper,
+    alpn: Value,
+    options: CoreQuicOptions,
 ) -> Result<QuicConnectionWrapper> {
     let alpn_vec: Vec<String> = rune::from_value(alpn)?;
 
@@ -54,17 +227,176 @@ pub async fn new_quic_connection(
             server.parse()?,
             resolver.into_inner(),
             alpn_vec.into_iter().map(|x| x.into_bytes()).collect(),
+            options,
         )
         .await?,
     )
     .into())
 }
 
+impl QuicOptions {
+    #[rune::function(path = Self::new)]
+    pub fn new() -> Self {
+        CoreQuicOptions::default().into()
+    }
+
+    #[rune::function]
+    pub fn set_ca_file(&mut self, path: &str) {
+        self.0.ca_file = Some(path.into());
+    }
+
+    #[rune::function]
+    pub fn add_pin(&mut self, pin: &str) -> Result<()> {
+        self.0.add_pin(pin)
+    }
+
+    #[rune::function]
+    pub fn set_sni(&mut self, sni: &str) {
+        self.0.sni = Some(sni.to_owned());
+    }
+
+    #[rune::function]
+    pub fn set_family(&mut self, family: &str) -> Result<()> {
+        self.0.staggering.family = family.parse()?;
+
+        Ok(())
+    }
+
+    #[rune::function]
+    pub fn set_resolution_delay(&mut self, delay_ms: u64) {
+        self.0.staggering.resolution_delay = Duration::from_millis(delay_ms);
+    }
+
+    #[rune::function]
+    pub fn set_attempt_delay(&mut self, delay_ms: u64) {
+        self.0.staggering.attempt_delay = Duration::from_millis(delay_ms);
+    }
+
+    #[rune::function]
+    pub fn set_congestion(&mut self, congestion: &str) -> Result<()> {
+        self.0.congestion = congestion.parse()?;
+
+        Ok(())
+    }
+
+    #[rune::function]
+    pub fn set_idle_timeout(&mut self, timeout_ms: u64) {
+        self.0.idle_timeout = Duration::from_millis(timeout_ms);
+    }
+
+    #[rune::function]
+    pub fn set_keep_alive_interval(&mut self, interval_ms: u64) {
+        self.0.keep_alive_interval = Some(Duration::from_millis(interval_ms));
+    }
+
+    #[rune::function]
+    pub fn disable_keep_alive(&mut self) {
+        self.0.keep_alive_interval = None;
+    }
+
+    #[rune::function]
+    pub fn set_max_concurrent_streams(&mut self, streams: u32) {
+        self.0.max_concurrent_streams = Some(streams);
+    }
+
+    #[rune::function]
+    pub fn set_stream_receive_window(&mut self, bytes: u32) {
+        self.0.stream_receive_window = Some(bytes);
+    }
+
+    #[rune::function]
+    pub fn set_receive_window(&mut self, bytes: u32) {
+        self.0.receive_window = Some(bytes);
+    }
+
+    #[rune::function]
+    pub fn set_send_window(&mut self, bytes: u64) {
+        self.0.send_window = Some(bytes);
+    }
+}
+
 #[rune::function(path = new_quic_async)]
 pub async fn new_quic(connection: QuicConnectionWrapper) -> Result<IoWrapper> {
     Ok(quic_connect(connection.inner()).await?.into())
 }
 
+#[rune::function(path = new_udp_async)]
+pub async fn new_udp(resolver: ResolverWrapper) -> Result<DatagramWrapper> {
+    let datagram: Rc<dyn Datagram> = Rc::new(udp_bind(resolver.into_inner()).await?);
+
+    Ok(datagram.into())
+}
+
+#[rune::function(path = new_socks5_udp_async)]
+pub async fn new_socks5_udp(
+    server: Ref<str>,
+    resolver: ResolverWrapper,
+) -> Result<DatagramWrapper> {
+    let datagram: Rc<dyn Datagram> =
+        Rc::new(socks5_associate(&server.parse()?, resolver.into_inner()).await?);
+
+    Ok(datagram.into())
+}
+
+#[rune::function(path = new_quic_datagram_async)]
+pub async fn new_quic_datagram(connection: QuicConnectionWrapper) -> Result<DatagramWrapper> {
+    let datagram: Rc<dyn Datagram> = Rc::new(quic_datagram(connection.inner()).await?);
+
+    Ok(datagram.into())
+}
+
+#[cfg(unix)]
+impl SshAuth {
+    #[rune::function(path = Self::password)]
+    pub fn password(username: &str, password: &str, known_hosts: &str) -> Self {
+        Auth::new(
+            username.to_owned(),
+            Credential::Password(password.to_owned()),
+            known_hosts.into(),
+        )
+        .into()
+    }
+
+    #[rune::function(path = Self::private_key)]
+    pub fn private_key(
+        username: &str,
+        path: &str,
+        passphrase: Option<String>,
+        known_hosts: &str,
+    ) -> Self {
+        Auth::new(
+            username.to_owned(),
+            Credential::PrivateKey {
+                path: path.into(),
+                passphrase,
+            },
+            known_hosts.into(),
+        )
+        .into()
+    }
+}
+
+#[cfg(unix)]
+#[rune::function(path = new_ssh_connection_async)]
+pub async fn new_ssh_connection(
+    server: Ref<str>,
+    resolver: ResolverWrapper,
+    auth: SshAuth,
+) -> Result<SshConnectionWrapper> {
+    Ok(Rc::new(
+        create_ssh_connection(server.parse()?, resolver.into_inner(), auth.into_inner()).await?,
+    )
+    .into())
+}
+
+#[cfg(unix)]
+#[rune::function(path = new_ssh_async)]
+pub async fn new_ssh(connection: SshConnectionWrapper, endpoint: Ref<str>) -> Result<IoWrapper> {
+    Ok(ssh_connect(connection.inner(), &endpoint.parse()?)
+        .await?
+        .into())
+}
+
 #[rune::function(path = new_tls_async)]
 pub async fn new_tls(endpoint: Ref<str>, nexthop: IoWrapper) -> Result<IoWrapper> {
     Ok(tls_connect(&endpoint.parse()?, nexthop.0).await?.into())
@@ -83,6 +415,47 @@ pub async fn new_http(endpoint: Ref<str>, nexthop: IoWrapper) -> Result<IoWrappe
     Ok(http_connect(&endpoint.parse()?, nexthop.0).await?.into())
 }
 
+#[rune::function(path = new_http2_connection_async)]
+pub async fn new_http2_connection(
+    server: Ref<str>,
+    nexthop: IoWrapper,
+) -> Result<Http2ConnectionWrapper> {
+    Ok(Rc::new(create_http2_connection(&server.parse()?, nexthop.0).await?).into())
+}
+
+#[rune::function(path = new_http2_async)]
+pub async fn new_http2(
+    connection: Http2ConnectionWrapper,
+    endpoint: Ref<str>,
+) -> Result<IoWrapper> {
+    Ok(http2_connect(
+        connection.inner(),
+        &endpoint.parse()?,
+        &ConnectMethod::Classic,
+    )
+    .await?
+    .into())
+}
+
+#[rune::function(path = new_http2_extended_async)]
+pub async fn new_http2_extended(
+    connection: Http2ConnectionWrapper,
+    endpoint: Ref<str>,
+    protocol: Ref<str>,
+    path: Ref<str>,
+) -> Result<IoWrapper> {
+    Ok(http2_connect(
+        connection.inner(),
+        &endpoint.parse()?,
+        &ConnectMethod::Extended {
+            protocol: protocol.as_ref().to_owned(),
+            path: path.as_ref().to_owned(),
+        },
+    )
+    .await?
+    .into())
+}
+
 #[derive(Any)]
 #[rune(constructor)]
 pub struct SimplexConfig {
@@ -114,6 +487,70 @@ pub async fn new_socks5(endpoint: Ref<str>, nexthop: IoWrapper) -> Result<IoWrap
     Ok(socks5_connect(&endpoint.parse()?, nexthop.0).await?.into())
 }
 
+// Handlers may return a reject instead of a connection, the acceptor then
+// answers the client itself.
+#[rune::function]
+pub fn new_reject_http(status: u16, body: Ref<str>) -> Result<Reject> {
+    ensure!(
+        (100..1000).contains(&status),
+        "Invalid HTTP status code: {}",
+        status
+    );
+
+    Ok(Rejection::Http {
+        status,
+        body: body.as_ref().to_owned(),
+    }
+    .into())
+}
+
+#[rune::function]
+pub fn new_reject_socks5(code: u8) -> Result<Reject> {
+    ensure!(code != 0, "SOCKS5 reply code 0 means success");
+
+    Ok(Rejection::Socks5(code).into())
+}
+
+#[rune::function]
+pub fn new_tarpit() -> Reject {
+    Rejection::Tarpit.into()
+}
+
+#[rune::function]
+pub fn new_drop() -> Reject {
+    Rejection::Drop.into()
+}
+
+#[rune::function(path = with_timeout_async)]
+pub async fn with_timeout(timeout_ms: u64, connector: Function) -> Result<IoWrapper> {
+    timeout(Duration::from_millis(timeout_ms), async move {
+        connector
+            .async_send_call::<(), Result<IoWrapper>>(())
+            .await
+            .into_result()?
+    })
+    .await
+    .with_context(|| format!("Timed out after {}ms", timeout_ms))?
+}
+
+#[rune::function(path = race_async)]
+pub async fn race(attempts: Vec<(u64, Function)>) -> Result<IoWrapper> {
+    speed_race(
+        attempts
+            .into_iter()
+            .map(|(delay, connector)| {
+                (Duration::from_millis(delay), async move {
+                    connector
+                        .async_send_call::<(), Result<IoWrapper>>(())
+                        .await
+                        .into_result()?
+                })
+            })
+            .collect(),
+    )
+    .await
+}
+
 impl ConnectRequest {
     #[rune::function]
     pub fn port(&self) -> u16 {
@@ -135,6 +572,16 @@ impl ConnectRequest {
         self.hostname_as_ip().is_some()
     }
 
+    #[rune::function]
+    pub fn is_udp(&self) -> bool {
+        self.udp
+    }
+
+    #[rune::function]
+    pub fn set_chain(&self, chain: &str) {
+        self.chain.replace(Some(chain.to_owned()));
+    }
+
     fn hostname_as_ip(&self) -> Option<String> {
         match &self.endpoint {
             Endpoint::Addr(addr) => Some(addr.ip().to_string()),
@@ -152,19 +599,81 @@ impl ConnectRequest {
         module.ty::<SimplexConfig>()?;
 
         module.function_meta(new_tcp)?;
+        module.function_meta(new_tcp_with_options)?;
         module.function_meta(new_tls)?;
         module.function_meta(new_block)?;
         module.function_meta(new_http)?;
         module.function_meta(new_simplex)?;
         module.function_meta(new_socks5)?;
+        module.function_meta(race)?;
+        module.function_meta(with_timeout)?;
+
+        module.ty::<Reject>()?;
+        module.function_meta(new_reject_http)?;
+        module.function_meta(new_reject_socks5)?;
+        module.function_meta(new_tarpit)?;
+        module.function_meta(new_drop)?;
+
+        module.ty::<TcpOptions>()?;
+        module.function_meta(TcpOptions::new)?;
+        module.function_meta(TcpOptions::set_interface)?;
+        module.function_meta(TcpOptions::set_bind_ip)?;
+        module.function_meta(TcpOptions::set_mark)?;
+        module.function_meta(TcpOptions::set_nodelay)?;
+        module.function_meta(TcpOptions::set_keepalive)?;
+        module.function_meta(TcpOptions::disable_keepalive)?;
+        module.function_meta(TcpOptions::set_send_buffer_size)?;
+        module.function_meta(TcpOptions::set_recv_buffer_size)?;
+        module.function_meta(TcpOptions::set_family)?;
+        module.function_meta(TcpOptions::set_resolution_delay)?;
+        module.function_meta(TcpOptions::set_attempt_delay)?;
+        module.function_meta(TcpOptions::set_connect_timeout)?;
 
         module.function_meta(new_quic_connection)?;
+        module.function_meta(new_quic_connection_with_options)?;
         module.function_meta(new_quic)?;
 
+        module.ty::<DatagramWrapper>()?;
+        module.function_meta(new_udp)?;
+        module.function_meta(new_socks5_udp)?;
+        module.function_meta(new_quic_datagram)?;
+
+        module.ty::<QuicOptions>()?;
+        module.function_meta(QuicOptions::new)?;
+        module.function_meta(QuicOptions::set_ca_file)?;
+        module.function_meta(QuicOptions::add_pin)?;
+        module.function_meta(QuicOptions::set_sni)?;
+        module.function_meta(QuicOptions::set_family)?;
+        module.function_meta(QuicOptions::set_resolution_delay)?;
+        module.function_meta(QuicOptions::set_attempt_delay)?;
+        module.function_meta(QuicOptions::set_congestion)?;
+        module.function_meta(QuicOptions::set_idle_timeout)?;
+        module.function_meta(QuicOptions::set_keep_alive_interval)?;
+        module.function_meta(QuicOptions::disable_keep_alive)?;
+        module.function_meta(QuicOptions::set_max_concurrent_streams)?;
+        module.function_meta(QuicOptions::set_stream_receive_window)?;
+        module.function_meta(QuicOptions::set_receive_window)?;
+        module.function_meta(QuicOptions::set_send_window)?;
+
+        module.function_meta(new_http2_connection)?;
+        module.function_meta(new_http2)?;
+        module.function_meta(new_http2_extended)?;
+
+        #[cfg(unix)]
+        {
+            module.ty::<SshAuth>()?;
+            module.function_meta(SshAuth::password)?;
+            module.function_meta(SshAuth::private_key)?;
+            module.function_meta(new_ssh_connection)?;
+            module.function_meta(new_ssh)?;
+        }
+
         module.function_meta(Self::port)?;
         module.function_meta(Self::hostname)?;
         module.function_meta(Self::endpoint)?;
         module.function_meta(Self::hostname_is_ip)?;
+        module.function_meta(Self::is_udp)?;
+        module.function_meta(Self::set_chain)?;
 
         Ok(module)
     }
@@ -181,6 +690,47 @@ mod tests {
 
     use super::*;
 
+    #[rstest]
+    #[case("Ok(new_reject_http(204, \"\")?)", Some(Rejection::Http { status: 204, body: String::new() }))]
+    #[case("Ok(new_reject_http(1000, \"\")?)", None)]
+    #[case("Ok(new_reject_socks5(2)?)", Some(Rejection::Socks5(2)))]
+    #[case("Ok(new_reject_socks5(0)?)", None)]
+    #[case("Ok(new_tarpit())", Some(Rejection::Tarpit))]
+    #[case("Ok(new_drop())", Some(Rejection::Drop))]
+    #[tokio::test]
+    async fn test_reject(#[case] code: &str, #[case] expected: Option<Rejection>) -> Result<()> {
+        let result: Result<Reject> =
+            testing::run(vec![ConnectRequest::module()?], code, ((),)).await;
+
+        assert_eq!(result.ok().map(Reject::into_inner), expected);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case("options.set_congestion(\"bbr\")?", true)]
+    #[case("options.set_congestion(\"vegas\")?", false)]
+    #[case("options.set_family(\"prefer_ipv4\")?", true)]
+    #[case("options.set_family(\"ipv5_only\")?", false)]
+    #[case(
+        "options.add_pin(\"sha256//0F2cqUr1QFo9mm/qSHOXbkRpQhG1C9f8ou8D0o09niQ=\")?",
+        true
+    )]
+    #[case("options.add_pin(\"c2hvcnQ=\")?", false)]
+    #[tokio::test]
+    async fn test_quic_options(#[case] statement: &str, #[case] succeed: bool) -> Result<()> {
+        let result: Result<QuicOptions> = testing::run(
+            vec![ConnectRequest::module()?],
+            &format!("let options = QuicOptions::new(); {statement}; Ok(options)"),
+            ((),),
+        )
+        .await;
+
+        assert_eq!(result.is_ok(), succeed);
+
+        Ok(())
+    }
+
     async fn test_request<T: FromValue>(method_name: &str, endpoint: Endpoint) -> Result<T> {
         let code = format!("Ok(value.{}())", method_name);
         let request = ConnectRequest::new(endpoint);
@@ -236,6 +786,202 @@ mod tests {
         Ok(())
     }
 
+    #[cfg(unix)]
+    #[rstest]
+    #[case(r#"SshAuth::password("user", "password", "/etc/ssh/ssh_known_hosts")"#)]
+    #[case(r#"SshAuth::private_key("user", "/id_ed25519", None, "/etc/ssh/ssh_known_hosts")"#)]
+    #[case(r#"SshAuth::private_key("user", "/id_ed25519", Some("secret"), "/etc/ssh/ssh_known_hosts")"#)]
+    #[tokio::test]
+    async fn test_ssh_auth(#[case] code: &str) -> Result<()> {
+        let _: SshAuth = testing::run(
+            vec![ConnectRequest::module()?],
+            &format!("Ok({})", code),
+            ((),),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case("[(0, block), (10, direct)]", true)]
+    #[case("[(0, direct), (60000, direct)]", true)]
+    #[case("[(0, block)]", false)]
+    #[case("[]", false)]
+    #[tokio::test]
+    async fn test_race(#[case] attempts: &str, #[case] success: bool) -> Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+
+        let result: Result<IoWrapper> = testing::run(
+            vec![ConnectRequest::module()?, ResolverWrapper::module()?],
+            &format!(
+                r#"
+                async fn direct() {{
+                    new_tcp_async("{}", create_system_resolver()?).await
+                }}
+
+                async fn block() {{
+                    new_block_async("example.com:443").await
+                }}
+
+                Ok(race_async({}).await?)
+                "#,
+                listener.local_addr()?,
+                attempts
+            ),
+            ((),),
+        )
+        .await;
+
+        assert_eq!(result.is_ok(), success, "{:?}", result);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_request_chain() -> Result<()> {
+        let request = ConnectRequest::new("example.com:443".parse()?);
+
+        testing::run::<(), _>(
+            vec![ConnectRequest::module()?],
+            r#"value.set_chain("direct"); Ok(())"#,
+            (request.clone(),),
+        )
+        .await?;
+
+        assert_eq!(request.chain().as_deref(), Some("direct"));
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case(true)]
+    #[case(false)]
+    #[tokio::test]
+    async fn test_udp(#[case] udp: bool) -> Result<()> {
+        let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let endpoint = Endpoint::new_from_addr(server.local_addr()?);
+        let request = if udp {
+            ConnectRequest::new_udp(endpoint)
+        } else {
+            ConnectRequest::new(endpoint)
+        };
+
+        let result: Result<DatagramWrapper> = testing::run(
+            vec![ConnectRequest::module()?, ResolverWrapper::module()?],
+            r#"
+            if !value.is_udp() {
+                return new_block_async(value.endpoint()).await;
+            }
+
+            new_udp_async(create_system_resolver()?).await
+            "#,
+            (request,),
+        )
+        .await;
+
+        assert_eq!(result.is_ok(), udp);
+        if let Ok(datagram) = result {
+            datagram
+                .inner()
+                .send_to(b"ping", &Endpoint::new_from_addr(server.local_addr()?))
+                .await?;
+
+            let mut buf = [0; 4];
+            server.recv_from(&mut buf).await?;
+            assert_eq!(&buf, b"ping");
+        }
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case("", true)]
+    #[case("options.set_nodelay(true);", true)]
+    #[case("options.set_keepalive(10, 5);", true)]
+    #[case("options.disable_keepalive();", true)]
+    #[case(
+        "options.set_send_buffer_size(65536); options.set_recv_buffer_size(65536);",
+        true
+    )]
+    #[case(r#"options.set_bind_ip("127.0.0.1")?;"#, true)]
+    #[case(r#"options.set_bind_ip("::1")?;"#, false)]
+    #[case(r#"options.set_bind_ip("not an ip")?;"#, false)]
+    #[case(r#"options.set_family("prefer_ipv6")?;"#, true)]
+    #[case(r#"options.set_family("ipv6_only")?;"#, false)]
+    #[case(r#"options.set_family("ipv7_only")?;"#, false)]
+    #[case(
+        "options.set_resolution_delay(10); options.set_attempt_delay(100);",
+        true
+    )]
+    #[case("options.set_connect_timeout(1000);", true)]
+    #[tokio::test]
+    async fn test_tcp_options(#[case] code: &str, #[case] success: bool) -> Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+
+        let result: Result<IoWrapper> = testing::run(
+            vec![ConnectRequest::module()?, ResolverWrapper::module()?],
+            &format!(
+                r#"
+                let options = TcpOptions::new();
+                {}
+                Ok(new_tcp_with_options_async("{}", create_system_resolver()?, options).await?)
+                "#,
+                code,
+                listener.local_addr()?,
+            ),
+            ((),),
+        )
+        .await;
+
+        assert_eq!(result.is_ok(), success, "{:?}", result);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case(1000, "direct", true)]
+    #[case(50, "slow", false)]
+    #[case(1000, "block", false)]
+    #[tokio::test]
+    async fn test_with_timeout(
+        #[case] timeout_ms: u64,
+        #[case] connector: &str,
+        #[case] success: bool,
+    ) -> Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+
+        let result: Result<IoWrapper> = testing::run(
+            vec![ConnectRequest::module()?, ResolverWrapper::module()?],
+            &format!(
+                r#"
+                async fn direct() {{
+                    new_tcp_async("{}", create_system_resolver()?).await
+                }}
+
+                async fn slow() {{
+                    race_async([(60000, direct)]).await
+                }}
+
+                async fn block() {{
+                    new_block_async("example.com:443").await
+                }}
+
+                Ok(with_timeout_async({}, {}).await?)
+                "#,
+                listener.local_addr()?,
+                timeout_ms,
+                connector
+            ),
+            ((),),
+        )
+        .await;
+
+        assert_eq!(result.is_ok(), success, "{:?}", result);
+
+        Ok(())
+    }
+
     #[rstest]
     #[case("127.0.0.1:80", true)]
     #[case("[::1]:80", true)]
@@ -0,0 +1,190 @@
+use super::connect::ConnectRequest;
+use crate::core::relay::Traffic;
+use chrono::{DateTime, Utc};
+use futures::future::AbortHandle;
+use serde::Serialize;
+use std::{cell::RefCell, collections::BTreeMap, net::SocketAddr, rc::Rc};
+use tokio::{sync::Notify, time::Instant};
+
+/// A live connection as listed by the admin API.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub client: SocketAddr,
+    pub acceptor: String,
+    // None during the handshake.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    pub chain: Option<String>,
+    pub start: DateTime<Utc>,
+    pub age_ms: u64,
+    pub up: u64,
+    pub down: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    client: SocketAddr,
+    acceptor: String,
+    request: Option<ConnectRequest>,
+    start: DateTime<Utc>,
+    started: Instant,
+    traffic: Rc<Traffic>,
+    abort: AbortHandle,
+}
+
+/// Connections being handled, keyed by the same ID as the access log.
+#[derive(Debug, Default)]
+pub struct Connections {
+    entries: RefCell<BTreeMap<u64, Entry>>,
+    drained: Notify,
+}
+
+/// Keeps the connection listed until dropped.
+#[derive(Debug)]
+pub struct Registration {
+    connections: Rc<Connections>,
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut entries = self.connections.entries.borrow_mut();
+        entries.remove(&self.id);
+
+        if entries.is_empty() {
+            self.connections.drained.notify_waiters();
+        }
+    }
+}
+
+impl Connections {
+    // `abort` stops handling the connection when it's closed by ID. The
+    // request is set once the handshake is done.
+    pub fn register(
+        self: &Rc<Self>,
+        id: u64,
+        client: SocketAddr,
+        acceptor: String,
+        traffic: Rc<Traffic>,
+        abort: AbortHandle,
+    ) -> Registration {
+        self.entries.borrow_mut().insert(
+            id,
+            Entry {
+                client,
+                acceptor,
+                request: None,
+                start: Utc::now(),
+                started: Instant::now(),
+                traffic,
+                abort,
+            },
+        );
+
+        Registration {
+            connections: self.clone(),
+            id,
+        }
+    }
+
+    pub fn set_request(&self, id: u64, request: ConnectRequest) {
+        if let Some(entry) = self.entries.borrow_mut().get_mut(&id) {
+            entry.request = Some(request);
+        }
+    }
+
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        self.entries
+            .borrow()
+            .iter()
+            .map(|(id, entry)| ConnectionInfo {
+                id: *id,
+                client: entry.client,
+                acceptor: entry.acceptor.clone(),
+                target: entry
+                    .request
+                    .as_ref()
+                    .map(|request| request.target().to_string()),
+                chain: entry.request.as_ref().and_then(ConnectRequest::chain),
+                start: entry.start,
+                age_ms: entry
+                    .started
+                    .elapsed()
+                    .as_millis()
+                    .try_into()
+                    .unwrap_or(u64::MAX),
+                up: entry.traffic.up(),
+                down: entry.traffic.down(),
+            })
+            .collect()
+    }
+
+    // Returns whether the connection was found.
+    pub fn close(&self, id: u64) -> bool {
+        match self.entries.borrow().get(&id) {
+            Some(entry) => {
+                entry.abort.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn close_all(&self) {
+        for entry in self.entries.borrow().values() {
+            entry.abort.abort();
+        }
+    }
+
+    /// Resolves once no connection is listed.
+    pub async fn drained(&self) {
+        loop {
+            // Created before checking so a notification in between isn't lost.
+            let notified = self.drained.notified();
+            if self.entries.borrow().is_empty() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::endpoint::Endpoint;
+    use futures::future::{abortable, pending};
+
+    #[tokio::test]
+    async fn test_connections() {
+        let connections = Rc::new(Connections::default());
+        let (connection, abort) = abortable(pending::<()>());
+
+        let registration = connections.register(
+            7,
+            "127.0.0.1:50000".parse().unwrap(),
+            "socks5://127.0.0.1:1080".to_owned(),
+            Rc::default(),
+            abort,
+        );
+        assert_eq!(connections.list()[0].target, None);
+
+        connections.set_request(
+            7,
+            ConnectRequest::new(Endpoint::new_from_domain("example.com", 443)),
+        );
+        let listed = connections.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, 7);
+        assert_eq!(listed[0].target.as_deref(), Some("example.com:443"));
+
+        assert!(!connections.close(8));
+        assert!(connections.close(7));
+        assert!(connection.await.is_err());
+
+        drop(registration);
+        assert!(connections.list().is_empty());
+        connections.drained().await;
+    }
+}
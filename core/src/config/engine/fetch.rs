@@ -0,0 +1,602 @@
+use super::connect::ConnectRequest;
+use crate::{
+    core::{connector::tls::connect as tls_connect, endpoint::Endpoint, io::Io},
+    Result,
+};
+use anyhow::{anyhow, bail, ensure, Context};
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use http_body_util::{BodyExt, Empty, Limited};
+use hyper::{
+    header::{
+        ACCEPT_ENCODING, CONNECTION, CONTENT_ENCODING, ETAG, HOST, IF_MODIFIED_SINCE,
+        IF_NONE_MATCH, LAST_MODIFIED, LOCATION,
+    },
+    HeaderMap, Method, Request, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use rune::{
+    runtime::{Bytes as RuneBytes, Function, Ref},
+    Any, Module,
+};
+use std::{io::Read, net::SocketAddr};
+use tracing::debug;
+use url::{Host, Position, Url};
+
+const DEFAULT_MAX_REDIRECTS: usize = 10;
+const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Any, Clone, Debug)]
+pub struct FetchOptions {
+    headers: Vec<(String, String)>,
+    max_redirects: usize,
+    // Whether redirects from `https` to `http` are followed.
+    allow_downgrade: bool,
+    // Of the body after decompression.
+    max_body_size: usize,
+    // The handler returns connections that are already secured, as
+    // `create_geoip_from_url_async` expects.
+    handler_tls: bool,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            headers: Vec::new(),
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            allow_downgrade: false,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            handler_tls: false,
+        }
+    }
+}
+
+impl FetchOptions {
+    #[rune::function(path = Self::new)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[rune::function]
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_owned(), value.to_owned()));
+    }
+
+    // Conditional request, the server answers 304 if the ETag still matches.
+    #[rune::function]
+    pub fn set_if_none_match(&mut self, etag: &str) {
+        self.headers
+            .push((IF_NONE_MATCH.to_string(), etag.to_owned()));
+    }
+
+    // Conditional request, the server answers 304 if it's not modified since.
+    #[rune::function]
+    pub fn set_if_modified_since(&mut self, last_modified: &str) {
+        self.headers
+            .push((IF_MODIFIED_SINCE.to_string(), last_modified.to_owned()));
+    }
+
+    #[rune::function]
+    pub fn set_max_redirects(&mut self, max_redirects: usize) {
+        self.max_redirects = max_redirects;
+    }
+
+    #[rune::function]
+    pub fn set_allow_downgrade(&mut self, allow_downgrade: bool) {
+        self.allow_downgrade = allow_downgrade;
+    }
+
+    // Larger responses fail instead of being read into memory.
+    #[rune::function]
+    pub fn set_max_body_size(&mut self, bytes: usize) {
+        self.max_body_size = bytes;
+    }
+
+    pub fn with_handler_tls() -> Self {
+        Self {
+            handler_tls: true,
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Any, Clone, Debug)]
+pub struct FetchResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl FetchResponse {
+    pub fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn body(&self) -> &Bytes {
+        &self.body
+    }
+
+    #[rune::function]
+    pub fn status(&self) -> u16 {
+        self.status.as_u16()
+    }
+
+    // The first value of the header, names are case insensitive.
+    #[rune::function]
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.header_value(name)
+    }
+
+    #[rune::function]
+    pub fn headers(&self) -> Vec<(String, String)> {
+        self.headers
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.to_string(),
+                    String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                )
+            })
+            .collect()
+    }
+
+    #[rune::function]
+    pub fn bytes(&self) -> Result<RuneBytes> {
+        Ok(RuneBytes::try_from(&self.body[..])?)
+    }
+
+    #[rune::function]
+    pub fn text(&self) -> Result<String> {
+        String::from_utf8(self.body.to_vec()).context("Response body is not valid UTF-8")
+    }
+
+    #[rune::function]
+    pub fn etag(&self) -> Option<String> {
+        self.header_value(ETAG.as_str())
+    }
+
+    #[rune::function]
+    pub fn last_modified(&self) -> Option<String> {
+        self.header_value(LAST_MODIFIED.as_str())
+    }
+
+    #[rune::function]
+    pub fn is_not_modified(&self) -> bool {
+        self.status == StatusCode::NOT_MODIFIED
+    }
+
+    fn header_value(&self, name: &str) -> Option<String> {
+        self.headers
+            .get(name)
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+    }
+}
+
+async fn connect(url: &Url, handler: &Function, options: &FetchOptions) -> Result<Box<dyn Io>> {
+    let port = url
+        .port_or_known_default()
+        .with_context(|| format!("URL must have a port: {}", url))?;
+    let endpoint = match url.host() {
+        Some(Host::Domain(domain)) => Endpoint::new_from_domain(domain, port),
+        Some(Host::Ipv4(ip)) => Endpoint::new_from_addr(SocketAddr::new(ip.into(), port)),
+        Some(Host::Ipv6(ip)) => Endpoint::new_from_addr(SocketAddr::new(ip.into(), port)),
+        None => bail!("URL must have a host: {}", url),
+    };
+
+    let io = ConnectRequest::new(endpoint.clone())
+        .call(handler)
+        .await?
+        .into_inner();
+
+    if url.scheme() == "https" && !options.handler_tls {
+        Ok(Box::new(tls_connect(&endpoint, io).await?))
+    } else {
+        Ok(io)
+    }
+}
+
+async fn send(
+    url: &Url,
+    handler: &Function,
+    options: &FetchOptions,
+    headers: &[(String, String)],
+) -> Result<FetchResponse> {
+    let io = connect(url, handler, options).await?;
+
+    let (mut request_sender, connection) =
+        hyper::client::conn::http1::handshake(TokioIo::new(io)).await?;
+
+    let connection_task = tokio::task::spawn_local(async move {
+        if let Err(err) = connection.await {
+            if err.is_canceled() {
+                return;
+            }
+
+            debug!("Connection to fetch from failed: {:?}", err);
+        }
+    });
+
+    let mut request = Request::builder()
+        .method(Method::GET)
+        .uri(&url[Position::BeforePath..])
+        .header(CONNECTION, "close")
+        .header(HOST, &url[Position::BeforeHost..Position::AfterPort])
+        .header(ACCEPT_ENCODING, "gzip");
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    let request = request.body(Empty::<Bytes>::new())?;
+
+    let response = request_sender.send_request(request).await?;
+    let (parts, body) = response.into_parts();
+    let body = Limited::new(body, options.max_body_size)
+        .collect()
+        .await
+        .map_err(|e| anyhow!(e))
+        .context("Failed to read response body")?
+        .to_bytes();
+
+    // Force abort the connection task since we're done with the response
+    connection_task.abort();
+
+    let body = match parts.headers.get(CONTENT_ENCODING) {
+        Some(encoding) if encoding == "gzip" => {
+            // A small body may decompress to a lot more.
+            let mut decoded = Vec::new();
+            GzDecoder::new(&body[..])
+                .take(options.max_body_size as u64 + 1)
+                .read_to_end(&mut decoded)
+                .context("Failed to decompress response body")?;
+            ensure!(
+                decoded.len() <= options.max_body_size,
+                "Response body is larger than {} bytes",
+                options.max_body_size
+            );
+            decoded.into()
+        }
+        Some(encoding) if encoding != "identity" => {
+            bail!("Unsupported content encoding: {:?}", encoding)
+        }
+        _ => body,
+    };
+
+    Ok(FetchResponse {
+        status: parts.status,
+        headers: parts.headers,
+        body,
+    })
+}
+
+fn check_redirect(from: &Url, to: &Url, options: &FetchOptions) -> Result<()> {
+    ensure!(
+        options.allow_downgrade || from.scheme() != "https" || to.scheme() != "http",
+        "Refusing to follow redirect from {} to plain HTTP {}",
+        from,
+        to
+    );
+
+    Ok(())
+}
+
+/// Sends a GET request to `url` over the connections returned by `handler`,
+/// which is called with the endpoint of every request like a handler, and
+/// follows redirects.
+pub async fn fetch(url: &str, handler: &Function, options: &FetchOptions) -> Result<FetchResponse> {
+    let mut url = Url::parse(url).with_context(|| format!("Failed to parse URL {}", url))?;
+    let origin = url.origin();
+
+    for _ in 0..=options.max_redirects {
+        ensure!(
+            url.scheme() == "https" || url.scheme() == "http",
+            "Unsupported URL scheme: {}",
+            url.scheme()
+        );
+
+        // The headers may carry credentials or validators meant for the
+        // origin asked for, so they aren't sent anywhere else.
+        let headers = if url.origin() == origin {
+            &options.headers[..]
+        } else {
+            &[]
+        };
+
+        let response = send(&url, handler, options, headers)
+            .await
+            .with_context(|| format!("Failed to fetch {}", url))?;
+
+        if !response.status.is_redirection() || response.status == StatusCode::NOT_MODIFIED {
+            return Ok(response);
+        }
+
+        let location = response
+            .header_value(LOCATION.as_str())
+            .with_context(|| format!("Redirect from {} has no location", url))?;
+        let next = url
+            .join(&location)
+            .with_context(|| format!("Invalid redirect location {}", location))?;
+        check_redirect(&url, &next, options)?;
+        url = next;
+    }
+
+    bail!("Too many redirects, the last one to {}", url)
+}
+
+#[rune::function(path = fetch_async)]
+async fn fetch_rune(
+    url: Ref<str>,
+    handler: Function,
+    options: Ref<FetchOptions>,
+) -> Result<FetchResponse> {
+    fetch(&url, &handler, &options).await
+}
+
+pub fn module() -> Result<Module> {
+    let mut module = Module::new();
+
+    module.ty::<FetchOptions>()?;
+    module.function_meta(FetchOptions::new)?;
+    module.function_meta(FetchOptions::set_header)?;
+    module.function_meta(FetchOptions::set_if_none_match)?;
+    module.function_meta(FetchOptions::set_if_modified_since)?;
+    module.function_meta(FetchOptions::set_max_redirects)?;
+    module.function_meta(FetchOptions::set_allow_downgrade)?;
+    module.function_meta(FetchOptions::set_max_body_size)?;
+
+    module.ty::<FetchResponse>()?;
+    module.function_meta(FetchResponse::status)?;
+    module.function_meta(FetchResponse::header)?;
+    module.function_meta(FetchResponse::headers)?;
+    module.function_meta(FetchResponse::bytes)?;
+    module.function_meta(FetchResponse::text)?;
+    module.function_meta(FetchResponse::etag)?;
+    module.function_meta(FetchResponse::last_modified)?;
+    module.function_meta(FetchResponse::is_not_modified)?;
+
+    module.function_meta(fetch_rune)?;
+
+    Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::engine::{resolver::ResolverWrapper, testing};
+    use flate2::{write::GzEncoder, Compression};
+    use http_body_util::Full;
+    use hyper::{server::conn::http1::Builder, service::service_fn, Response};
+    use rstest::rstest;
+    use std::io::Write;
+    use tokio::{net::TcpListener, task::LocalSet};
+
+    fn respond(request: &Request<hyper::body::Incoming>) -> Result<Response<Full<Bytes>>> {
+        let response = Response::builder();
+
+        Ok(match request.uri().path() {
+            // Redirects to the URL in the query.
+            "/away" => response
+                .status(StatusCode::FOUND)
+                .header(LOCATION, request.uri().query().unwrap_or_default())
+                .body(Full::default())?,
+            "/headers" => {
+                let mut names: Vec<_> = request
+                    .headers()
+                    .keys()
+                    .map(|name| name.as_str())
+                    .filter(|name| name.starts_with("x-") || name.starts_with("if-"))
+                    .collect();
+                names.sort();
+
+                response.body(Full::new(names.join(",").into()))?
+            }
+            // A megabyte of zeros, which compresses to about a kilobyte.
+            "/zeros" => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&[0; 1 << 20])?;
+
+                response
+                    .header(CONTENT_ENCODING, "gzip")
+                    .body(Full::new(encoder.finish()?.into()))?
+            }
+            "/redirect" => response
+                .status(StatusCode::FOUND)
+                .header(LOCATION, "/data")
+                .body(Full::default())?,
+            "/loop" => response
+                .status(StatusCode::FOUND)
+                .header(LOCATION, "/loop")
+                .body(Full::default())?,
+            _ if request
+                .headers()
+                .get(IF_NONE_MATCH)
+                .is_some_and(|etag| etag == "\"v1\"") =>
+            {
+                response
+                    .status(StatusCode::NOT_MODIFIED)
+                    .body(Full::default())?
+            }
+            _ => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(b"hello")?;
+
+                response
+                    .header(ETAG, "\"v1\"")
+                    .header(CONTENT_ENCODING, "gzip")
+                    .body(Full::new(encoder.finish()?.into()))?
+            }
+        })
+    }
+
+    async fn serve() -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        tokio::task::spawn_local(async move {
+            loop {
+                let (io, _) = listener.accept().await?;
+                tokio::task::spawn_local(Builder::new().serve_connection(
+                    TokioIo::new(io),
+                    service_fn(|request| async move { respond(&request) }),
+                ));
+            }
+
+            #[allow(unreachable_code)]
+            anyhow::Ok(())
+        });
+
+        Ok(addr)
+    }
+
+    fn modules() -> Result<Vec<Module>> {
+        Ok(vec![
+            module()?,
+            ConnectRequest::module()?,
+            ResolverWrapper::module()?,
+        ])
+    }
+
+    const DIRECT: &str = r#"
+        async fn direct(connector) {
+            new_tcp_async(connector.endpoint(), create_system_resolver()?).await
+        }
+    "#;
+
+    #[tokio::test]
+    async fn test_fetch() -> Result<()> {
+        LocalSet::new()
+            .run_until(async move {
+                let addr = serve().await?;
+                let direct = DIRECT;
+
+                let result: (u16, String, Option<String>, bool) = testing::run(
+                    modules()?,
+                    &format!(
+                        r#"
+                        {direct}
+
+                        let response = fetch_async("http://{addr}/redirect", direct, FetchOptions::new()).await?;
+
+                        let options = FetchOptions::new();
+                        options.set_if_none_match(response.etag().unwrap());
+                        let cached = fetch_async("http://{addr}/data", direct, options).await?;
+
+                        Ok((response.status(), response.text()?, response.etag(), cached.is_not_modified()))
+                        "#
+                    ),
+                    ((),),
+                )
+                .await?;
+                assert_eq!(result, (200, "hello".to_owned(), Some("\"v1\"".to_owned()), true));
+
+                let result: Result<FetchResponse> = testing::run(
+                    modules()?,
+                    &format!(
+                        r#"
+                        {direct}
+
+                        Ok(fetch_async("http://{addr}/loop", direct, FetchOptions::new()).await?)
+                        "#
+                    ),
+                    ((),),
+                )
+                .await;
+                assert!(result.is_err());
+
+                Ok(())
+            })
+            .await
+    }
+
+    #[rstest]
+    #[case("/data", 4, false)]
+    #[case("/data", 1024, true)]
+    #[case("/zeros", 64 * 1024, false)]
+    #[case("/zeros", 1 << 20, true)]
+    #[tokio::test]
+    async fn test_max_body_size(
+        #[case] path: &str,
+        #[case] max_body_size: usize,
+        #[case] succeed: bool,
+    ) -> Result<()> {
+        LocalSet::new()
+            .run_until(async move {
+                let addr = serve().await?;
+
+                let result: Result<FetchResponse> = testing::run(
+                    modules()?,
+                    &format!(
+                        r#"
+                        {DIRECT}
+
+                        let options = FetchOptions::new();
+                        options.set_max_body_size({max_body_size});
+                        Ok(fetch_async("http://{addr}{path}", direct, options).await?)
+                        "#
+                    ),
+                    ((),),
+                )
+                .await;
+                assert_eq!(result.is_ok(), succeed);
+
+                Ok(())
+            })
+            .await
+    }
+
+    #[rstest]
+    #[case("https://a.test/", "http://a.test/", false, false)]
+    #[case("https://a.test/", "http://a.test/", true, true)]
+    #[case("https://a.test/", "https://b.test/", false, true)]
+    #[case("http://a.test/", "http://b.test/", false, true)]
+    fn test_check_redirect(
+        #[case] from: &str,
+        #[case] to: &str,
+        #[case] allow_downgrade: bool,
+        #[case] allowed: bool,
+    ) -> Result<()> {
+        let options = FetchOptions {
+            allow_downgrade,
+            ..FetchOptions::default()
+        };
+
+        assert_eq!(
+            check_redirect(&from.parse()?, &to.parse()?, &options).is_ok(),
+            allowed
+        );
+
+        Ok(())
+    }
+
+    // Another port is another origin.
+    #[tokio::test]
+    async fn test_cross_origin_headers() -> Result<()> {
+        LocalSet::new()
+            .run_until(async move {
+                let addr = serve().await?;
+                let other = serve().await?;
+
+                let result: (String, String) = testing::run(
+                    modules()?,
+                    &format!(
+                        r#"
+                        {DIRECT}
+
+                        let options = FetchOptions::new();
+                        options.set_header("X-Token", "secret");
+                        options.set_if_none_match("\"v0\"");
+
+                        let same = fetch_async("http://{addr}/away?/headers", direct, options).await?;
+                        let cross = fetch_async("http://{addr}/away?http://{other}/headers", direct, options).await?;
+
+                        Ok((same.text()?, cross.text()?))
+                        "#
+                    ),
+                    ((),),
+                )
+                .await?;
+                assert_eq!(result, ("if-none-match,x-token".to_owned(), String::new()));
+
+                Ok(())
+            })
+            .await
+    }
+}
@@ -1,31 +1,107 @@
-use crate::{
-    config::engine::connect::{ConnectRequest, IoWrapper},
-    core::endpoint::Endpoint,
-    Result,
-};
+use super::fetch::{fetch, FetchOptions};
+use crate::{core::metrics::METRICS, Result};
 use anyhow::Context;
-use http_body_util::{BodyExt, Empty};
-use hyper::{Method, Request};
-use hyper_util::rt::TokioIo;
+use hyper::StatusCode;
 use maxminddb::{geoip2::Country, Mmap, Reader};
-use reqwest::Url;
 use rune::{
     runtime::{Function, Ref},
     Any,
 };
 use sha2::{Digest, Sha256};
 use std::{
+    cell::RefCell,
     env,
     fs::{self, create_dir_all},
     net::IpAddr,
-    rc::Rc,
+    path::{Path, PathBuf},
+    rc::{Rc, Weak},
     time::Duration,
 };
-use tracing::{debug, info};
+use tracing::info;
+
+thread_local! {
+    // Databases in use, so the admin API can refresh them.
+    static DATABASES: RefCell<Vec<Weak<Database>>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Debug)]
+enum Source {
+    Path(String),
+    Url {
+        url: String,
+        handler: Function,
+        db_path: PathBuf,
+    },
+}
+
+impl Source {
+    fn name(&self) -> &str {
+        match self {
+            Source::Path(path) => path,
+            Source::Url { url, .. } => url,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Database {
+    reader: RefCell<Reader<Mmap>>,
+    source: Source,
+}
+
+impl Database {
+    // Reopens a database loaded from a path, or downloads it again.
+    async fn refresh(&self) -> Result<()> {
+        self.reload()
+            .await
+            .with_context(|| format!("Failed to refresh GeoIP database {}", self.source.name()))
+    }
+
+    async fn reload(&self) -> Result<()> {
+        let reader = match &self.source {
+            Source::Path(path) => {
+                // SAFETY: The mmap'd file must not be modified while the reader is in use.
+                unsafe { Reader::open_mmap(path) }
+                    .with_context(|| format!("Failed to load GeoIP database from {}", path))?
+            }
+            Source::Url {
+                url,
+                handler,
+                db_path,
+            } => {
+                download(url, handler, db_path).await?;
+
+                // SAFETY: The downloaded file replaces the old one instead of
+                // modifying it, so the old reader is still valid until dropped.
+                unsafe { Reader::open_mmap(db_path) }
+                    .context("Failed to open downloaded GeoIP database")?
+            }
+        };
+
+        METRICS.geoip_loaded(self.source.name(), reader.metadata().build_epoch);
+        *self.reader.borrow_mut() = reader;
+
+        Ok(())
+    }
+}
 
 #[derive(Any, Debug, Clone)]
 pub struct GeoIp {
-    reader: Rc<Reader<Mmap>>,
+    database: Rc<Database>,
+}
+
+/// Reloads all the GeoIP databases in use, returning how many there are.
+pub async fn refresh_geoip() -> Result<usize> {
+    let databases: Vec<_> = DATABASES.with_borrow_mut(|databases| {
+        databases.retain(|database| database.strong_count() > 0);
+        databases.iter().filter_map(Weak::upgrade).collect()
+    });
+
+    for database in &databases {
+        database.refresh().await?;
+    }
+
+    Ok(databases.len())
 }
 
 #[rune::function]
@@ -35,9 +111,7 @@ pub fn create_geoip_from_absolute_path(path: Ref<str>) -> Result<GeoIp> {
     let reader = unsafe { Reader::open_mmap(path.as_ref()) }
         .with_context(|| format!("Failed to load GeoIP database from {}", path.as_ref()))?;
 
-    Ok(GeoIp {
-        reader: Rc::new(reader),
-    })
+    Ok(GeoIp::new(Source::Path(path.as_ref().to_owned()), reader))
 }
 
 #[rune::function(path = create_geoip_from_url_async)]
@@ -53,7 +127,11 @@ pub async fn create_geoip_from_url(
     // now create a filename using the hash of the url
     let mut hasher = Sha256::new();
     hasher.update(url.as_ref().as_bytes());
-    let url_hash = format!("{:x}", hasher.finalize());
+    let url_hash = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
     let db_path = temp_dir.join(format!("{}.mmdb", url_hash));
 
     // If the file already exists, we check if it is older than the update interval
@@ -70,87 +148,75 @@ pub async fn create_geoip_from_url(
             let reader = unsafe { Reader::open_mmap(&db_path) }
                 .context("Failed to open existing GeoIP database")?;
 
-            return Ok(GeoIp {
-                reader: Rc::new(reader),
-            });
+            return Ok(GeoIp::new(
+                Source::Url {
+                    url: url.as_ref().to_owned(),
+                    handler,
+                    db_path,
+                },
+                reader,
+            ));
         }
     }
 
+    download(url.as_ref(), &handler, &db_path).await?;
+
+    // SAFETY: The mmap'd file is not modified while the reader is in use.
+    let reader = unsafe { Reader::open_mmap(&db_path) }
+        .context("Failed to open downloaded GeoIP database")?;
+
+    Ok(GeoIp::new(
+        Source::Url {
+            url: url.as_ref().to_owned(),
+            handler,
+            db_path,
+        },
+        reader,
+    ))
+}
+
+async fn download(url: &str, handler: &Function, db_path: &Path) -> Result<()> {
     info!(
         "Downloading GeoIP database from {} to {}",
-        url.as_ref(),
+        url,
         db_path.display()
     );
 
-    let url = Url::parse(url.as_ref()).context("Failed to parse GeoIP database URL")?;
-    if url.scheme() != "https" && url.scheme() != "http" {
-        anyhow::bail!("Unsupported URL scheme: {}", url.scheme());
-    }
-
-    let domain = url.domain().ok_or_else(|| {
-        anyhow::anyhow!("GeoIP database URL must have a domain: {}", url.as_ref())
-    })?;
-
-    let port = url
-        .port()
-        .unwrap_or(if url.scheme() == "https" { 443 } else { 80 });
-
-    let io = handler
-        .async_send_call::<(ConnectRequest,), Result<IoWrapper>>((ConnectRequest::new(
-            Endpoint::new_from_domain(domain, port),
-        ),))
-        .await
-        .into_result()??
-        .into_inner();
-
-    let (mut request_sender, connection) =
-        hyper::client::conn::http1::handshake(TokioIo::new(io)).await?;
-
-    let connection_task = tokio::task::spawn_local(async move {
-        if let Err(err) = connection.await {
-            if err.is_canceled() {
-                return;
-            }
-
-            debug!("Connection to download GeoIP failed: {:?}", err);
-        }
-    });
+    // The handler returns secure connections for HTTPS itself.
+    let response = fetch(url, handler, &FetchOptions::with_handler_tls()).await?;
 
-    let req = Request::builder()
-        .method(Method::GET)
-        .uri(url.as_ref())
-        .header("Connection", "close")
-        .header("Host", domain)
-        .body(Empty::<hyper::body::Bytes>::new())?;
-
-    let response = request_sender.send_request(req).await?;
-
-    if response.status() != 200 {
+    if response.status_code() != StatusCode::OK {
         anyhow::bail!(
             "HTTP request failed: {}, {}",
-            response.status(),
-            String::from_utf8_lossy(&response.collect().await?.to_bytes())
+            response.status_code(),
+            String::from_utf8_lossy(response.body())
         );
     }
+    let body = response.body();
 
-    let body = response.collect().await?.to_bytes();
-
-    // Force abort the connection task since we're done with the response
-    connection_task.abort();
-
-    fs::write(&db_path, body).context("Failed to write GeoIP database")?;
+    // Replace the file instead of writing to it, a reader may still map it.
+    let mut tmp_path = db_path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, body).context("Failed to write GeoIP database")?;
+    fs::rename(&tmp_path, db_path).context("Failed to write GeoIP database")?;
     info!("Downloaded GeoIP database to {}", db_path.display());
 
-    // SAFETY: The mmap'd file is not modified while the reader is in use.
-    let reader = unsafe { Reader::open_mmap(&db_path) }
-        .context("Failed to open downloaded GeoIP database")?;
-
-    Ok(GeoIp {
-        reader: Rc::new(reader),
-    })
+    Ok(())
 }
 
 impl GeoIp {
+    fn new(source: Source, reader: Reader<Mmap>) -> Self {
+        METRICS.geoip_loaded(source.name(), reader.metadata().build_epoch);
+
+        let database = Rc::new(Database {
+            reader: RefCell::new(reader),
+            source,
+        });
+        DATABASES.with_borrow_mut(|databases| databases.push(Rc::downgrade(&database)));
+
+        Self { database }
+    }
+
     // We don't differentiate any error here, just return an empty string.
     // User should not care about the internal implementation of maxminddb.
     #[rune::function]
@@ -160,7 +226,7 @@ impl GeoIp {
             Err(_) => return "".to_owned(),
         };
 
-        match self.reader.lookup(ip) {
+        match self.database.reader.borrow().lookup(ip) {
             Ok(result) => result
                 .decode::<Country>()
                 .ok()
@@ -172,12 +238,20 @@ impl GeoIp {
         .to_owned()
     }
 
+    // The same as `POST /geoip/refresh` of the admin API, for this database
+    // only.
+    #[rune::function(instance, path = Self::refresh_async)]
+    async fn refresh(this: Ref<Self>) -> Result<()> {
+        this.database.refresh().await
+    }
+
     pub fn module() -> Result<rune::Module> {
         let mut module = rune::Module::new();
 
         module.ty::<Self>()?;
 
         module.function_meta(Self::lookup)?;
+        module.function_meta(Self::refresh)?;
         module.function_meta(create_geoip_from_url)?;
         module.function_meta(create_geoip_from_absolute_path)?;
 
@@ -211,6 +285,33 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_refresh_geoip() -> Result<()> {
+        let mut path_buf = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path_buf.push("fixtures");
+        path_buf.push("asn-country.mmdb");
+
+        let geoip: GeoIp = testing::run(
+            vec![GeoIp::module()?],
+            &format!(
+                r#"
+                let geoip = create_geoip_from_absolute_path({:?})?;
+                geoip.refresh_async().await?;
+                Ok(geoip)
+                "#,
+                path_buf.to_string_lossy()
+            ),
+            ((),),
+        )
+        .await?;
+        assert_eq!(refresh_geoip().await?, 1);
+
+        drop(geoip);
+        assert_eq!(refresh_geoip().await?, 0);
+
+        Ok(())
+    }
+
     #[tokio::test]
     #[ignore]
     async fn test_geoip_from_url() -> Result<()> {
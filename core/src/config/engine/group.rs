@@ -0,0 +1,186 @@
+use super::connect::{ConnectRequest, IoWrapper};
+use crate::{
+    core::connector::group::{connect as group_connect, Group, Policy},
+    Result,
+};
+use rune::{
+    runtime::{Function, Ref},
+    Any, Module,
+};
+use std::rc::Rc;
+
+// Members are functions taking the connect request and returning the
+// connection, the same as a handler minus the cache. The group lives as long
+// as any copy of it, so keep it in the cache to share member states across
+// connections.
+#[derive(Any, Clone, Debug)]
+pub struct OutboundGroup {
+    group: Rc<Group>,
+    members: Rc<Vec<Function>>,
+}
+
+impl OutboundGroup {
+    fn new(policy: Policy, members: Vec<Function>) -> Result<Self> {
+        Ok(Self {
+            group: Rc::new(Group::new(policy, members.len())?),
+            members: Rc::new(members),
+        })
+    }
+
+    #[rune::function(path = Self::round_robin)]
+    pub fn round_robin(members: Vec<Function>) -> Result<Self> {
+        Self::new(Policy::RoundRobin, members)
+    }
+
+    #[rune::function(path = Self::random)]
+    pub fn random(members: Vec<Function>) -> Result<Self> {
+        Self::new(Policy::Random, members)
+    }
+
+    #[rune::function(path = Self::consistent_hash)]
+    pub fn consistent_hash(members: Vec<Function>) -> Result<Self> {
+        Self::new(Policy::ConsistentHash, members)
+    }
+
+    #[rune::function(path = Self::failover)]
+    pub fn failover(members: Vec<Function>) -> Result<Self> {
+        Self::new(Policy::Failover, members)
+    }
+
+    #[rune::function(instance, path = Self::connect_async)]
+    async fn connect(this: Ref<Self>, request: Ref<ConnectRequest>) -> Result<IoWrapper> {
+        group_connect(&this.group, request.target(), |index| {
+            let members = this.members.clone();
+            let request = (*request).clone();
+
+            async move { request.call(&members[index]).await }
+        })
+        .await
+    }
+
+    #[rune::function]
+    pub fn is_up(&self, index: usize) -> bool {
+        self.group.is_up(index)
+    }
+
+    pub fn module() -> Result<Module> {
+        let mut module = Module::new();
+
+        module.ty::<Self>()?;
+        module.function_meta(Self::round_robin)?;
+        module.function_meta(Self::random)?;
+        module.function_meta(Self::consistent_hash)?;
+        module.function_meta(Self::failover)?;
+        module.function_meta(Self::connect)?;
+        module.function_meta(Self::is_up)?;
+
+        Ok(module)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::engine::{resolver::ResolverWrapper, testing},
+        core::endpoint::Endpoint,
+    };
+    use rstest::rstest;
+    use std::str::FromStr;
+
+    #[rstest]
+    #[case("round_robin", Some(false))]
+    #[case("random", None)]
+    #[case("consistent_hash", None)]
+    #[case("failover", Some(false))]
+    #[tokio::test]
+    async fn test_group(#[case] policy: &str, #[case] block_up: Option<bool>) -> Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let request = ConnectRequest::new(Endpoint::from_str(&listener.local_addr()?.to_string())?);
+
+        let up: Vec<bool> = testing::run(
+            vec![
+                ConnectRequest::module()?,
+                ResolverWrapper::module()?,
+                OutboundGroup::module()?,
+            ],
+            &format!(
+                r#"
+                async fn block(request) {{
+                    new_block_async(request.endpoint()).await
+                }}
+
+                async fn direct(request) {{
+                    new_tcp_async(request.endpoint(), create_system_resolver()?).await
+                }}
+
+                let group = OutboundGroup::{policy}([block, direct])?;
+                for _ in 0..4 {{
+                    group.connect_async(value).await?;
+                }}
+
+                Ok([group.is_up(0), group.is_up(1)])
+                "#,
+            ),
+            (request,),
+        )
+        .await?;
+
+        // Whether the blocking member is ever tried depends on the policy.
+        if let Some(block_up) = block_up {
+            assert_eq!(up[0], block_up);
+        }
+        assert!(up[1]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_target() -> Result<()> {
+        // Nothing listens on the port once the listener is dropped.
+        let addr = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await?
+            .local_addr()?;
+        let request = ConnectRequest::new(Endpoint::new_from_addr(addr));
+
+        let up: Vec<bool> = testing::run(
+            vec![
+                ConnectRequest::module()?,
+                ResolverWrapper::module()?,
+                OutboundGroup::module()?,
+            ],
+            r#"
+            async fn direct(request) {
+                new_tcp_async(request.endpoint(), create_system_resolver()?).await
+            }
+
+            let group = OutboundGroup::failover([direct, direct])?;
+            if group.connect_async(value).await.is_ok() {
+                return Err("Connected to a closed port");
+            }
+
+            Ok([group.is_up(0), group.is_up(1)])
+            "#,
+            (request,),
+        )
+        .await?;
+
+        assert_eq!(up, vec![true, true]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_group() -> Result<()> {
+        let result: Result<OutboundGroup> = testing::run(
+            vec![OutboundGroup::module()?],
+            "Ok(OutboundGroup::failover([])?)",
+            ((),),
+        )
+        .await;
+
+        assert!(result.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,309 @@
+use super::connect::ConnectRequest;
+use crate::{core::endpoint::Endpoint, Result};
+use anyhow::{ensure, Context};
+use futures::future::join_all;
+use http_body_util::Empty;
+use hyper::{body::Bytes, Method, Request};
+use hyper_util::rt::TokioIo;
+use rune::{
+    runtime::{Function, Ref},
+    Any, Module,
+};
+use std::{
+    cell::RefCell,
+    rc::{Rc, Weak},
+    time::Duration,
+};
+use tokio::time::{interval, timeout, Instant};
+use url::{Position, Url};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy)]
+struct Latency {
+    connect: Duration,
+    first_byte: Duration,
+}
+
+#[derive(Debug)]
+struct Prober {
+    url: Url,
+    endpoint: Endpoint,
+    members: Vec<(String, Function)>,
+    // In the order of `members`, `None` if the last probe failed or there is
+    // no probe yet.
+    status: RefCell<Vec<(String, Option<Latency>)>>,
+}
+
+impl Prober {
+    async fn probe(&self, member: &Function) -> Result<Latency> {
+        let start = Instant::now();
+
+        let io = ConnectRequest::new(self.endpoint.clone())
+            .call(member)
+            .await?
+            .into_inner();
+        let connect = start.elapsed();
+
+        let (mut request_sender, mut connection) =
+            hyper::client::conn::http1::handshake(TokioIo::new(io)).await?;
+
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(&self.url[Position::BeforePath..])
+            .header("Host", self.endpoint.hostname())
+            .header("Connection", "close")
+            .body(Empty::<Bytes>::new())?;
+
+        // Any response means the chain works, we only care about when the
+        // first byte comes back. The connection may finish along with the
+        // response since we asked the server to close it.
+        let response = request_sender.send_request(request);
+        tokio::pin!(response);
+        tokio::select! {
+            result = &mut response => { result?; }
+            result = &mut connection => {
+                result?;
+                response.await?;
+            }
+        }
+
+        Ok(Latency {
+            connect,
+            first_byte: start.elapsed() - connect,
+        })
+    }
+
+    async fn probe_all(&self) {
+        let results = join_all(self.members.iter().map(|(name, member)| async move {
+            let result = timeout(PROBE_TIMEOUT, self.probe(member))
+                .await
+                .context("Probe timed out")
+                .and_then(|r| r);
+
+            if let Err(e) = &result {
+                tracing::warn!("Health check of {} failed: {:?}", name, e);
+            }
+
+            (name.clone(), result.ok())
+        }))
+        .await;
+
+        *self.status.borrow_mut() = results;
+    }
+
+    fn status(&self, name: &str) -> Option<Latency> {
+        self.status
+            .borrow()
+            .iter()
+            .find(|(n, _)| n == name)
+            .and_then(|(_, latency)| *latency)
+    }
+}
+
+// Ties go to the member declared first, so the pick doesn't flip between
+// members that are equally fast.
+fn fastest(status: &[(String, Option<Latency>)]) -> Option<String> {
+    status
+        .iter()
+        .filter_map(|(name, latency)| latency.map(|l| (name, l.connect + l.first_byte)))
+        .min_by_key(|(_, latency)| *latency)
+        .map(|(name, _)| name.clone())
+}
+
+// The prober only holds a weak reference, so it stops once the health check
+// is dropped. Keep it in the cache to query it from the handlers.
+#[derive(Any, Clone, Debug)]
+pub struct HealthCheck {
+    inner: Rc<Prober>,
+}
+
+impl HealthCheck {
+    #[rune::function(path = Self::new)]
+    pub fn new(
+        url: Ref<str>,
+        interval_secs: u64,
+        members: Vec<(String, Function)>,
+    ) -> Result<Self> {
+        let url = Url::parse(url.as_ref()).context("Failed to parse health check URL")?;
+        ensure!(
+            url.scheme() == "http",
+            "Unsupported health check URL scheme: {}",
+            url.scheme()
+        );
+        ensure!(interval_secs > 0, "Health check interval must be positive");
+
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("Health check URL must have a host: {}", url))?;
+        let endpoint = format!("{}:{}", host, url.port().unwrap_or(80)).parse()?;
+
+        let status = members
+            .iter()
+            .map(|(name, _)| (name.clone(), None))
+            .collect();
+        let inner = Rc::new(Prober {
+            url,
+            endpoint,
+            members,
+            status: RefCell::new(status),
+        });
+
+        Self::spawn(Rc::downgrade(&inner), Duration::from_secs(interval_secs));
+
+        Ok(Self { inner })
+    }
+
+    fn spawn(prober: Weak<Prober>, period: Duration) {
+        tokio::task::spawn_local(async move {
+            let mut ticker = interval(period);
+
+            loop {
+                ticker.tick().await;
+
+                match prober.upgrade() {
+                    Some(prober) => prober.probe_all().await,
+                    None => break,
+                }
+            }
+        });
+    }
+
+    #[rune::function(instance, path = Self::probe_async)]
+    async fn probe(this: Ref<Self>) {
+        this.inner.probe_all().await
+    }
+
+    #[rune::function]
+    pub fn is_up(&self, name: &str) -> bool {
+        self.inner.status(name).is_some()
+    }
+
+    /// Connect time plus first-byte latency in milliseconds.
+    #[rune::function]
+    pub fn latency(&self, name: &str) -> Option<u64> {
+        self.inner
+            .status(name)
+            .map(|l| (l.connect + l.first_byte).as_millis() as u64)
+    }
+
+    #[rune::function]
+    pub fn fastest(&self) -> Option<String> {
+        fastest(&self.inner.status.borrow())
+    }
+
+    pub fn module() -> Result<Module> {
+        let mut module = Module::new();
+
+        module.ty::<Self>()?;
+        module.function_meta(Self::new)?;
+        module.function_meta(Self::probe)?;
+        module.function_meta(Self::is_up)?;
+        module.function_meta(Self::latency)?;
+        module.function_meta(Self::fastest)?;
+
+        Ok(module)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::engine::{resolver::ResolverWrapper, testing};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task::LocalSet,
+    };
+
+    #[tokio::test]
+    async fn test_health_check() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    let len = stream.read(&mut buf).await.unwrap_or(0);
+                    if !buf[..len].starts_with(b"GET /generate_204?token=1 ") {
+                        return;
+                    }
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                        .await;
+                });
+            }
+        });
+
+        let (direct_up, block_up, fastest): (bool, bool, Option<String>) = LocalSet::new()
+            .run_until(testing::run(
+                vec![
+                    ConnectRequest::module()?,
+                    ResolverWrapper::module()?,
+                    HealthCheck::module()?,
+                ],
+                &format!(
+                    r#"
+                    async fn direct(request) {{
+                        new_tcp_async(request.endpoint(), create_system_resolver()?).await
+                    }}
+
+                    async fn block(request) {{
+                        new_block_async(request.endpoint()).await
+                    }}
+
+                    let health = HealthCheck::new("http://{addr}/generate_204?token=1", 3600, [("direct", direct), ("block", block)])?;
+                    health.probe_async().await;
+
+                    Ok((health.is_up("direct"), health.is_up("block"), health.fastest()))
+                    "#,
+                ),
+                ((),),
+            ))
+            .await?;
+
+        assert!(direct_up);
+        assert!(!block_up);
+        assert_eq!(fastest.as_deref(), Some("direct"));
+
+        Ok(())
+    }
+
+    #[rstest::rstest]
+    #[case("https://example.com/", 60)]
+    #[case("http://example.com/", 0)]
+    #[case("not a url", 60)]
+    #[tokio::test]
+    async fn test_invalid_health_check(#[case] url: &str, #[case] interval: u64) -> Result<()> {
+        let result: Result<HealthCheck> = LocalSet::new()
+            .run_until(testing::run(
+                vec![HealthCheck::module()?],
+                &format!(r#"Ok(HealthCheck::new("{url}", {interval}, [])?)"#),
+                ((),),
+            ))
+            .await;
+
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fastest_tie() {
+        let latency = |ms| {
+            Some(Latency {
+                connect: Duration::from_millis(ms),
+                first_byte: Duration::ZERO,
+            })
+        };
+        let status = vec![
+            ("a".to_owned(), latency(20)),
+            ("b".to_owned(), None),
+            ("c".to_owned(), latency(10)),
+            ("d".to_owned(), latency(10)),
+        ];
+
+        assert_eq!(fastest(&status).as_deref(), Some("c"));
+    }
+}
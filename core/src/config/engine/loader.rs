@@ -0,0 +1,75 @@
+use rune::{
+    ast::Spanned,
+    compile::{self, SourceLoader},
+    item::ComponentRef,
+    Item, Source,
+};
+use std::path::{Path, PathBuf};
+
+// Resolves `mod a::b;` to `a/b.rn` or `a/b/mod.rn`, next to the main config
+// first and then under each include directory in order. Every file loaded is
+// recorded so they can be watched for changes.
+pub struct ConfigLoader<'a> {
+    include: &'a [PathBuf],
+    loaded: Vec<PathBuf>,
+}
+
+impl<'a> ConfigLoader<'a> {
+    pub fn new(include: &'a [PathBuf]) -> Self {
+        Self {
+            include,
+            loaded: Vec::new(),
+        }
+    }
+
+    pub fn into_loaded(self) -> Vec<PathBuf> {
+        self.loaded
+    }
+}
+
+impl SourceLoader for ConfigLoader<'_> {
+    fn load(&mut self, root: &Path, item: &Item, span: &dyn Spanned) -> compile::Result<Source> {
+        let mut relative = PathBuf::new();
+        for component in item {
+            match component {
+                ComponentRef::Str(name) => relative.push(name),
+                _ => {
+                    return Err(compile::Error::msg(
+                        span,
+                        format!("Unsupported module {}", item),
+                    ))
+                }
+            }
+        }
+
+        let path = root
+            .parent()
+            .into_iter()
+            .chain(self.include.iter().map(PathBuf::as_path))
+            .flat_map(|dir| {
+                let base = dir.join(&relative);
+                [base.join("mod.rn"), base.with_extension("rn")]
+            })
+            .find(|path| path.is_file())
+            .ok_or_else(|| {
+                compile::Error::msg(
+                    span,
+                    format!(
+                        "Module {} is not found next to {} or in the include directories",
+                        item,
+                        root.display()
+                    ),
+                )
+            })?;
+
+        let source = Source::from_path(&path).map_err(|e| {
+            compile::Error::msg(
+                span,
+                format!("Failed to load module {}: {}", path.display(), e),
+            )
+        })?;
+        self.loaded.push(path);
+
+        Ok(source)
+    }
+}
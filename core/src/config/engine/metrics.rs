@@ -0,0 +1,80 @@
+use crate::{core::metrics::METRICS, Result};
+use rune::{runtime::Object, FromValue, Module};
+
+fn increment_counter_impl(name: &str, labels: &Object, value: u64) -> Result<()> {
+    let labels = labels
+        .iter()
+        .map(|(label, value)| anyhow::Ok((label.to_string(), String::from_value(value.clone())?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    METRICS.increment_counter(name, labels, value)
+}
+
+// Exported as `<name>_total` with the labels given, e.g.
+// `increment_counter("rule_hits", #{rule: "direct"})?`.
+#[rune::function]
+pub fn increment_counter(name: &str, labels: &Object) -> Result<()> {
+    increment_counter_impl(name, labels, 1)
+}
+
+#[rune::function]
+pub fn increment_counter_by(name: &str, labels: &Object, value: u64) -> Result<()> {
+    increment_counter_impl(name, labels, value)
+}
+
+pub fn module() -> Result<Module> {
+    let mut module = Module::new();
+
+    module.function_meta(increment_counter)?;
+    module.function_meta(increment_counter_by)?;
+
+    Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::engine::testing;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(
+        r#"increment_counter("test_script_counter", #{rule: "direct"})?"#,
+        true
+    )]
+    #[case(
+        r#"increment_counter_by("test_script_counter", #{rule: "proxy"}, 3)?"#,
+        true
+    )]
+    #[case(r#"increment_counter("test_script_counter", #{rule: 1})?"#, false)]
+    #[case(r#"increment_counter("dandelion_connections", #{})?"#, false)]
+    #[tokio::test]
+    async fn test_increment_counter(#[case] code: &str, #[case] succeed: bool) -> Result<()> {
+        let result: Result<()> =
+            testing::run(vec![module()?], &format!("{code}; Ok(())"), ((),)).await;
+
+        assert_eq!(result.is_ok(), succeed);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_counter_value() -> Result<()> {
+        let _: () = testing::run(
+            vec![module()?],
+            r#"
+            increment_counter("test_counter_value", #{rule: "direct"})?;
+            increment_counter_by("test_counter_value", #{rule: "direct"}, 2)?;
+            Ok(())
+            "#,
+            ((),),
+        )
+        .await?;
+
+        assert!(METRICS
+            .encode()?
+            .contains(r#"test_counter_value_total{rule="direct"} 3"#));
+
+        Ok(())
+    }
+}
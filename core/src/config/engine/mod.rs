@@ -1,44 +1,140 @@
+mod admin;
 mod connect;
+mod connections;
+mod fetch;
 mod geoip;
+mod group;
+mod health;
 mod iplist;
+mod loader;
+mod metrics;
+mod mux;
 mod resolver;
+mod scheduler;
+mod store;
 mod testing;
+mod throttle;
 mod tun;
+mod worker;
 
+pub use self::worker::Worker;
 use self::{
-    connect::{ConnectRequest, IoWrapper},
+    connect::{ConnectRequest, DatagramWrapper, IoWrapper, Reject},
+    connections::Connections,
     geoip::GeoIp,
+    group::OutboundGroup,
+    health::HealthCheck,
     iplist::IpNetworkSetWrapper,
+    loader::ConfigLoader,
+    mux::MuxPool,
     resolver::ResolverWrapper,
+    scheduler::{Schedule, Task},
+    store::Store,
+    throttle::Limiter,
+    worker::bind_shared,
 };
 use crate::{
+    config::rune::create_wrapper,
     core::{
-        acceptor::{http, socks5},
+        acceptor::{http, socks5, socks5::UdpAssociation, PendingConnection, Rejection},
+        access_log::{AccessLog, AccessLogConfig, AccessRecord, Outcome},
+        datagram::Datagram,
         endpoint::Endpoint,
-        io::Io,
+        metrics::{self as core_metrics, METRICS},
+        nat::Nat,
+        relay::{relay, RelayOptions, Traffic},
     },
     Result,
 };
-use anyhow::Context as AnyhowContext;
-use futures::{future::select_all, Future, FutureExt};
+use anyhow::{anyhow, bail, ensure, Context as AnyhowContext};
+use chrono::Utc;
+use futures::{future::abortable, Future};
 use rune::{
     alloc::clone::TryClone,
-    runtime::{Object, RuntimeContext},
+    runtime::{Object, RuntimeContext, Value},
     termcolor::{ColorChoice, StandardStream},
     Any, Context, Diagnostics, Module, Source, Sources, Unit, Vm,
 };
-use std::{net::SocketAddr, rc::Rc, sync::Arc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tokio::{
-    io::copy_bidirectional,
     net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        Notify,
+    },
+    task::JoinHandle,
+    time::{timeout, timeout_at, Instant},
 };
+use tokio_util::sync::CancellationToken;
 
 type HandlerName = String;
 
+// How long UDP flows are kept without traffic when no idle timeout is set.
+const DEFAULT_UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// How long tarpits hold connections when no idle timeout is set.
+const DEFAULT_TARPIT_TIMEOUT: Duration = Duration::from_secs(60);
+// How long connections may take to finish on shutdown when no drain timeout
+// is set.
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+// How long closed relays get to pass the close on before they are aborted.
+const CLOSE_GRACE: Duration = Duration::from_secs(2);
+// How long acceptors wait before accepting again after an error.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+// Connection IDs are process wide so they are unique across workers, e.g. in an
+// access log they share.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// Diagnostics name the source by its path.
+fn source_from_path(path: &Path) -> Result<Source> {
+    Source::from_path(path)
+        .with_context(|| format!("Failed to load config file {}", path.display()))
+}
+
 #[derive(Debug, PartialEq)]
 pub enum AcceptorConfig {
-    Socks5(SocketAddr, HandlerName),
-    Http(SocketAddr, HandlerName),
+    Socks5(SocketAddr, HandlerName, RelayOptions),
+    Http(SocketAddr, HandlerName, RelayOptions),
+}
+
+impl AcceptorConfig {
+    // Identifies the acceptor across reloads.
+    fn name(&self) -> String {
+        match self {
+            AcceptorConfig::Socks5(addr, ..) => format!("socks5://{}", addr),
+            AcceptorConfig::Http(addr, ..) => format!("http://{}", addr),
+        }
+    }
+}
+
+create_wrapper!(AcceptorOptions, RelayOptions);
+
+impl AcceptorOptions {
+    #[rune::function(path = Self::new)]
+    pub fn new() -> Self {
+        RelayOptions::default().into()
+    }
+
+    #[rune::function]
+    pub fn set_idle_timeout(&mut self, timeout_ms: u64) {
+        self.0.idle_timeout = Some(Duration::from_millis(timeout_ms));
+    }
+
+    #[rune::function]
+    pub fn set_half_close_timeout(&mut self, timeout_ms: u64) {
+        self.0.half_close_timeout = Some(Duration::from_millis(timeout_ms));
+    }
 }
 
 #[derive(Debug, Any)]
@@ -46,6 +142,11 @@ struct Config {
     acceptors: Vec<AcceptorConfig>,
     #[rune(get, set)]
     cache: Option<Object>,
+    access_log: Option<AccessLogConfig>,
+    metrics: Option<SocketAddr>,
+    admin: Option<(SocketAddr, String)>,
+    drain_timeout: Duration,
+    tasks: Vec<Task>,
 }
 
 impl Config {
@@ -54,14 +155,113 @@ impl Config {
         Self {
             acceptors: Vec::new(),
             cache: None,
+            access_log: None,
+            metrics: None,
+            admin: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            tasks: Vec::new(),
         }
     }
 
+    // Calls `async fn <name>(cache)` every `interval_secs`, starting one
+    // interval after the config is applied.
+    #[rune::function]
+    pub fn every(&mut self, interval_secs: u64, name: &str) -> Result<()> {
+        ensure!(interval_secs > 0, "Task interval must be positive");
+
+        self.tasks.push(Task {
+            name: name.to_owned(),
+            schedule: Schedule::Every(Duration::from_secs(interval_secs)),
+        });
+
+        Ok(())
+    }
+
+    // Calls `async fn <name>(cache)` once, `delay_secs` after the config is
+    // applied.
+    #[rune::function]
+    pub fn after(&mut self, delay_secs: u64, name: &str) {
+        self.tasks.push(Task {
+            name: name.to_owned(),
+            schedule: Schedule::After(Duration::from_secs(delay_secs)),
+        });
+    }
+
+    // How long connections may take to finish on shutdown before they are
+    // closed.
+    #[rune::function]
+    pub fn set_drain_timeout(&mut self, timeout_ms: u64) {
+        self.drain_timeout = Duration::from_millis(timeout_ms);
+    }
+
+    // Serves the admin API, requests must carry `Authorization: Bearer <token>`.
+    #[rune::function]
+    pub fn set_admin_listener(&mut self, addr: &str, token: &str) -> Result<()> {
+        ensure!(!token.is_empty(), "Admin API token must not be empty");
+        // It would only see the connections of the worker it runs on.
+        ensure!(
+            Worker::current().count == 1,
+            "Admin API can't be used with more than one worker"
+        );
+
+        self.admin = Some((addr.parse()?, token.to_owned()));
+
+        Ok(())
+    }
+
+    // Serves Prometheus metrics at `http://<addr>/metrics`.
+    #[rune::function]
+    pub fn set_metrics_listener(&mut self, addr: &str) -> Result<()> {
+        self.metrics = Some(addr.parse()?);
+
+        Ok(())
+    }
+
+    #[rune::function]
+    pub fn set_access_log(&mut self, path: &str) {
+        self.access_log = Some(AccessLogConfig::new(path.into()));
+    }
+
+    #[rune::function]
+    pub fn set_access_log_with_rotation(
+        &mut self,
+        path: &str,
+        max_size: u64,
+        max_files: usize,
+    ) -> Result<()> {
+        ensure!(max_size > 0, "Access log size must be positive");
+
+        self.access_log = Some(AccessLogConfig {
+            path: path.into(),
+            max_size,
+            max_files,
+        });
+
+        Ok(())
+    }
+
     #[rune::function]
     pub fn add_socks5_acceptor(&mut self, addr: &str, handler_name: &str) -> Result<()> {
         self.acceptors.push(AcceptorConfig::Socks5(
             addr.parse()?,
             handler_name.to_owned(),
+            RelayOptions::default(),
+        ));
+
+        Ok(())
+    }
+
+    #[rune::function]
+    pub fn add_socks5_acceptor_with_options(
+        &mut self,
+        addr: &str,
+        handler_name: &str,
+        options: &AcceptorOptions,
+    ) -> Result<()> {
+        self.acceptors.push(AcceptorConfig::Socks5(
+            addr.parse()?,
+            handler_name.to_owned(),
+            options.inner().clone(),
         ));
 
         Ok(())
@@ -69,8 +269,27 @@ impl Config {
 
     #[rune::function]
     pub fn add_http_acceptor(&mut self, addr: &str, handler_name: &str) -> Result<()> {
-        self.acceptors
-            .push(AcceptorConfig::Http(addr.parse()?, handler_name.to_owned()));
+        self.acceptors.push(AcceptorConfig::Http(
+            addr.parse()?,
+            handler_name.to_owned(),
+            RelayOptions::default(),
+        ));
+
+        Ok(())
+    }
+
+    #[rune::function]
+    pub fn add_http_acceptor_with_options(
+        &mut self,
+        addr: &str,
+        handler_name: &str,
+        options: &AcceptorOptions,
+    ) -> Result<()> {
+        self.acceptors.push(AcceptorConfig::Http(
+            addr.parse()?,
+            handler_name.to_owned(),
+            options.inner().clone(),
+        ));
 
         Ok(())
     }
@@ -84,22 +303,49 @@ impl Config {
         module.function_meta(Self::new)?;
         module.function_meta(Self::add_socks5_acceptor)?;
         module.function_meta(Self::add_http_acceptor)?;
+        module.function_meta(Self::add_socks5_acceptor_with_options)?;
+        module.function_meta(Self::add_http_acceptor_with_options)?;
+        module.function_meta(Self::set_access_log)?;
+        module.function_meta(Self::set_access_log_with_rotation)?;
+        module.function_meta(Self::set_metrics_listener)?;
+        module.function_meta(Self::set_admin_listener)?;
+        module.function_meta(Self::set_drain_timeout)?;
+        module.function_meta(Self::every)?;
+        module.function_meta(Self::after)?;
+
+        module.ty::<AcceptorOptions>()?;
+        module.function_meta(AcceptorOptions::new)?;
+        module.function_meta(AcceptorOptions::set_idle_timeout)?;
+        module.function_meta(AcceptorOptions::set_half_close_timeout)?;
 
         Ok(module)
     }
 }
 
-pub struct Engine {
+// Everything built from a config script, replaced as a whole on reload.
+struct Loaded {
     context: Arc<RuntimeContext>,
     unit: Arc<Unit>,
     acceptors: Vec<AcceptorConfig>,
     cache: Option<Object>,
+    access_log: Option<AccessLog>,
+    metrics: Option<SocketAddr>,
+    admin: Option<(SocketAddr, String)>,
+    drain_timeout: Duration,
+    tasks: Vec<Task>,
+    // The config file and the modules it loaded.
+    files: Vec<PathBuf>,
 }
 
-impl Engine {
-    pub async fn load_config(code: impl AsRef<str>) -> Result<Engine> {
+impl Loaded {
+    async fn load(source: Source, include: &[PathBuf]) -> Result<Self> {
+        let mut files = source
+            .path()
+            .map(Path::to_path_buf)
+            .into_iter()
+            .collect::<Vec<_>>();
         let mut sources = Sources::new();
-        sources.insert(Source::memory(code)?)?;
+        sources.insert(source)?;
 
         let mut context = Context::with_default_modules()?;
         context.install(Config::module()?)?;
@@ -107,12 +353,23 @@ impl Engine {
         context.install(ResolverWrapper::module()?)?;
         context.install(IpNetworkSetWrapper::module()?)?;
         context.install(GeoIp::module()?)?;
+        context.install(OutboundGroup::module()?)?;
+        context.install(HealthCheck::module()?)?;
+        context.install(MuxPool::module()?)?;
+        context.install(Limiter::module()?)?;
+        context.install(Store::module()?)?;
+        context.install(metrics::module()?)?;
+        context.install(fetch::module()?)?;
+        context.install(worker::module()?)?;
 
         let mut diagnostics = Diagnostics::new();
+        let mut loader = ConfigLoader::new(include);
         let result = rune::prepare(&mut sources)
             .with_context(&context)
             .with_diagnostics(&mut diagnostics)
+            .with_source_loader(&mut loader)
             .build();
+        files.extend(loader.into_loaded());
 
         if !diagnostics.is_empty() {
             let mut writer = StandardStream::stderr(ColorChoice::Always);
@@ -135,6 +392,11 @@ impl Engine {
                 .context("Cache can only contain cloneable objects")?;
         }
 
+        for task in &config.tasks {
+            vm.lookup_function([task.name.as_str()])
+                .with_context(|| format!("Task function {} is not found", task.name))?;
+        }
+
         log::info!("Done");
 
         Ok(Self {
@@ -142,82 +404,543 @@ impl Engine {
             unit,
             acceptors: config.acceptors,
             cache: config.cache,
+            access_log: config.access_log.map(AccessLog::new).transpose()?,
+            metrics: config.metrics,
+            admin: config.admin,
+            drain_timeout: config.drain_timeout,
+            tasks: config.tasks,
+            files,
         })
     }
 
-    fn vm(&self) -> Vm {
-        Vm::new(self.context.clone(), self.unit.clone())
+    // The listeners the config asks for, by names that stay the same across
+    // reloads as long as the listener doesn't change. Metrics are only served
+    // by the primary worker.
+    fn listeners(&self) -> Vec<(String, SocketAddr, ListenerKind)> {
+        let primary = Worker::current().is_primary();
+
+        self.acceptors
+            .iter()
+            .map(|acceptor| match acceptor {
+                AcceptorConfig::Socks5(addr, ..) => (acceptor.name(), *addr, ListenerKind::Socks5),
+                AcceptorConfig::Http(addr, ..) => (acceptor.name(), *addr, ListenerKind::Http),
+            })
+            .chain(
+                self.metrics
+                    .filter(|_| primary)
+                    .map(|addr| (format!("metrics://{}", addr), addr, ListenerKind::Metrics)),
+            )
+            .chain(
+                self.admin
+                    .as_ref()
+                    .map(|(addr, _)| (format!("admin://{}", addr), *addr, ListenerKind::Admin)),
+            )
+            .collect()
+    }
+}
+
+// Shares the address with the other workers if there are any. Tests hand over
+// the listeners they bound beforehand instead.
+async fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    #[cfg(test)]
+    if let Some(listener) = testing::take_listener(addr) {
+        return Ok(listener);
+    }
+
+    if Worker::current().count > 1 {
+        bind_shared(addr)
+    } else {
+        Ok(TcpListener::bind(addr).await?)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ListenerKind {
+    Socks5,
+    Http,
+    Metrics,
+    Admin,
+}
+
+// A socket stays open across reloads as long as anything listens on its
+// address, even if what's served on it changes.
+struct Listening {
+    name: String,
+    socket: Rc<TcpListener>,
+    task: JoinHandle<()>,
+}
+
+pub struct Engine {
+    loaded: RefCell<Rc<Loaded>>,
+    listeners: RefCell<HashMap<SocketAddr, Listening>>,
+    // Scheduled tasks of the current config.
+    tasks: RefCell<Vec<JoinHandle<()>>>,
+    // Listeners report here when they stop accepting.
+    failures: UnboundedSender<anyhow::Error>,
+    failures_receiver: RefCell<Option<UnboundedReceiver<anyhow::Error>>>,
+    connections: Rc<Connections>,
+    reload: Rc<Notify>,
+    shutting_down: Cell<bool>,
+    // Cancelled to close the relays still running when draining times out.
+    closing: CancellationToken,
+}
+
+impl Engine {
+    pub async fn load_config(code: impl AsRef<str>) -> Result<Engine> {
+        Ok(Self::new(Loaded::load(Source::memory(code)?, &[]).await?))
+    }
+
+    /// Loads the config file at `path`. The modules it declares with `mod` are
+    /// looked up next to it first, then in each of `include` in order.
+    pub async fn load_config_file(path: &Path, include: &[PathBuf]) -> Result<Engine> {
+        Ok(Self::new(
+            Loaded::load(source_from_path(path)?, include).await?,
+        ))
+    }
+
+    fn new(loaded: Loaded) -> Self {
+        let (failures, failures_receiver) = unbounded_channel();
+
+        Self {
+            loaded: RefCell::new(Rc::new(loaded)),
+            listeners: RefCell::default(),
+            tasks: RefCell::default(),
+            failures,
+            failures_receiver: RefCell::new(Some(failures_receiver)),
+            connections: Rc::default(),
+            reload: Rc::default(),
+            shutting_down: Cell::new(false),
+            closing: CancellationToken::new(),
+        }
+    }
+
+    /// Replaces the config with `code`. New connections are handled by the new
+    /// config while the ones in flight keep going, and listeners are added and
+    /// closed to match it. Nothing changes if the new config fails to load or
+    /// a new listener can't be bound.
+    pub async fn reload(self: &Rc<Self>, code: impl AsRef<str>) -> Result<()> {
+        let loaded = Loaded::load(Source::memory(code)?, &[]).await?;
+
+        self.reconcile(Rc::new(loaded)).await
+    }
+
+    /// Same as `reload`, but with the config file at `path` like
+    /// `load_config_file`.
+    pub async fn reload_file(self: &Rc<Self>, path: &Path, include: &[PathBuf]) -> Result<()> {
+        let loaded = Loaded::load(source_from_path(path)?, include).await?;
+
+        self.reconcile(Rc::new(loaded)).await
+    }
+
+    /// The config file and the modules it loaded, empty if the config isn't
+    /// loaded from a file.
+    pub fn config_files(&self) -> Vec<PathBuf> {
+        self.loaded().files.clone()
+    }
+
+    /// Notified when a reload of the config is requested, e.g. from the admin
+    /// API. Whoever loads the config is expected to call `reload`.
+    pub fn reload_signal(&self) -> Rc<Notify> {
+        self.reload.clone()
+    }
+
+    fn loaded(&self) -> Rc<Loaded> {
+        self.loaded.borrow().clone()
+    }
+
+    async fn reconcile(self: &Rc<Self>, loaded: Rc<Loaded>) -> Result<()> {
+        ensure!(!self.shutting_down.get(), "The engine is shutting down");
+
+        let wanted = loaded.listeners();
+
+        // Bind first so a failure leaves everything as it was. Addresses
+        // already listened on keep their sockets, they can't be bound again
+        // while open.
+        let mut sockets = HashMap::new();
+        for (name, addr, _) in &wanted {
+            let listening = self
+                .listeners
+                .borrow()
+                .get(addr)
+                .map(|listening| listening.socket.clone());
+            let socket = match listening {
+                Some(socket) => socket,
+                None => Rc::new(
+                    bind(*addr)
+                        .await
+                        .with_context(|| format!("Failed to listen on {}", name))?,
+                ),
+            };
+            ensure!(
+                sockets.insert(*addr, socket).is_none(),
+                "More than one listener on {}",
+                addr
+            );
+        }
+
+        *self.loaded.borrow_mut() = loaded.clone();
+
+        let tasks = loaded
+            .tasks
+            .iter()
+            .map(|task| tokio::task::spawn_local(scheduler::run(loaded.clone(), task.clone())))
+            .collect();
+        for task in self.tasks.replace(tasks) {
+            task.abort();
+        }
+
+        let mut listeners = self.listeners.borrow_mut();
+        listeners.retain(|addr, listening| {
+            let keep = wanted
+                .iter()
+                .any(|(name, wanted, _)| wanted == addr && *name == listening.name);
+            if !keep {
+                log::info!("Closing listener {}", listening.name);
+                listening.task.abort();
+            }
+            keep
+        });
+
+        for (name, addr, kind) in wanted {
+            if listeners.contains_key(&addr) {
+                continue;
+            }
+
+            log::info!("Listening on {}", name);
+            let socket = sockets.remove(&addr).context("Listener isn't bound")?;
+            let task = self
+                .clone()
+                .spawn_listener(name.clone(), kind, socket.clone());
+            listeners.insert(addr, Listening { name, socket, task });
+        }
+
+        Ok(())
+    }
+
+    fn spawn_listener(
+        self: Rc<Self>,
+        name: String,
+        kind: ListenerKind,
+        listener: Rc<TcpListener>,
+    ) -> JoinHandle<()> {
+        tokio::task::spawn_local(async move {
+            let failures = self.failures.clone();
+            let result = match kind {
+                ListenerKind::Socks5 => {
+                    self.handle_acceptors(listener, socks5::handshake, name.clone())
+                        .await
+                }
+                ListenerKind::Http => {
+                    self.handle_acceptors(listener, http::handshake, name.clone())
+                        .await
+                }
+                ListenerKind::Metrics => core_metrics::serve(listener).await,
+                ListenerKind::Admin => admin::serve(self, listener).await,
+            };
+
+            if let Err(e) = result {
+                let _ = failures.send(e.context(format!("Listener {} failed", name)));
+            }
+        })
+    }
+
+    async fn evaluate(&self, eval_fn: &str, request: ConnectRequest) -> Result<Value> {
+        let loaded = self.loaded();
+        let mut vm = Vm::new(loaded.context.clone(), loaded.unit.clone());
+
+        let start = Instant::now();
+        let result = vm
+            .async_call([eval_fn], (request, loaded.cache.try_clone()?))
+            .await;
+        METRICS.handler_evaluated(eval_fn, start.elapsed());
+
+        rune::from_value::<Result<Value>>(result?)?
+    }
+
+    async fn datagram(&self, eval_fn: &str, target: Endpoint) -> Result<Rc<dyn Datagram>> {
+        let value = self
+            .evaluate(eval_fn, ConnectRequest::new_udp(target))
+            .await?;
+
+        if value.borrow_ref::<Reject>().is_ok() {
+            bail!("UDP flow is rejected");
+        }
+
+        Ok(rune::from_value::<DatagramWrapper>(value)?.into_inner())
+    }
+
+    // Every target of the association gets its own flow from the handler.
+    async fn relay_udp(
+        self: Rc<Self>,
+        eval_fn: String,
+        mut association: UdpAssociation,
+        idle_timeout: Duration,
+        traffic: Rc<Traffic>,
+    ) -> Result<()> {
+        let nat = Nat::new(idle_timeout);
+
+        // There is nothing to half close, the association just ends.
+        while let Some((payload, target)) = tokio::select! {
+            packet = association.recv() => packet?,
+            _ = self.closing.cancelled() => None,
+        } {
+            traffic.add_up(payload.len() as u64);
+
+            let replier = association.replier();
+            let engine = self.clone();
+            let eval_fn = eval_fn.clone();
+            let flow_target = target.clone();
+            let traffic = traffic.clone();
+
+            if let Err(e) = nat
+                .send(
+                    target.clone(),
+                    &payload,
+                    &target,
+                    move || async move { engine.datagram(&eval_fn, flow_target).await },
+                    move |payload, from| {
+                        let replier = replier.clone();
+                        let traffic = traffic.clone();
+                        async move {
+                            replier.send(&payload, &from).await?;
+                            traffic.add_down(payload.len() as u64);
+                            Ok(())
+                        }
+                    },
+                )
+                .await
+            {
+                tracing::debug!("Dropped UDP packet to {}: {:?}", target, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn log_access(&self, record: AccessRecord) {
+        if let Some(access_log) = &self.loaded().access_log {
+            if let Err(e) = access_log.write(&record) {
+                tracing::warn!("Failed to write access log: {:?}", e);
+            }
+        }
+    }
+
+    // The handler and options of the acceptor in the current config.
+    fn route(&self, acceptor: &str) -> Option<(HandlerName, RelayOptions)> {
+        self.loaded()
+            .acceptors
+            .iter()
+            .find(|config| config.name() == acceptor)
+            .map(|config| match config {
+                AcceptorConfig::Socks5(_, handler, options)
+                | AcceptorConfig::Http(_, handler, options) => (handler.clone(), options.clone()),
+            })
     }
 
     pub async fn handle_acceptors<
-        F: Future<Output = Result<(Endpoint, impl Future<Output = Result<impl Io>>)>> + 'static,
+        F: Future<Output = Result<(Endpoint, impl PendingConnection)>> + 'static,
     >(
         self: Rc<Self>,
-        addr: &SocketAddr,
+        listener: Rc<TcpListener>,
         handshake: fn(TcpStream) -> F,
-        eval_fn: String,
+        acceptor: String,
     ) -> Result<()> {
-        let listener = TcpListener::bind(addr).await?;
-
         loop {
-            let io = listener.accept().await?.0;
+            let (io, client) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                // Running out of file descriptors or a client giving up
+                // before it's accepted doesn't stop the listener.
+                Err(e) => {
+                    tracing::warn!("Failed to accept connection on {}: {:?}", acceptor, e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+
+            let Some((eval_fn, options)) = self.route(&acceptor) else {
+                continue;
+            };
 
             let engine = self.clone();
-            let eval_fn = eval_fn.clone();
+            let acceptor = acceptor.clone();
 
             tokio::task::spawn_local(async move {
-                if let Err(e) = async move {
-                    let (endpoint, fut) = handshake(io).await?;
+                let _active = METRICS.connection(&acceptor);
+                let stage = Cell::new("handshake");
 
-                    let endpoint_cloned = endpoint.clone();
-                    async move {
-                        let mut remote = rune::from_value::<Result<IoWrapper>>(
-                            engine
-                                .vm()
-                                .async_call(
-                                    [eval_fn.as_str()],
-                                    (ConnectRequest::new(endpoint), engine.cache.try_clone()?),
-                                )
-                                .await?,
-                        )??
-                        .into_inner();
-
-                        let mut local = fut.await?;
-
-                        copy_bidirectional(&mut local, &mut remote)
+                let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+                let start = Utc::now();
+                let started = Instant::now();
+                let traffic = Rc::new(Traffic::default());
+                // Unknown until the handshake is done.
+                let target = RefCell::new(None);
+                let request = RefCell::new(None::<ConnectRequest>);
+
+                // Registered from the start, so a shutdown waits for the
+                // handshakes in flight as well.
+                let (handling, abort) = abortable(async {
+                    // The address the client reached us on, a wildcard listener
+                    // can't tell it.
+                    let bind_ip = io.local_addr()?.ip();
+                    let (endpoint, pending) = tokio::select! {
+                        result = handshake(io) => result?,
+                        _ = engine.closing.cancelled() => bail!("Closed during the handshake"),
+                    };
+                    target.replace(Some(endpoint.to_string()));
+
+                    if pending.is_udp() {
+                        engine
+                            .connections
+                            .set_request(id, ConnectRequest::new_udp(endpoint));
+
+                        stage.set("accept");
+                        let association = pending.associate(bind_ip, client.ip()).await?;
+                        let idle_timeout = options.idle_timeout.unwrap_or(DEFAULT_UDP_IDLE_TIMEOUT);
+
+                        stage.set("relay");
+                        engine
+                            .clone()
+                            .relay_udp(eval_fn, association, idle_timeout, traffic.clone())
+                            .await
+                            .context("Error happened when relaying UDP")?;
+
+                        return Ok(Outcome::Associated);
+                    }
+
+                    let connect_request = ConnectRequest::new(endpoint.clone());
+                    request.replace(Some(connect_request.clone()));
+                    engine.connections.set_request(id, connect_request.clone());
+
+                    async {
+                        stage.set("handler");
+                        let value = engine.evaluate(&eval_fn, connect_request).await?;
+
+                        if let Ok(reject) = value.borrow_ref::<Reject>() {
+                            let rejecting = pending.reject(reject.inner().clone());
+
+                            if *reject.inner() == Rejection::Tarpit {
+                                // A tarpit never ends by itself, so it's held as
+                                // long as an idle connection at most.
+                                let limit = options.idle_timeout.unwrap_or(DEFAULT_TARPIT_TIMEOUT);
+                                timeout(limit, rejecting).await.unwrap_or(Ok(()))?;
+                            } else {
+                                rejecting.await?;
+                            }
+
+                            return Ok(Outcome::Rejected);
+                        }
+
+                        let mut remote = rune::from_value::<IoWrapper>(value)?.into_inner();
+                        stage.set("accept");
+                        let mut local = pending.accept().await?;
+
+                        stage.set("relay");
+                        relay(&mut local, &mut remote, &options, &traffic, &engine.closing)
                             .await
                             .context("Error happened when forwarding data")?;
 
-                        anyhow::Ok(())
+                        anyhow::Ok(Outcome::Closed)
                     }
                     .await
-                    .with_context(|| format!("target endpoint {}", endpoint_cloned))
-                }
-                .await
-                {
+                    .with_context(|| format!("target endpoint {}", endpoint))
+                });
+                let registration = engine.connections.register(
+                    id,
+                    client,
+                    acceptor.clone(),
+                    traffic.clone(),
+                    abort,
+                );
+
+                let result = handling
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow!("Connection is aborted")));
+                drop(registration);
+
+                METRICS.transferred(&acceptor, traffic.up(), traffic.down());
+                engine.log_access(AccessRecord {
+                    id,
+                    client,
+                    acceptor: acceptor.clone(),
+                    target: target.take(),
+                    chain: request.take().and_then(|request| request.chain()),
+                    start,
+                    duration_ms: started.elapsed().as_millis().try_into().unwrap_or(u64::MAX),
+                    up: traffic.up(),
+                    down: traffic.down(),
+                    outcome: *result.as_ref().unwrap_or(&Outcome::Failed),
+                    error: result.as_ref().err().map(|e| format!("{:#}", e)),
+                });
+
+                if let Err(e) = result {
+                    METRICS.connection_failed(&acceptor, stage.get());
                     tracing::error!("{:?}", e)
                 }
             });
         }
     }
 
-    pub async fn run(self) -> Result<()> {
-        let self_ptr = Rc::new(self);
+    /// Starts the listeners of the config, returning when one of them fails.
+    pub async fn run(self: Rc<Self>) -> Result<()> {
+        let mut failures = self
+            .failures_receiver
+            .borrow_mut()
+            .take()
+            .context("The engine is already running")?;
 
-        select_all(self_ptr.clone().acceptors.iter().map(|c| {
-            match c {
-                AcceptorConfig::Socks5(addr, handler) => self_ptr
-                    .clone()
-                    .handle_acceptors(addr, socks5::handshake, handler.to_owned())
-                    .boxed_local(),
-                AcceptorConfig::Http(addr, handler) => self_ptr
-                    .clone()
-                    .handle_acceptors(addr, http::handshake, handler.to_owned())
-                    .boxed_local(),
-            }
-        }))
-        .await
-        .0
+        self.reconcile(self.loaded()).await?;
+
+        Err(failures
+            .recv()
+            .await
+            .unwrap_or_else(|| anyhow!("The engine is dropped")))
+    }
+
+    /// Stops accepting right away and waits up to the drain timeout of the
+    /// config for the connections in flight to finish. The ones left are then
+    /// half closed on both sides and aborted if that doesn't end them either.
+    ///
+    /// Returns whether every connection finished by itself.
+    pub async fn shutdown(&self) -> bool {
+        self.shutting_down.set(true);
+
+        for task in self.tasks.take() {
+            task.abort();
+        }
+
+        for (_, listening) in self.listeners.borrow_mut().drain() {
+            log::info!("Closing listener {}", listening.name);
+            listening.task.abort();
+        }
+
+        let drain_timeout = self.loaded().drain_timeout;
+        log::info!(
+            "Waiting up to {:?} for {} connections to finish",
+            drain_timeout,
+            self.connections.list().len()
+        );
+
+        if timeout_at(Instant::now() + drain_timeout, self.connections.drained())
+            .await
+            .is_ok()
+        {
+            return true;
+        }
+
+        log::warn!(
+            "Closing {} connections that didn't finish in time",
+            self.connections.list().len()
+        );
+        self.closing.cancel();
+
+        if timeout(CLOSE_GRACE, self.connections.drained())
+            .await
+            .is_err()
+        {
+            self.connections.close_all();
+        }
+
+        false
     }
 }
 
@@ -225,6 +948,8 @@ impl Engine {
 mod tests {
 
     use super::*;
+    use rstest::rstest;
+    use std::net::Ipv4Addr;
 
     #[tokio::test]
     async fn test_add_acceptor() -> Result<()> {
@@ -236,17 +961,46 @@ mod tests {
                 config.add_socks5_acceptor("127.0.0.1:8080", "handler")?;
                 config.add_http_acceptor("127.0.0.1:8081", "handler")?;
 
+                let options = AcceptorOptions::new();
+                options.set_idle_timeout(300000);
+                options.set_half_close_timeout(10000);
+                config.add_socks5_acceptor_with_options("127.0.0.1:8082", "handler", options)?;
+                config.add_http_acceptor_with_options("127.0.0.1:8083", "handler", options)?;
+
                 Ok(config)
             }
         "#,
         )
         .await?;
 
+        let options = RelayOptions {
+            idle_timeout: Some(Duration::from_secs(300)),
+            half_close_timeout: Some(Duration::from_secs(10)),
+        };
+
         assert_eq!(
-            engine.acceptors,
+            engine.loaded().acceptors,
             vec![
-                AcceptorConfig::Socks5("127.0.0.1:8080".parse().unwrap(), "handler".to_owned()),
-                AcceptorConfig::Http("127.0.0.1:8081".parse().unwrap(), "handler".to_owned())
+                AcceptorConfig::Socks5(
+                    "127.0.0.1:8080".parse().unwrap(),
+                    "handler".to_owned(),
+                    RelayOptions::default()
+                ),
+                AcceptorConfig::Http(
+                    "127.0.0.1:8081".parse().unwrap(),
+                    "handler".to_owned(),
+                    RelayOptions::default()
+                ),
+                AcceptorConfig::Socks5(
+                    "127.0.0.1:8082".parse().unwrap(),
+                    "handler".to_owned(),
+                    options.clone()
+                ),
+                AcceptorConfig::Http(
+                    "127.0.0.1:8083".parse().unwrap(),
+                    "handler".to_owned(),
+                    options
+                ),
             ]
         );
 
@@ -268,9 +1022,10 @@ mod tests {
         )
         .await?;
 
-        assert!(engine.cache.is_some());
+        let loaded = engine.loaded();
+        assert!(loaded.cache.is_some());
 
-        let cache = engine.cache.unwrap();
+        let cache = loaded.cache.as_ref().unwrap();
 
         assert!(cache.get("key").is_some());
         assert_eq!(
@@ -280,4 +1035,729 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_access_log() -> Result<()> {
+        use crate::core::connector::socks5::connect as socks5_connect;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("access.log");
+
+        let echo = TcpListener::bind("127.0.0.1:0").await?;
+        let target = echo.local_addr()?;
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await?;
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(&buf).await?;
+            stream.shutdown().await?;
+
+            anyhow::Ok(())
+        });
+
+        let acceptor = testing::listen().await?;
+        let engine = Engine::load_config(format!(
+            r#"
+            pub async fn config() {{
+                let config = Config::new();
+                config.add_socks5_acceptor("{acceptor}", "handler")?;
+                config.set_access_log("{}");
+                Ok(config)
+            }}
+
+            pub async fn handler(connector, cache) {{
+                if connector.is_udp() {{
+                    return new_udp_async(create_system_resolver()?).await;
+                }}
+
+                connector.set_chain("direct");
+                new_tcp_async(connector.endpoint(), create_system_resolver()?).await
+            }}
+            "#,
+            path.display()
+        ))
+        .await?;
+
+        let udp_echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let udp_target = Endpoint::new_from_addr(udp_echo.local_addr()?);
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            let (len, from) = udp_echo.recv_from(&mut buf).await?;
+            udp_echo.send_to(&buf[..len], from).await?;
+
+            anyhow::Ok(())
+        });
+
+        tokio::task::LocalSet::new()
+            .run_until(async move {
+                tokio::task::spawn_local(Rc::new(engine).run());
+
+                let io = TcpStream::connect(acceptor).await?;
+                let mut io = socks5_connect(&Endpoint::new_from_addr(target), io).await?;
+                io.write_all(b"ping").await?;
+                io.shutdown().await?;
+                let mut response = Vec::new();
+                io.read_to_end(&mut response).await?;
+                assert_eq!(response, b"ping");
+
+                // A client that isn't speaking SOCKS5.
+                let mut io = TcpStream::connect(acceptor).await?;
+                io.write_all(b"GET / HTTP/1.1\r\n\r\n").await?;
+                let _ = io.read_to_end(&mut Vec::new()).await;
+
+                let datagram = crate::core::connector::socks5::associate(
+                    &Endpoint::new_from_addr(acceptor),
+                    crate::core::resolver::system::SystemResolver::new(),
+                )
+                .await?;
+                datagram.send_to(b"pong", &udp_target).await?;
+                datagram.recv_from(&mut [0; 1024]).await?;
+                drop(datagram);
+
+                let records = loop {
+                    if let Ok(log) = std::fs::read_to_string(&path) {
+                        if log.lines().count() == 3 {
+                            break log
+                                .lines()
+                                .map(serde_json::from_str::<serde_json::Value>)
+                                .collect::<Result<Vec<_>, _>>()?;
+                        }
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                };
+                let find = |outcome: &str| {
+                    records
+                        .iter()
+                        .find(|record| record["outcome"] == outcome)
+                        .cloned()
+                        .unwrap_or_default()
+                };
+
+                let failed = find("failed");
+                assert!(failed.get("target").is_none());
+                assert!(failed["error"].is_string());
+
+                let associated = find("associated");
+                assert_eq!(
+                    (associated["up"].as_u64(), associated["down"].as_u64()),
+                    (Some(4), Some(4))
+                );
+
+                let record = find("closed");
+                assert_eq!(record["acceptor"], format!("socks5://{acceptor}"));
+                assert_eq!(record["target"], target.to_string());
+                assert_eq!(record["chain"], "direct");
+                assert_eq!(
+                    (record["up"].as_u64(), record["down"].as_u64()),
+                    (Some(4), Some(4))
+                );
+                assert_eq!(record["outcome"], "closed");
+
+                anyhow::Ok(())
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_metrics_listener() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let acceptor = testing::listen().await?;
+        let metrics = testing::listen().await?;
+        let engine = Engine::load_config(format!(
+            r#"
+            pub async fn config() {{
+                let config = Config::new();
+                config.add_socks5_acceptor("{acceptor}", "handler")?;
+                config.set_metrics_listener("{metrics}")?;
+                Ok(config)
+            }}
+
+            pub async fn handler(connector, cache) {{
+                new_tcp_async(connector.endpoint(), create_system_resolver()?).await
+            }}
+            "#
+        ))
+        .await?;
+
+        tokio::task::LocalSet::new()
+            .run_until(async move {
+                tokio::task::spawn_local(Rc::new(engine).run());
+
+                let mut io = TcpStream::connect(metrics).await?;
+                io.write_all(
+                    b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                )
+                .await?;
+                let mut response = String::new();
+                io.read_to_string(&mut response).await?;
+
+                assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+                assert!(response.contains("# TYPE dandelion_connections counter"));
+                assert!(response.ends_with("# EOF\n"));
+
+                Ok(())
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_admin() -> Result<()> {
+        use crate::core::connector::socks5::connect as socks5_connect;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        async fn request(
+            addr: SocketAddr,
+            method: &str,
+            path: &str,
+            token: &str,
+        ) -> Result<String> {
+            let mut io = TcpStream::connect(addr).await?;
+            io.write_all(
+                format!(
+                    "{method} {path} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {token}\r\nConnection: close\r\n\r\n"
+                )
+                .as_bytes(),
+            )
+            .await?;
+            let mut response = String::new();
+            io.read_to_string(&mut response).await?;
+
+            Ok(response)
+        }
+
+        let target_addr = testing::hold_open().await?;
+
+        let acceptor = testing::listen().await?;
+        let admin = testing::listen().await?;
+        let engine = Engine::load_config(format!(
+            r#"
+            pub async fn config() {{
+                let config = Config::new();
+                config.add_socks5_acceptor("{acceptor}", "handler")?;
+                config.set_admin_listener("{admin}", "secret")?;
+                Ok(config)
+            }}
+
+            pub async fn handler(connector, cache) {{
+                connector.set_chain("direct");
+                new_tcp_async(connector.endpoint(), create_system_resolver()?).await
+            }}
+            "#
+        ))
+        .await?;
+        let reload = engine.reload_signal();
+
+        tokio::task::LocalSet::new()
+            .run_until(async move {
+                tokio::task::spawn_local(Rc::new(engine).run());
+
+                let io = TcpStream::connect(acceptor).await?;
+                let mut io = socks5_connect(&Endpoint::new_from_addr(target_addr), io).await?;
+                io.write_all(b"ping").await?;
+
+                assert!(request(admin, "GET", "/connections", "wrong")
+                    .await?
+                    .starts_with("HTTP/1.1 401"));
+
+                let connections = loop {
+                    let response = request(admin, "GET", "/connections", "secret").await?;
+                    let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+                    let connections = serde_json::from_str::<serde_json::Value>(body)?;
+                    if connections[0]["up"] == 4 {
+                        break connections;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                };
+                assert_eq!(connections[0]["target"], target_addr.to_string());
+                assert_eq!(connections[0]["chain"], "direct");
+
+                let id = connections[0]["id"].as_u64().unwrap();
+                assert!(request(
+                    admin,
+                    "DELETE",
+                    &format!("/connections/{}", id + 1),
+                    "secret"
+                )
+                .await?
+                .starts_with("HTTP/1.1 404"));
+                assert!(
+                    request(admin, "DELETE", &format!("/connections/{id}"), "secret")
+                        .await?
+                        .starts_with("HTTP/1.1 204")
+                );
+                assert_eq!(io.read(&mut [0; 1]).await?, 0);
+
+                let response = request(admin, "GET", "/dns", "secret").await?;
+                let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+                let dns = serde_json::from_str::<serde_json::Value>(body)?;
+                assert!(dns["fake_dns"].is_array());
+                assert!(dns["last_working"].is_array());
+
+                assert!(request(admin, "POST", "/reload", "secret")
+                    .await?
+                    .starts_with("HTTP/1.1 202"));
+                reload.notified().await;
+
+                Ok(())
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_config_file() -> Result<()> {
+        use std::fs::{create_dir, write};
+
+        let dir = tempfile::tempdir()?;
+        let include = dir.path().join("include");
+        create_dir(&include)?;
+        create_dir(dir.path().join("rules"))?;
+
+        let main = dir.path().join("config.rn");
+        write(
+            &main,
+            r#"
+            mod rules;
+            mod shared;
+
+            pub async fn config() {
+                let config = Config::new();
+                config.add_socks5_acceptor(shared::ADDR, rules::handler_name())?;
+                Ok(config)
+            }
+            "#,
+        )?;
+        write(
+            dir.path().join("rules/mod.rn"),
+            r#"pub fn handler_name() { "handler" }"#,
+        )?;
+        write(
+            include.join("shared.rn"),
+            r#"pub const ADDR = "127.0.0.1:8080";"#,
+        )?;
+
+        assert!(Engine::load_config_file(&main, &[]).await.is_err());
+
+        let engine = Engine::load_config_file(&main, std::slice::from_ref(&include)).await?;
+        assert_eq!(
+            engine.loaded().acceptors,
+            vec![AcceptorConfig::Socks5(
+                "127.0.0.1:8080".parse().unwrap(),
+                "handler".to_owned(),
+                RelayOptions::default()
+            )]
+        );
+        assert_eq!(
+            engine.config_files(),
+            vec![
+                main,
+                dir.path().join("rules/mod.rn"),
+                include.join("shared.rn")
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tasks() -> Result<()> {
+        let config = r#"
+            pub async fn config() {
+                let config = Config::new();
+                config.cache = Some(#{ "runs": Store::new(8)? });
+                config.every(1, "tick");
+                config.after(0, "once");
+                Ok(config)
+            }
+
+            pub async fn tick(cache) {
+                cache.unwrap().runs.increment("tick", 1)?;
+                Ok(())
+            }
+
+            pub async fn once(cache) {
+                cache.unwrap().runs.increment("once", 1)?;
+                Ok(())
+            }
+
+            pub fn runs(cache, task) {
+                cache.unwrap().runs.get(task).unwrap_or(0)
+            }
+        "#;
+        let engine = Rc::new(Engine::load_config(config).await?);
+        // The runs are counted in the cache of this engine's config.
+        let loaded = engine.loaded();
+        let count = |task: &str| {
+            let mut vm = Vm::new(loaded.context.clone(), loaded.unit.clone());
+            anyhow::Ok(rune::from_value::<i64>(
+                vm.call(["runs"], (loaded.cache.try_clone()?, task))?,
+            )?)
+        };
+
+        assert!(
+            Engine::load_config(config.replace(r#""once""#, r#""missing""#))
+                .await
+                .is_err()
+        );
+
+        tokio::task::LocalSet::new()
+            .run_until(async move {
+                tokio::task::spawn_local(engine.clone().run());
+
+                tokio::time::sleep(Duration::from_millis(1500)).await;
+                assert_eq!((count("tick")?, count("once")?), (1, 1));
+
+                // Tasks of the previous config are stopped.
+                engine
+                    .reload(
+                        r#"
+                        pub async fn config() {
+                            Ok(Config::new())
+                        }
+                        "#,
+                    )
+                    .await?;
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                assert_eq!(count("tick")?, 1);
+
+                Ok(())
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() -> Result<()> {
+        use crate::core::connector::socks5::connect as socks5_connect;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let target_addr = testing::hold_open().await?;
+
+        let acceptor = testing::listen().await?;
+        let config = format!(
+            r#"
+            pub async fn config() {{
+                let config = Config::new();
+                config.add_socks5_acceptor("{acceptor}", "handler")?;
+                config.set_drain_timeout(100);
+                Ok(config)
+            }}
+
+            pub async fn handler(connector, cache) {{
+                new_tcp_async(connector.endpoint(), create_system_resolver()?).await
+            }}
+            "#
+        );
+        let engine = Rc::new(Engine::load_config(&config).await?);
+
+        tokio::task::LocalSet::new()
+            .run_until(async move {
+                tokio::task::spawn_local(engine.clone().run());
+
+                let io = TcpStream::connect(acceptor).await?;
+                let mut io = socks5_connect(&Endpoint::new_from_addr(target_addr), io).await?;
+                io.write_all(b"ping").await?;
+                while engine.connections.list().is_empty() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+
+                // The connection doesn't finish within the drain timeout.
+                assert!(!engine.shutdown().await);
+                assert_eq!(io.read(&mut [0; 1]).await?, 0);
+                assert!(engine.connections.list().is_empty());
+
+                assert!(TcpStream::connect(acceptor).await.is_err());
+                assert!(engine.reload(&config).await.is_err());
+
+                // Nothing is left to drain.
+                assert!(engine.shutdown().await);
+
+                Ok(())
+            })
+            .await
+    }
+
+    // Neither a handshake in flight nor a UDP association finishes by
+    // itself, both are closed once the drain timeout is over.
+    #[rstest]
+    #[case(&[], 0)]
+    #[case(&[5, 1, 0, 5, 3, 0, 1, 0, 0, 0, 0, 0, 0], 12)]
+    #[tokio::test]
+    async fn test_shutdown_pending(#[case] request: &[u8], #[case] reply_len: usize) -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let acceptor = testing::listen().await?;
+        let engine = Rc::new(
+            Engine::load_config(format!(
+                r#"
+                pub async fn config() {{
+                    let config = Config::new();
+                    config.add_socks5_acceptor("{acceptor}", "handler")?;
+                    config.set_drain_timeout(100);
+                    Ok(config)
+                }}
+
+                pub async fn handler(connector, cache) {{
+                    new_udp_async(create_system_resolver()?).await
+                }}
+                "#
+            ))
+            .await?,
+        );
+
+        tokio::task::LocalSet::new()
+            .run_until(async move {
+                tokio::task::spawn_local(engine.clone().run());
+
+                let mut io = TcpStream::connect(acceptor).await?;
+                io.write_all(request).await?;
+                io.read_exact(&mut vec![0; reply_len]).await?;
+                while engine.connections.list().is_empty() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+
+                assert!(!engine.shutdown().await);
+                assert_eq!(io.read(&mut [0; 1]).await?, 0);
+                assert!(engine.connections.list().is_empty());
+
+                Ok(())
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_tarpit() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let addr = testing::listen().await?;
+        let engine = Rc::new(
+            Engine::load_config(format!(
+                r#"
+                pub async fn config() {{
+                    let config = Config::new();
+                    let options = AcceptorOptions::new();
+                    options.set_idle_timeout(100);
+                    config.add_socks5_acceptor_with_options("{addr}", "handler", options)?;
+                    Ok(config)
+                }}
+
+                pub async fn handler(connector, cache) {{
+                    Ok(new_tarpit())
+                }}
+                "#
+            ))
+            .await?,
+        );
+
+        tokio::task::LocalSet::new()
+            .run_until(async move {
+                tokio::task::spawn_local(engine.clone().run());
+
+                let mut io = TcpStream::connect(addr).await?;
+                io.write_all(&[5, 1, 0]).await?;
+                let mut buf = [0; 2];
+                io.read_exact(&mut buf).await?;
+                io.write_all(&[5, 1, 0, 1, 127, 0, 0, 1, 0, 80]).await?;
+
+                // Nothing is answered, and the connection is closed once the
+                // idle timeout runs out.
+                let mut buf = Vec::new();
+                timeout(Duration::from_secs(5), io.read_to_end(&mut buf)).await??;
+                assert!(buf.is_empty());
+
+                Ok(())
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_associate_on_wildcard() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let addr = testing::listen_on(Ipv4Addr::UNSPECIFIED.into()).await?;
+        let engine = Rc::new(
+            Engine::load_config(format!(
+                r#"
+                pub async fn config() {{
+                    let config = Config::new();
+                    config.add_socks5_acceptor("{addr}", "handler")?;
+                    Ok(config)
+                }}
+
+                pub async fn handler(connector, cache) {{
+                    new_udp_async(create_system_resolver()?).await
+                }}
+                "#
+            ))
+            .await?,
+        );
+
+        tokio::task::LocalSet::new()
+            .run_until(async move {
+                tokio::task::spawn_local(engine.clone().run());
+
+                let mut io = TcpStream::connect((Ipv4Addr::LOCALHOST, addr.port())).await?;
+                io.write_all(&[5, 1, 0]).await?;
+                io.read_exact(&mut [0; 2]).await?;
+                io.write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+
+                // The relay is advertised on the address the client connected to.
+                let mut reply = [0; 10];
+                io.read_exact(&mut reply).await?;
+                assert_eq!(reply[..4], [5, 0, 0, 1]);
+                assert_eq!(reply[4..8], Ipv4Addr::LOCALHOST.octets());
+
+                Ok(())
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_reload() -> Result<()> {
+        use crate::core::connector::socks5::connect as socks5_connect;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let echo = TcpListener::bind("127.0.0.1:0").await?;
+        let target = Endpoint::new_from_addr(echo.local_addr()?);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = echo.accept().await?;
+                tokio::spawn(async move {
+                    let (mut read, mut write) = stream.split();
+                    tokio::io::copy(&mut read, &mut write).await
+                });
+            }
+
+            #[allow(unreachable_code)]
+            anyhow::Ok(())
+        });
+
+        let kept = testing::listen().await?;
+        let removed = testing::listen().await?;
+        let added = testing::listen().await?;
+
+        let config = |acceptors: &[(SocketAddr, &str)]| {
+            let acceptors = acceptors
+                .iter()
+                .map(|(addr, handler)| {
+                    format!(r#"config.add_socks5_acceptor("{addr}", "{handler}")?;"#)
+                })
+                .collect::<String>();
+
+            format!(
+                r#"
+                pub async fn config() {{
+                    let config = Config::new();
+                    {acceptors}
+                    Ok(config)
+                }}
+
+                pub async fn direct(connector, cache) {{
+                    new_tcp_async(connector.endpoint(), create_system_resolver()?).await
+                }}
+
+                pub async fn reject(connector, cache) {{
+                    Ok(new_reject_socks5(2)?)
+                }}
+                "#
+            )
+        };
+
+        let engine =
+            Rc::new(Engine::load_config(config(&[(kept, "direct"), (removed, "direct")])).await?);
+
+        tokio::task::LocalSet::new()
+            .run_until(async move {
+                tokio::task::spawn_local(engine.clone().run());
+
+                let connect = |addr| {
+                    let target = target.clone();
+                    async move {
+                        let io = TcpStream::connect(addr).await?;
+                        let mut io = socks5_connect(&target, io).await?;
+                        io.write_all(b"ping").await?;
+                        let mut buf = [0; 4];
+                        io.read_exact(&mut buf).await?;
+                        assert_eq!(&buf, b"ping");
+
+                        anyhow::Ok(io)
+                    }
+                };
+
+                let mut established = connect(kept).await?;
+                connect(removed).await?;
+
+                engine
+                    .reload(config(&[(kept, "reject"), (added, "direct")]))
+                    .await?;
+
+                // The listener is kept but new connections get the new handler.
+                assert!(connect(kept).await.is_err());
+                connect(added).await?;
+                while TcpStream::connect(removed).await.is_ok() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+
+                // Connections in flight are left alone.
+                established.write_all(b"pong").await?;
+                let mut buf = [0; 4];
+                established.read_exact(&mut buf).await?;
+                assert_eq!(&buf, b"pong");
+
+                // A broken config keeps the current one.
+                assert!(engine.reload("pub async fn config() {").await.is_err());
+                connect(added).await?;
+
+                Ok(())
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_reload_acceptor_kind() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let addr = testing::listen().await?;
+        let config = |kind: &str| {
+            format!(
+                r#"
+                pub async fn config() {{
+                    let config = Config::new();
+                    config.add_{kind}_acceptor("{addr}", "handler")?;
+                    Ok(config)
+                }}
+
+                pub async fn handler(connector, cache) {{
+                    new_tcp_async(connector.endpoint(), create_system_resolver()?).await
+                }}
+                "#
+            )
+        };
+
+        let engine = Rc::new(Engine::load_config(config("socks5")).await?);
+
+        tokio::task::LocalSet::new()
+            .run_until(async move {
+                tokio::task::spawn_local(engine.clone().run());
+
+                let target = Endpoint::new_from_addr(testing::hold_open().await?);
+                let io = TcpStream::connect(addr).await?;
+                crate::core::connector::socks5::connect(&target, io).await?;
+
+                // The address is served by an HTTP acceptor now, on the same
+                // socket.
+                engine.reload(config("http")).await?;
+
+                let target = testing::hold_open().await?;
+                let mut io = TcpStream::connect(addr).await?;
+                io.write_all(format!("CONNECT {target} HTTP/1.1\r\n\r\n").as_bytes())
+                    .await?;
+                let mut buf = [0; 12];
+                io.read_exact(&mut buf).await?;
+                assert_eq!(&buf, b"HTTP/1.1 200");
+
+                Ok(())
+            })
+            .await
+    }
 }
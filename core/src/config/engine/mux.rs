@@ -0,0 +1,188 @@
+use super::connect::IoWrapper;
+use crate::{core::mux::Session, Result};
+use anyhow::ensure;
+use rune::{
+    runtime::{Function, Ref},
+    Any, Module,
+};
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+
+#[derive(Any, Clone, Debug)]
+pub struct MuxSession {
+    inner: Rc<Session>,
+}
+
+#[rune::function(path = new_mux_session_async)]
+pub async fn new_mux_session(io: IoWrapper) -> MuxSession {
+    MuxSession {
+        inner: Rc::new(Session::client(io.into_inner())),
+    }
+}
+
+impl MuxSession {
+    #[rune::function(instance, path = Self::open_async)]
+    async fn open(this: Ref<Self>, endpoint: Ref<str>) -> Result<IoWrapper> {
+        Ok(this.inner.open(&endpoint.parse()?).await?.into())
+    }
+
+    #[rune::function]
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+}
+
+#[derive(Debug)]
+struct Pool {
+    // Returns the connection to run a new session over.
+    connector: Function,
+    size: usize,
+    sessions: RefCell<Vec<Rc<Session>>>,
+    next: Cell<usize>,
+}
+
+// Spreads streams over up to `size` sessions, closed sessions are replaced on
+// the next open. Keep it in the cache to share the sessions across connections.
+#[derive(Any, Clone, Debug)]
+pub struct MuxPool {
+    inner: Rc<Pool>,
+}
+
+impl MuxPool {
+    #[rune::function(path = Self::new)]
+    pub fn new(connector: Function, size: usize) -> Result<Self> {
+        ensure!(size > 0, "Mux pool size must be positive");
+
+        Ok(Self {
+            inner: Rc::new(Pool {
+                connector,
+                size,
+                sessions: RefCell::new(Vec::new()),
+                next: Cell::new(0),
+            }),
+        })
+    }
+
+    async fn session(&self) -> Result<Rc<Session>> {
+        {
+            let mut sessions = self.inner.sessions.borrow_mut();
+            sessions.retain(|session| !session.is_closed());
+
+            if sessions.len() >= self.inner.size {
+                let next = self.inner.next.get();
+                self.inner.next.set(next.wrapping_add(1));
+
+                return Ok(sessions[next % sessions.len()].clone());
+            }
+        }
+
+        let io = self
+            .inner
+            .connector
+            .async_send_call::<(), Result<IoWrapper>>(())
+            .await
+            .into_result()??;
+
+        let session = Rc::new(Session::client(io.into_inner()));
+        self.inner.sessions.borrow_mut().push(session.clone());
+
+        Ok(session)
+    }
+
+    #[rune::function(instance, path = Self::open_async)]
+    async fn open(this: Ref<Self>, endpoint: Ref<str>) -> Result<IoWrapper> {
+        let session = this.session().await?;
+
+        Ok(session.open(&endpoint.parse()?).await?.into())
+    }
+
+    #[rune::function]
+    pub fn active_sessions(&self) -> usize {
+        self.inner
+            .sessions
+            .borrow()
+            .iter()
+            .filter(|session| !session.is_closed())
+            .count()
+    }
+
+    pub fn module() -> Result<Module> {
+        let mut module = Module::new();
+
+        module.ty::<MuxSession>()?;
+        module.function_meta(new_mux_session)?;
+        module.function_meta(MuxSession::open)?;
+        module.function_meta(MuxSession::is_closed)?;
+
+        module.ty::<Self>()?;
+        module.function_meta(Self::new)?;
+        module.function_meta(Self::open)?;
+        module.function_meta(Self::active_sessions)?;
+
+        Ok(module)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::engine::{connect::ConnectRequest, resolver::ResolverWrapper, testing},
+        core::mux::accept,
+    };
+    use futures::TryStreamExt;
+    use tokio::{io::AsyncWriteExt, net::TcpListener, task::LocalSet};
+
+    #[tokio::test]
+    async fn test_mux() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let local = LocalSet::new();
+        local.spawn_local(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::task::spawn_local(async move {
+                    let mut streams = accept(stream);
+                    while let Ok(Some((endpoint, mut stream))) = streams.try_next().await {
+                        let _ = stream.write_all(endpoint.to_string().as_bytes()).await;
+                    }
+                });
+            }
+        });
+
+        let (closed, sessions): (bool, usize) = local
+            .run_until(testing::run(
+                vec![
+                    ConnectRequest::module()?,
+                    ResolverWrapper::module()?,
+                    MuxPool::module()?,
+                ],
+                &format!(
+                    r#"
+                async fn connect() {{
+                    new_tcp_async("{addr}", create_system_resolver()?).await
+                }}
+
+                let session = new_mux_session_async(connect().await?).await;
+                session.open_async("example.com:443").await?;
+
+                let pool = MuxPool::new(connect, 2)?;
+                for _ in 0..4 {{
+                    pool.open_async("example.com:443").await?;
+                }}
+
+                Ok((session.is_closed(), pool.active_sessions()))
+                "#,
+                ),
+                ((),),
+            ))
+            .await?;
+
+        assert!(!closed);
+        assert_eq!(sessions, 2);
+
+        Ok(())
+    }
+}
@@ -1,10 +1,11 @@
 use crate::config::rune::create_wrapper;
 use crate::{
-    core::resolver::{hickory::HickoryResolver, system::SystemResolver, Resolver},
+    core::resolver::{
+        hickory::HickoryResolver, metered::Metered, system::SystemResolver, Resolver,
+    },
     Result,
 };
-use hickory_proto::xfer::Protocol;
-use hickory_resolver::config::NameServerConfig;
+use hickory_resolver::config::{ConnectionConfig, NameServerConfig};
 use itertools::Itertools;
 use rune::alloc::clone::TryClone;
 use rune::{
@@ -29,24 +30,35 @@ impl TryClone for ResolverWrapper {
 
 #[rune::function]
 fn create_system_resolver() -> Result<ResolverWrapper> {
-    Ok(SystemResolver::default().into())
+    Ok(Metered::new("system", SystemResolver::default()).into())
 }
 
 #[rune::function]
 fn create_udp_resolver(addrs: RuneVec, timeout: u64) -> Result<ResolverWrapper> {
-    Ok(HickoryResolver::new(
+    let name = format!(
+        "udp:{}",
+        addrs
+            .iter()
+            .map(|addr| anyhow::Ok(addr.borrow_string_ref()?.to_string()))
+            .collect::<Result<Vec<_>>>()?
+            .join(",")
+    );
+
+    let resolver = HickoryResolver::new(
+        name.clone(),
         addrs
             .into_iter()
             .map(|addr| {
-                anyhow::Ok(NameServerConfig::new(
-                    String::from_value(addr)?.parse::<SocketAddr>()?,
-                    Protocol::Udp,
-                ))
+                let addr = String::from_value(addr)?.parse::<SocketAddr>()?;
+                let mut connection = ConnectionConfig::udp();
+                connection.port = addr.port();
+                anyhow::Ok(NameServerConfig::new(addr.ip(), true, vec![connection]))
             })
             .try_collect()?,
         Duration::from_millis(timeout),
-    )?
-    .into())
+    )?;
+
+    Ok(Metered::new(name, resolver).into())
 }
 
 impl ResolverWrapper {
@@ -0,0 +1,58 @@
+use super::Loaded;
+use crate::Result;
+use rune::{alloc::clone::TryClone, runtime::Value, Vm};
+use std::{rc::Rc, time::Duration};
+use tokio::time::{interval_at, sleep, Instant, MissedTickBehavior};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    Every(Duration),
+    After(Duration),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Task {
+    pub name: String,
+    pub schedule: Schedule,
+}
+
+async fn call(loaded: &Loaded, name: &str) {
+    let result = async {
+        let mut vm = Vm::new(loaded.context.clone(), loaded.unit.clone());
+        let value = vm.async_call([name], (loaded.cache.try_clone()?,)).await?;
+
+        rune::from_value::<Result<Value>>(value)??;
+
+        anyhow::Ok(())
+    }
+    .await;
+
+    if let Err(e) = result {
+        tracing::warn!("Task {} failed: {:?}", name, e);
+    }
+}
+
+// Calls the task function with the cache on its schedule, timed from when the
+// config is applied. Runs of a periodic task never overlap, a run taking longer
+// than the interval delays the next one. Abort it to stop.
+//
+// Every worker runs its tasks with its own cache, state the task refreshes is
+// per worker just like the cache. Scripts check `worker_index()` for work that
+// should happen once.
+pub async fn run(loaded: Rc<Loaded>, task: Task) {
+    match task.schedule {
+        Schedule::After(delay) => {
+            sleep(delay).await;
+            call(&loaded, &task.name).await;
+        }
+        Schedule::Every(period) => {
+            let mut interval = interval_at(Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                interval.tick().await;
+                call(&loaded, &task.name).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use crate::Result;
+use anyhow::{ensure, Context};
+use lru::LruCache;
+use rune::{runtime::Value, Any, Module};
+use std::{
+    cell::RefCell, cmp::Reverse, collections::BinaryHeap, num::NonZeroUsize, rc::Rc, time::Duration,
+};
+use tokio::time::Instant;
+
+#[derive(Debug)]
+struct Entry {
+    value: Value,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+#[derive(Debug)]
+struct Entries {
+    entries: LruCache<String, Entry>,
+    // When entries with a TTL expire, so the expired ones can be found without
+    // scanning. Keys set again or evicted since are left in and skipped.
+    expiries: BinaryHeap<Reverse<(Instant, String)>>,
+}
+
+impl Entries {
+    fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            entries: LruCache::new(capacity),
+            expiries: BinaryHeap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<Value> {
+        match self.entries.get(key) {
+            Some(entry) if entry.is_expired(Instant::now()) => {
+                self.entries.pop(key);
+                None
+            }
+            entry => entry.map(|entry| entry.value.clone()),
+        }
+    }
+
+    fn drop_expired(&mut self) {
+        let now = Instant::now();
+
+        while let Some(Reverse((expires_at, _))) = self.expiries.peek() {
+            if *expires_at > now {
+                break;
+            }
+
+            let Some(Reverse((expires_at, key))) = self.expiries.pop() else {
+                break;
+            };
+            if self
+                .entries
+                .peek(&key)
+                .is_some_and(|entry| entry.expires_at == Some(expires_at))
+            {
+                self.entries.pop(&key);
+            }
+        }
+    }
+
+    fn set(&mut self, key: &str, value: Value, ttl: Option<Duration>) {
+        // Expired entries go first, only then the least recently used ones.
+        if self.entries.len() == self.entries.cap().get() && !self.entries.contains(key) {
+            self.drop_expired();
+        }
+
+        let expires_at = ttl.map(|ttl| Instant::now() + ttl);
+        if let Some(expires_at) = expires_at {
+            self.expiries.push(Reverse((expires_at, key.to_owned())));
+        }
+
+        self.entries
+            .put(key.to_owned(), Entry { value, expires_at });
+
+        // Rebuilt once the skipped ones pile up, which keeps it in proportion
+        // to the capacity.
+        if self.expiries.len() > self.entries.cap().get() * 2 {
+            self.expiries = self
+                .entries
+                .iter()
+                .filter_map(|(key, entry)| {
+                    entry
+                        .expires_at
+                        .map(|expires_at| Reverse((expires_at, key.clone())))
+                })
+                .collect();
+        }
+    }
+
+    fn delete(&mut self, key: &str) -> bool {
+        self.entries
+            .pop(key)
+            .is_some_and(|entry| !entry.is_expired(Instant::now()))
+    }
+
+    // A missing key counts from 0 and expires after `ttl`, an existing one keeps
+    // its expiry.
+    fn increment(&mut self, key: &str, by: i64, ttl: Option<Duration>) -> Result<i64> {
+        match self.entries.get_mut(key) {
+            Some(entry) if !entry.is_expired(Instant::now()) => {
+                let value = entry
+                    .value
+                    .as_integer::<i64>()
+                    .ok()
+                    .with_context(|| format!("Value of {} is not an integer", key))?
+                    .checked_add(by)
+                    .with_context(|| format!("Value of {} overflows", key))?;
+                entry.value = Value::from(value);
+
+                Ok(value)
+            }
+            _ => {
+                self.set(key, Value::from(by), ttl);
+
+                Ok(by)
+            }
+        }
+    }
+
+    fn len(&mut self) -> usize {
+        let now = Instant::now();
+        self.entries
+            .iter()
+            .filter(|(_, entry)| !entry.is_expired(now))
+            .count()
+    }
+}
+
+// Unlike the cache, which is copied for every call, all copies of a store share
+// the same entries. Keep it in the cache to use it from handlers. Once full,
+// setting a new key evicts the least recently used one.
+#[derive(Any, Clone, Debug)]
+pub struct Store {
+    entries: Rc<RefCell<Entries>>,
+}
+
+impl Store {
+    #[rune::function(path = Self::new)]
+    pub fn new(capacity: usize) -> Result<Self> {
+        let capacity = NonZeroUsize::new(capacity).context("Store capacity must be positive")?;
+
+        Ok(Self {
+            entries: Rc::new(RefCell::new(Entries::new(capacity))),
+        })
+    }
+
+    #[rune::function]
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.entries.borrow_mut().get(key)
+    }
+
+    #[rune::function]
+    pub fn set(&self, key: &str, value: Value) {
+        self.entries.borrow_mut().set(key, value, None)
+    }
+
+    #[rune::function]
+    pub fn set_with_ttl(&self, key: &str, value: Value, ttl_ms: u64) -> Result<()> {
+        ensure!(ttl_ms > 0, "Store TTL must be positive");
+
+        self.entries
+            .borrow_mut()
+            .set(key, value, Some(Duration::from_millis(ttl_ms)));
+
+        Ok(())
+    }
+
+    // Returns whether the key was set.
+    #[rune::function]
+    pub fn delete(&self, key: &str) -> bool {
+        self.entries.borrow_mut().delete(key)
+    }
+
+    // Adds `by` to the integer at `key` and returns the result.
+    #[rune::function]
+    pub fn increment(&self, key: &str, by: i64) -> Result<i64> {
+        self.entries.borrow_mut().increment(key, by, None)
+    }
+
+    // Same as `increment`, but the key expires after `ttl_ms` if it's created.
+    #[rune::function]
+    pub fn increment_with_ttl(&self, key: &str, by: i64, ttl_ms: u64) -> Result<i64> {
+        ensure!(ttl_ms > 0, "Store TTL must be positive");
+
+        self.entries
+            .borrow_mut()
+            .increment(key, by, Some(Duration::from_millis(ttl_ms)))
+    }
+
+    #[rune::function]
+    pub fn len(&self) -> usize {
+        self.entries.borrow_mut().len()
+    }
+
+    pub fn module() -> Result<Module> {
+        let mut module = Module::new();
+
+        module.ty::<Self>()?;
+        module.function_meta(Self::new)?;
+        module.function_meta(Self::get)?;
+        module.function_meta(Self::set)?;
+        module.function_meta(Self::set_with_ttl)?;
+        module.function_meta(Self::delete)?;
+        module.function_meta(Self::increment)?;
+        module.function_meta(Self::increment_with_ttl)?;
+        module.function_meta(Self::len)?;
+
+        Ok(module)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::engine::testing;
+    use rstest::rstest;
+    use rune::{alloc::clone::TryClone, runtime::Object};
+
+    #[rstest]
+    #[case(r#"store.set("a", "x"); store.get("a") == Some("x")"#, true)]
+    #[case(r#"store.get("a") == None"#, true)]
+    #[case(
+        r#"store.set("a", 1); store.delete("a") && store.get("a") == None"#,
+        true
+    )]
+    #[case(r#"store.delete("a")"#, false)]
+    #[case(
+        r#"store.increment("a", 2)?; store.increment("a", 3)? == 5 && store.get("a") == Some(5)"#,
+        true
+    )]
+    #[case(
+        r#"store.set("a", 1); store.set("b", 2); store.set("c", 3); store.len() == 2 && store.get("a") == None"#,
+        true
+    )]
+    #[tokio::test]
+    async fn test_store(#[case] code: &str, #[case] expected: bool) -> Result<()> {
+        let result: bool = testing::run(
+            vec![Store::module()?],
+            &format!("let store = Store::new(2)?; Ok({{ {code} }})"),
+            ((),),
+        )
+        .await?;
+
+        assert_eq!(result, expected);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case("Store::new(0)?")]
+    #[case(r#"let store = Store::new(1)?; store.set("a", "x"); store.increment("a", 1)?"#)]
+    #[case(r#"Store::new(1)?.set_with_ttl("a", 1, 0)?"#)]
+    #[tokio::test]
+    async fn test_store_error(#[case] code: &str) -> Result<()> {
+        let result: Result<()> =
+            testing::run(vec![Store::module()?], &format!("{code}; Ok(())"), ((),)).await;
+
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ttl() -> Result<()> {
+        let mut entries = Entries::new(NonZeroUsize::new(2).unwrap());
+
+        entries.set("a", Value::from(1), Some(Duration::from_millis(20)));
+        assert_eq!(
+            entries.increment("b", 1, Some(Duration::from_millis(20)))?,
+            1
+        );
+        assert_eq!(entries.len(), 2);
+
+        tokio::time::advance(Duration::from_millis(30)).await;
+
+        assert!(entries.get("a").is_none());
+        assert_eq!(entries.increment("b", 1, None)?, 1);
+        assert_eq!(entries.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expired_evicted_first() -> Result<()> {
+        let mut entries = Entries::new(NonZeroUsize::new(2).unwrap());
+
+        entries.set("a", Value::from(1), None);
+        entries.set("b", Value::from(2), Some(Duration::from_millis(10)));
+        tokio::time::advance(Duration::from_millis(20)).await;
+
+        // "a" is the least recently used, but "b" has expired.
+        entries.set("c", Value::from(3), None);
+        assert!(entries.get("a").is_some());
+        assert!(entries.get("c").is_some());
+
+        // Setting a key again doesn't grow the expiries without bound.
+        for _ in 0..100 {
+            entries.set("d", Value::from(4), Some(Duration::from_secs(60)));
+        }
+        assert!(entries.expiries.len() <= 4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shared_through_cache() -> Result<()> {
+        let cache: Object = testing::run(
+            vec![Store::module()?],
+            "Ok(#{store: Store::new(8)?})",
+            ((),),
+        )
+        .await?;
+
+        let copy = cache.try_clone()?;
+        let store = copy.get("store").unwrap().borrow_ref::<Store>()?.clone();
+        store.entries.borrow_mut().set("a", Value::from(1), None);
+
+        let store = cache.get("store").unwrap().borrow_ref::<Store>()?.clone();
+        assert!(store.entries.borrow_mut().get("a").is_some());
+
+        Ok(())
+    }
+}
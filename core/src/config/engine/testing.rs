@@ -6,6 +6,21 @@ use rune::{
     Diagnostics, FromValue, Module, Source, Sources, Vm,
 };
 use std::sync::Arc;
+#[cfg(test)]
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+#[cfg(test)]
+use tokio::{io::AsyncReadExt, net::TcpListener};
+
+#[cfg(test)]
+thread_local! {
+    // Listeners bound by the tests for the engine to take over, so the port
+    // can't be taken by anyone else before the engine listens on it.
+    static LISTENERS: RefCell<HashMap<SocketAddr, TcpListener>> = RefCell::default();
+}
 
 #[allow(dead_code)]
 pub async fn run<T: FromValue, A: GuardedArgs>(
@@ -46,3 +61,41 @@ pub async fn run<T: FromValue, A: GuardedArgs>(
 
     rune::from_value::<Result<T>>(value)?
 }
+
+/// Binds a port for the engine of the test to listen on. Connections made to
+/// it before the engine runs wait to be accepted.
+#[cfg(test)]
+pub async fn listen() -> Result<SocketAddr> {
+    listen_on(Ipv4Addr::LOCALHOST.into()).await
+}
+
+/// The same as `listen`, on the given address instead of localhost.
+#[cfg(test)]
+pub async fn listen_on(ip: IpAddr) -> Result<SocketAddr> {
+    let listener = TcpListener::bind((ip, 0)).await?;
+    let addr = listener.local_addr()?;
+    LISTENERS.with(|listeners| listeners.borrow_mut().insert(addr, listener));
+
+    Ok(addr)
+}
+
+#[cfg(test)]
+pub fn take_listener(addr: SocketAddr) -> Option<TcpListener> {
+    LISTENERS.with(|listeners| listeners.borrow_mut().remove(&addr))
+}
+
+/// A target that holds the connection open until it's closed from the other
+/// side.
+#[cfg(test)]
+pub async fn hold_open() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        stream.read_to_end(&mut Vec::new()).await?;
+
+        anyhow::Ok(())
+    });
+
+    Ok(addr)
+}
@@ -0,0 +1,85 @@
+use super::{connect::IoWrapper, worker::Worker};
+use crate::{
+    config::rune::create_wrapper,
+    core::throttle::{Limiter as CoreLimiter, Throttled},
+    Result,
+};
+use anyhow::ensure;
+use rune::{Any, Module};
+use std::sync::Arc;
+
+create_wrapper!(Limiter, Arc<CoreLimiter>);
+
+impl Limiter {
+    // Rates are in bytes per second, `None` leaves the direction unlimited.
+    #[rune::function(path = Self::new)]
+    pub fn new(up: Option<u64>, down: Option<u64>) -> Result<Self> {
+        ensure!(
+            up != Some(0) && down != Some(0),
+            "Throttle rates must be positive"
+        );
+        // Every worker would get a limiter of its own, multiplying the rates.
+        ensure!(
+            Worker::current().count == 1,
+            "Throttling can't be used with more than one worker"
+        );
+
+        Ok(Arc::new(CoreLimiter::new(up, down)).into())
+    }
+
+    pub fn module() -> Result<Module> {
+        let mut module = Module::new();
+
+        module.ty::<Self>()?;
+        module.function_meta(Self::new)?;
+        module.function_meta(throttle)?;
+
+        Ok(module)
+    }
+}
+
+// Connections throttled by the same limiter share its rates, keep it in the
+// cache to cap a group of connections together.
+#[rune::function]
+pub fn throttle(io: IoWrapper, limiter: &Limiter) -> IoWrapper {
+    Throttled::new(io.into_inner(), limiter.inner().clone()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::engine::{connect::ConnectRequest, resolver::ResolverWrapper, testing};
+    use rstest::rstest;
+    use tokio::net::TcpListener;
+
+    #[rstest]
+    #[case("Limiter::new(Some(2000000), None)?", true)]
+    #[case("Limiter::new(None, Some(1024))?", true)]
+    #[case("Limiter::new(Some(0), None)?", false)]
+    #[tokio::test]
+    async fn test_throttle(#[case] limiter: &str, #[case] succeed: bool) -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let result: Result<IoWrapper> = testing::run(
+            vec![
+                ConnectRequest::module()?,
+                ResolverWrapper::module()?,
+                Limiter::module()?,
+            ],
+            &format!(
+                r#"
+                let limiter = {limiter};
+                let io = new_tcp_async("{addr}", create_system_resolver()?).await?;
+                Ok(throttle(io, limiter))
+                "#
+            ),
+            ((),),
+        )
+        .await;
+
+        assert_eq!(result.is_ok(), succeed);
+
+        Ok(())
+    }
+}
@@ -9,10 +9,34 @@ use rune::{
     Any, Unit, Vm,
 };
 use std::{
-    rc::Rc,
-    sync::{Arc, Mutex},
+    cell::RefCell,
+    net::IpAddr,
+    rc::{Rc, Weak},
+    sync::{Arc, Mutex, PoisonError},
 };
 
+thread_local! {
+    // Fake resolvers in use, so the admin API can list their mappings.
+    static FAKE_RESOLVERS: RefCell<Vec<Weak<Mutex<FakeDnsResolver>>>> = const { RefCell::new(Vec::new()) };
+}
+
+pub fn fake_dns_mappings() -> Vec<(String, IpAddr)> {
+    FAKE_RESOLVERS.with_borrow_mut(|resolvers| {
+        resolvers.retain(|resolver| resolver.strong_count() > 0);
+
+        resolvers
+            .iter()
+            .filter_map(Weak::upgrade)
+            .flat_map(|resolver| {
+                resolver
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .mappings()
+            })
+            .collect()
+    })
+}
+
 #[derive(Any)]
 pub struct FakeResolver {
     inner: Rc<Mutex<FakeDnsResolver>>,
@@ -20,6 +44,8 @@ pub struct FakeResolver {
 
 impl FakeResolver {
     pub fn new(inner: Rc<Mutex<FakeDnsResolver>>) -> Self {
+        FAKE_RESOLVERS.with_borrow_mut(|resolvers| resolvers.push(Rc::downgrade(&inner)));
+
         Self { inner }
     }
 
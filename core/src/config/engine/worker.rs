@@ -0,0 +1,140 @@
+use crate::Result;
+use rune::Module;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{cell::Cell, net::SocketAddr};
+use tokio::net::TcpListener;
+
+thread_local! {
+    static CURRENT: Cell<Worker> = const { Cell::new(Worker { index: 0, count: 1 }) };
+}
+
+/// One of the threads running an engine.
+///
+/// Every worker compiles the config on its own, so everything a script
+/// creates, such as resolvers, fake DNS pools, GeoIP databases, health checks
+/// and limiters, is per worker and only seen by the handlers of that worker.
+/// Health checks in particular can't be shared, each worker probes on its own.
+/// State that must exist once is either created on the primary worker only
+/// (e.g. `if worker_index() == 0` in the script, which is what a TUN device
+/// with fake DNS needs), or lives behind a process wide `Send + Sync` static
+/// in Rust, like `METRICS` and the access log writers.
+///
+/// The metrics listener only runs on the primary worker. The admin API and
+/// limiters are rejected with more than one worker, they would only see the
+/// connections of their own worker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Worker {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl Worker {
+    /// The worker of the current thread, the only one unless `enter` is called.
+    pub fn current() -> Self {
+        CURRENT.with(Cell::get)
+    }
+
+    /// Makes the current thread run this worker. Must be called before the
+    /// engine of the thread is loaded.
+    pub fn enter(self) {
+        CURRENT.with(|current| current.set(self));
+    }
+
+    pub fn is_primary(&self) -> bool {
+        self.index == 0
+    }
+}
+
+#[rune::function]
+pub fn worker_index() -> usize {
+    Worker::current().index
+}
+
+#[rune::function]
+pub fn worker_count() -> usize {
+    Worker::current().count
+}
+
+pub fn module() -> Result<Module> {
+    let mut module = Module::new();
+
+    module.function_meta(worker_index)?;
+    module.function_meta(worker_count)?;
+
+    Ok(module)
+}
+
+// Listens with SO_REUSEPORT so every worker accepts on the same address and the
+// kernel spreads the connections among them.
+pub fn bind_shared(addr: SocketAddr) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    cfg_if::cfg_if! {
+        if #[cfg(all(
+            unix,
+            not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+        ))] {
+            socket.set_reuse_address(true)?;
+            socket.set_reuse_port(true)?;
+        } else {
+            anyhow::bail!("Running multiple workers is not supported on this platform");
+        }
+    }
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::engine::{testing, Engine};
+    use rstest::rstest;
+
+    #[tokio::test]
+    async fn test_worker() -> Result<()> {
+        Worker { index: 1, count: 2 }.enter();
+
+        let result: (usize, usize) = testing::run(
+            vec![module()?],
+            "Ok((worker_index(), worker_count()))",
+            ((),),
+        )
+        .await?;
+        assert_eq!(result, (1, 2));
+
+        let listener = bind_shared("127.0.0.1:0".parse()?)?;
+        let shared = bind_shared(listener.local_addr()?)?;
+        assert_eq!(listener.local_addr()?, shared.local_addr()?);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case(r#"config.set_admin_listener("127.0.0.1:0", "token")?;"#)]
+    #[case("Limiter::new(Some(1024), None)?;")]
+    #[tokio::test]
+    async fn test_single_worker_only(
+        #[case] code: &str,
+        #[values(1, 2)] count: usize,
+    ) -> Result<()> {
+        Worker { index: 0, count }.enter();
+
+        let result = Engine::load_config(format!(
+            r#"
+            pub async fn config() {{
+                let config = Config::new();
+                {code}
+                Ok(config)
+            }}
+            "#
+        ))
+        .await;
+        assert_eq!(result.is_ok(), count == 1);
+
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
 mod engine;
 mod rune;
 
-pub use engine::Engine;
+pub use engine::{Engine, Worker};
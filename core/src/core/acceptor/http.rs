@@ -1,12 +1,13 @@
+use super::{PendingConnection, Rejection};
 use crate::core::{endpoint::Endpoint, io::Io};
 use anyhow::{bail, ensure, Result};
 use bytes::Bytes;
-use futures::{Future, FutureExt};
+use futures::{future::BoxFuture, FutureExt, TryFutureExt};
 use http::{
     header::{CONNECTION, HOST, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION},
-    Method, Request, Response,
+    Method, Request, Response, StatusCode,
 };
-use http_body_util::{Either, Empty};
+use http_body_util::{Either, Full};
 use hyper::{
     body::Incoming, client::conn::http1::SendRequest, server::conn::http1::Builder,
     service::service_fn,
@@ -14,21 +15,38 @@ use hyper::{
 use hyper_util::rt::TokioIo;
 use std::{str::FromStr, sync::Arc};
 use tokio::{
-    io::duplex,
+    io::{duplex, AsyncWriteExt},
     sync::{
         oneshot::{channel, Receiver, Sender},
         Mutex,
     },
 };
 
+type Body = Either<Incoming, Full<Bytes>>;
+
 enum State {
     NotConnected(Option<ConnectSignal>),
     Connected((Endpoint, SendRequest<Incoming>)),
 }
 
+// How to answer the first request.
+enum Done {
+    Tunnel,
+    Forward(SendRequest<Incoming>),
+    Reject(StatusCode, String),
+    Tarpit,
+}
+
 struct ConnectSignal {
     endpoint_tx: Sender<(bool, Endpoint)>,
-    done_rx: Receiver<Option<SendRequest<Incoming>>>,
+    done_rx: Receiver<Done>,
+}
+
+fn reject_response(status: StatusCode, body: String) -> Result<Response<Body>> {
+    Ok(Response::builder()
+        .status(status)
+        .header(CONNECTION, "close")
+        .body(Either::Right(Full::new(body.into())))?)
 }
 
 fn transform_proxy_request(mut request: Request<Incoming>) -> Option<Request<Incoming>> {
@@ -50,10 +68,7 @@ fn transform_proxy_request(mut request: Request<Incoming>) -> Option<Request<Inc
     Some(request)
 }
 
-async fn handler(
-    request: Request<Incoming>,
-    state: Arc<Mutex<State>>,
-) -> Result<Response<Either<Incoming, Empty<Bytes>>>> {
+async fn handler(request: Request<Incoming>, state: Arc<Mutex<State>>) -> Result<Response<Body>> {
     let mut state = state.lock().await;
 
     if matches!(request.method(), &Method::CONNECT) {
@@ -64,12 +79,16 @@ async fn handler(
                     .send((true, Endpoint::from_str(&request.uri().to_string())?))
                     .expect("the other side should not be released");
 
-                signal
+                return match signal
                     .done_rx
                     .await
-                    .expect("the done signal should be sent before polling the connection");
-
-                return Ok(Response::new(Either::Right(Empty::new())));
+                    .expect("the done signal should be sent before polling the connection")
+                {
+                    Done::Tunnel => Ok(Response::new(Either::Right(Full::default()))),
+                    Done::Reject(status, body) => reject_response(status, body),
+                    Done::Tarpit => futures::future::pending().await,
+                    Done::Forward(_) => unreachable!(),
+                };
             }
         }
         bail!("The CONNECT method can only be send in the first header")
@@ -89,11 +108,16 @@ async fn handler(
                         .send((false, endpoint.clone()))
                         .expect("the other side should not be released");
 
-                    let mut send_request = signal
+                    let mut send_request = match signal
                         .done_rx
                         .await
                         .expect("the done signal should be sent before polling the connection")
-                        .unwrap();
+                    {
+                        Done::Forward(send_request) => send_request,
+                        Done::Reject(status, body) => return reject_response(status, body),
+                        Done::Tarpit => futures::future::pending().await,
+                        Done::Tunnel => unreachable!(),
+                    };
 
                     let request = transform_proxy_request(request)
                         .ok_or_else(|| anyhow::anyhow!("Not a valid proxy request"))?;
@@ -136,9 +160,72 @@ async fn handler(
     }
 }
 
-pub async fn handshake(
-    io: impl Io,
-) -> Result<(Endpoint, impl Future<Output = Result<impl Io>> + Send)> {
+pub struct HttpPendingConnection<I: Io> {
+    is_connect: bool,
+    conn: BoxFuture<'static, hyper::Result<I>>,
+    done_tx: Sender<Done>,
+}
+
+impl<I: Io> HttpPendingConnection<I> {
+    fn send_done(done_tx: Sender<Done>, done: Done) {
+        // This should never error since we are not polling the other side, so
+        // the receiver should not be deallocated.
+        if done_tx.send(done).is_err() {
+            panic!("bug: the done signal receiver should not be deallocated");
+        }
+    }
+}
+
+impl<I: Io> PendingConnection for HttpPendingConnection<I> {
+    async fn accept(self) -> Result<impl Io> {
+        if self.is_connect {
+            Self::send_done(self.done_tx, Done::Tunnel);
+
+            let io: Box<dyn Io> = Box::new(self.conn.await?);
+            Ok(io)
+        } else {
+            // 64KB
+            let (s1, s2) = duplex(65536);
+
+            let (request_sender, connection) =
+                hyper::client::conn::http1::handshake(TokioIo::new(s1)).await?;
+
+            Self::send_done(self.done_tx, Done::Forward(request_sender));
+
+            // We don't really care the error from here since it will drop the connection.
+            // We will then read the EOF from the other side.
+            tokio::task::spawn(self.conn);
+            tokio::task::spawn(connection);
+
+            let io: Box<dyn Io> = Box::new(s2);
+            Ok(io)
+        }
+    }
+
+    async fn reject(self, rejection: Rejection) -> Result<()> {
+        let done = match rejection {
+            Rejection::Http { status, body } => Done::Reject(StatusCode::from_u16(status)?, body),
+            Rejection::Socks5(_) => Done::Reject(StatusCode::FORBIDDEN, String::new()),
+            Rejection::Tarpit => Done::Tarpit,
+            Rejection::Drop => return Ok(()),
+        };
+
+        let is_tarpit = matches!(done, Done::Tarpit);
+        Self::send_done(self.done_tx, done);
+
+        let result = self.conn.await;
+        // The client gives up, that's what we want.
+        if is_tarpit {
+            return Ok(());
+        }
+
+        result?.shutdown().await?;
+
+        Ok(())
+    }
+}
+
+pub async fn handshake<I: Io>(io: I) -> Result<(Endpoint, HttpPendingConnection<I>)> {
     let (endpoint_tx, endpoint_rx) = channel();
     let (done_tx, done_rx) = channel();
 
@@ -158,7 +245,9 @@ pub async fn handshake(
                 .boxed()
             }),
         )
-        .without_shutdown();
+        .without_shutdown()
+        .map_ok(|part| part.io.into_inner())
+        .boxed();
 
     let endpoint = tokio::select! {
         _ = &mut conn => {
@@ -173,52 +262,22 @@ pub async fn handshake(
         }
     };
 
-    if endpoint.0 {
-        Ok((
-            endpoint.1,
-            async move {
-                done_tx
-                    .send(None)
-                    .expect("bug: the done signal receiver should not be deallocated");
-
-                let part = conn.await?;
-
-                let io: Box<dyn Io> = Box::new(part.io.into_inner());
-                Ok(io)
-            }
-            .boxed(),
-        ))
-    } else {
-        Ok((
-            endpoint.1,
-            async move {
-                // 64KB
-                let (s1, s2) = duplex(65536);
-
-                let (request_sender, connection) =
-                    hyper::client::conn::http1::handshake(TokioIo::new(s1)).await?;
-
-                done_tx
-                    .send(Some(request_sender))
-                    .expect("bug: the done signal receiver should not be deallocated");
-
-                // We don't really care the error from here since it will drop the connection.
-                // We will then read the EOF from the other side.
-                tokio::task::spawn(conn);
-                tokio::task::spawn(connection);
-
-                let io: Box<dyn Io> = Box::new(s2);
-                Ok(io)
-            }
-            .boxed(),
-        ))
-    }
+    Ok((
+        endpoint.1,
+        HttpPendingConnection {
+            is_connect: endpoint.0,
+            conn,
+            done_tx,
+        },
+    ))
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     use http::Uri;
     use rstest::*;
+    use tokio::io::AsyncReadExt;
 
     // Make sure the Uri crate would parse the data as we expected
     #[rstest]
@@ -235,4 +294,48 @@ mod tests {
         let pq = case.path_and_query().map(|p| p.as_str());
         assert_eq!(pq, expected);
     }
+
+    #[rstest]
+    #[case("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n")]
+    #[case("GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n")]
+    #[tokio::test]
+    async fn test_reject(#[case] request: &str) -> Result<()> {
+        let (mut client, server) = duplex(1024);
+
+        client.write_all(request.as_bytes()).await?;
+
+        let (_, pending) = handshake(server).await?;
+        pending
+            .reject(Rejection::Http {
+                status: 403,
+                body: "blocked".to_owned(),
+            })
+            .await?;
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await?;
+
+        assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+        assert!(response.ends_with("blocked"), "{}", response);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drop() -> Result<()> {
+        let (mut client, server) = duplex(1024);
+
+        client
+            .write_all(b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await?;
+
+        let (_, pending) = handshake(server).await?;
+        pending.reject(Rejection::Drop).await?;
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await?;
+        assert!(response.is_empty());
+
+        Ok(())
+    }
 }
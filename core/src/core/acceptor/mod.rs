@@ -1,11 +1,61 @@
 pub mod http;
+pub mod mux;
 pub mod socks5;
 
 use crate::{
     core::{endpoint::Endpoint, io::Io},
     Result,
 };
+use anyhow::bail;
 use futures::{Future, Stream, TryFutureExt, TryStreamExt};
+use std::net::IpAddr;
+use tokio::io::{copy, sink};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    // SOCKS5 clients get "connection not allowed by ruleset" instead.
+    Http { status: u16, body: String },
+    // HTTP clients get 403 instead.
+    Socks5(u8),
+    // Keep the connection open without ever answering.
+    Tarpit,
+    // Close the connection without answering.
+    Drop,
+}
+
+/// A connection that finished the handshake and is waiting to learn whether
+/// the request is accepted.
+pub trait PendingConnection {
+    /// Tell the client the request is accepted and return the stream.
+    fn accept(self) -> impl Future<Output = Result<impl Io>>;
+
+    fn reject(self, rejection: Rejection) -> impl Future<Output = Result<()>>;
+
+    /// Whether the client asks to relay UDP instead of opening a stream.
+    fn is_udp(&self) -> bool {
+        false
+    }
+
+    /// Start relaying UDP from a socket bound to `bind_ip` for the client
+    /// connected from `client_ip`.
+    fn associate(
+        self,
+        _bind_ip: IpAddr,
+        _client_ip: IpAddr,
+    ) -> impl Future<Output = Result<socks5::UdpAssociation>>
+    where
+        Self: Sized,
+    {
+        async { bail!("UDP is not supported by this acceptor") }
+    }
+}
+
+// Swallow whatever the client sends until it gives up.
+async fn tarpit(mut io: impl Io) -> Result<()> {
+    copy(&mut io, &mut sink()).await?;
+
+    Ok(())
+}
 
 pub fn handle_connection_stream<
     Input: Io,
@@ -0,0 +1,174 @@
+use super::{tarpit, PendingConnection, Rejection};
+use crate::{
+    core::{
+        endpoint::Endpoint,
+        io::Io,
+        mux::{accept, MuxStream, SESSION_ENDPOINT},
+        simplex::{server::handshake as simplex_handshake, Config},
+    },
+    Result,
+};
+use futures::{
+    future::{ready, LocalBoxFuture},
+    stream::{once, LocalBoxStream},
+    FutureExt, Stream, StreamExt, TryStreamExt,
+};
+use tokio::io::AsyncWriteExt;
+
+pub struct MuxPendingConnection {
+    stream: MuxStream,
+}
+
+impl PendingConnection for MuxPendingConnection {
+    async fn accept(self) -> Result<impl Io> {
+        Ok(self.stream)
+    }
+
+    // There is no reply in the mux protocol, the client just sees the stream
+    // closed.
+    async fn reject(mut self, rejection: Rejection) -> Result<()> {
+        match rejection {
+            Rejection::Tarpit => tarpit(self.stream).await,
+            Rejection::Drop => Ok(()),
+            Rejection::Http { .. } | Rejection::Socks5(_) => Ok(self.stream.shutdown().await?),
+        }
+    }
+}
+
+// Serve a mux session over any `Io`.
+pub fn handshake(io: impl Io) -> impl Stream<Item = Result<(Endpoint, MuxPendingConnection)>> {
+    accept(io).map_ok(|(endpoint, stream)| (endpoint, MuxPendingConnection { stream }))
+}
+
+pub enum SimplexPendingConnection {
+    Tunnel(LocalBoxFuture<'static, Result<Box<dyn Io>>>),
+    Mux(MuxPendingConnection),
+}
+
+impl PendingConnection for SimplexPendingConnection {
+    async fn accept(self) -> Result<impl Io> {
+        let io: Box<dyn Io> = match self {
+            Self::Tunnel(upgrade) => upgrade.await?,
+            Self::Mux(pending) => Box::new(pending.stream),
+        };
+
+        Ok(io)
+    }
+
+    async fn reject(self, rejection: Rejection) -> Result<()> {
+        match self {
+            // The websocket is never upgraded.
+            Self::Tunnel(_) => Ok(()),
+            Self::Mux(pending) => pending.reject(rejection).await,
+        }
+    }
+}
+
+// Serve a simplex connection. Clients asking for `SESSION_ENDPOINT` get a mux
+// session carrying many tunnels, any other endpoint is a single tunnel.
+pub async fn simplex_mux_handshake(
+    io: impl Io,
+    config: Config,
+) -> Result<LocalBoxStream<'static, Result<(Endpoint, SimplexPendingConnection)>>> {
+    let (endpoint, upgrade) = simplex_handshake(io, config).await?;
+
+    if endpoint.to_string() == SESSION_ENDPOINT {
+        Ok(handshake(upgrade.await?)
+            .map_ok(|(endpoint, pending)| (endpoint, SimplexPendingConnection::Mux(pending)))
+            .boxed_local())
+    } else {
+        let upgrade = upgrade
+            .map(|result| result.map(|io| Box::new(io) as Box<dyn Io>))
+            .boxed_local();
+
+        Ok(once(ready(Ok((
+            endpoint,
+            SimplexPendingConnection::Tunnel(upgrade),
+        ))))
+        .boxed_local())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{mux::Session, simplex::client::connect as simplex_connect};
+    use rstest::rstest;
+    use tokio::{
+        io::{duplex, AsyncReadExt},
+        task::LocalSet,
+    };
+
+    fn config() -> Config {
+        Config::new(
+            "example.com".to_owned(),
+            "/tunnel".to_owned(),
+            ("Simplex-Secret".to_owned(), "secret".to_owned()),
+        )
+    }
+
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    #[tokio::test]
+    async fn test_simplex(#[case] mux: bool) -> Result<()> {
+        let (client, server) = duplex(65536);
+
+        let server = async move {
+            let mut connections = simplex_mux_handshake(server, config()).await?;
+
+            let mut endpoints = Vec::new();
+            while let Some((endpoint, pending)) = connections.try_next().await? {
+                let mut io = pending.accept().await?;
+                io.write_all(b"pong").await?;
+                io.flush().await?;
+
+                endpoints.push(endpoint.to_string());
+                if endpoints.len() == 2 || !mux {
+                    break;
+                }
+            }
+
+            anyhow::Ok(endpoints)
+        };
+
+        let client = async move {
+            let mut responses = Vec::new();
+
+            if mux {
+                let io = simplex_connect(client, &SESSION_ENDPOINT.parse()?, &config()).await?;
+                let session = Session::client(io);
+
+                for endpoint in ["example.com:443", "example.org:80"] {
+                    let mut stream = session.open(&endpoint.parse()?).await?;
+                    let mut response = [0; 4];
+                    stream.read_exact(&mut response).await?;
+                    responses.push(response);
+                }
+            } else {
+                let mut io =
+                    simplex_connect(client, &"example.com:443".parse()?, &config()).await?;
+                let mut response = [0; 4];
+                io.read_exact(&mut response).await?;
+                responses.push(response);
+            }
+
+            anyhow::Ok(responses)
+        };
+
+        // The mux sessions are driven by local tasks.
+        let (endpoints, responses) = LocalSet::new()
+            .run_until(async { tokio::join!(server, client) })
+            .await;
+
+        if mux {
+            assert_eq!(endpoints?, ["example.com:443", "example.org:80"]);
+            assert_eq!(responses?, [*b"pong", *b"pong"]);
+        } else {
+            assert_eq!(endpoints?, ["example.com:443"]);
+            assert_eq!(responses?, [*b"pong"]);
+        }
+
+        Ok(())
+    }
+}
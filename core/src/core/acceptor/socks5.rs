@@ -1,15 +1,183 @@
+use super::{tarpit, PendingConnection, Rejection};
 use crate::{
-    core::{endpoint::Endpoint, io::Io},
+    core::{
+        datagram::{decode_address, encode_address, MAX_DATAGRAM_SIZE},
+        endpoint::Endpoint,
+        io::Io,
+    },
     Result,
 };
 use anyhow::{bail, ensure, Context};
-use futures::Future;
-use std::net::{IpAddr, SocketAddr};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::{
+    cell::Cell,
+    net::{IpAddr, SocketAddr},
+    rc::Rc,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+};
+use tracing::debug;
+
+const COMMAND_CONNECT: u8 = 1;
+const COMMAND_UDP_ASSOCIATE: u8 = 3;
+
+const REPLY_SUCCEEDED: u8 = 0;
+const REPLY_NOT_ALLOWED: u8 = 2;
+
+pub struct Socks5PendingConnection<I: Io> {
+    io: I,
+    command: u8,
+    request_type: u8,
+    // The port the client says it will send UDP packets from, if it knows.
+    udp_port: Option<u16>,
+}
+
+// Sends packets back to the client of an association.
+#[derive(Clone, Debug)]
+pub struct UdpReplier {
+    socket: Rc<UdpSocket>,
+    // Only one address is served, the first one sending to the relay from the
+    // IP of the TCP connection unless the client told its port up front.
+    client: Rc<Cell<Option<SocketAddr>>>,
+}
+
+impl UdpReplier {
+    pub async fn send(&self, payload: &[u8], from: &Endpoint) -> Result<()> {
+        let Some(client) = self.client.get() else {
+            bail!("The UDP association has no client yet")
+        };
+
+        let mut packet = vec![0, 0, 0];
+        encode_address(from, &mut packet)?;
+        packet.extend_from_slice(payload);
+        self.socket.send_to(&packet, client).await?;
+
+        Ok(())
+    }
+}
+
+// The UDP relay of a client, which lasts as long as its TCP connection.
+#[derive(Debug)]
+pub struct UdpAssociation {
+    control: Box<dyn Io>,
+    client_ip: IpAddr,
+    replier: UdpReplier,
+}
+
+impl UdpAssociation {
+    pub fn replier(&self) -> UdpReplier {
+        self.replier.clone()
+    }
+
+    // Returns the next packet from the client along with its target, or `None`
+    // once the client closes the TCP connection.
+    pub async fn recv(&mut self) -> Result<Option<(Vec<u8>, Endpoint)>> {
+        let mut packet = vec![0; MAX_DATAGRAM_SIZE];
+        let mut control = [0; 1];
+
+        loop {
+            tokio::select! {
+                result = self.replier.socket.recv_from(&mut packet) => {
+                    let (len, from) = result?;
+
+                    // Anyone else could take over the relay otherwise.
+                    match self.replier.client.get() {
+                        Some(client) if client != from => continue,
+                        Some(_) => {}
+                        None if from.ip().to_canonical() != self.client_ip => continue,
+                        None => self.replier.client.set(Some(from)),
+                    }
+
+                    // Fragmentation is optional and we don't support it.
+                    let packet = &packet[..len];
+                    if packet.len() < 3 || packet[2] != 0 {
+                        continue;
+                    }
+
+                    match decode_address(&packet[3..]) {
+                        Ok((target, header_len)) => {
+                            return Ok(Some((packet[3 + header_len..].to_vec(), target)))
+                        }
+                        Err(e) => debug!("Dropped malformed packet from socks5 client: {}", e),
+                    }
+                }
+                read = self.control.read(&mut control) => {
+                    if matches!(read, Ok(0) | Err(_)) {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<I: Io> Socks5PendingConnection<I> {
+    async fn reply(&mut self, code: u8) -> Result<()> {
+        let response: &[u8] = match self.request_type {
+            1 | 3 => &[5, code, 0, 1, 0, 0, 0, 0, 0, 0],
+            4 => &[
+                5, code, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            ],
+            _ => unreachable!(),
+        };
+
+        Ok(self.io.write_all(response).await?)
+    }
+}
+
+impl<I: Io> PendingConnection for Socks5PendingConnection<I> {
+    async fn accept(mut self) -> Result<impl Io> {
+        self.reply(REPLY_SUCCEEDED).await?;
+
+        Ok(self.io)
+    }
+
+    async fn reject(mut self, rejection: Rejection) -> Result<()> {
+        let code = match rejection {
+            Rejection::Http { .. } => REPLY_NOT_ALLOWED,
+            Rejection::Socks5(code) => code,
+            Rejection::Tarpit => return tarpit(self.io).await,
+            Rejection::Drop => return Ok(()),
+        };
+
+        self.reply(code).await?;
+        self.io.shutdown().await?;
+
+        Ok(())
+    }
+
+    fn is_udp(&self) -> bool {
+        self.command == COMMAND_UDP_ASSOCIATE
+    }
 
-pub async fn handshake(
-    mut io: impl Io,
-) -> Result<(Endpoint, impl Future<Output = Result<impl Io>>)> {
+    async fn associate(mut self, bind_ip: IpAddr, client_ip: IpAddr) -> Result<UdpAssociation> {
+        let socket = UdpSocket::bind((bind_ip, 0)).await?;
+
+        let mut response = vec![5, REPLY_SUCCEEDED, 0];
+        encode_address(
+            &Endpoint::new_from_addr(socket.local_addr()?),
+            &mut response,
+        )?;
+        self.io.write_all(&response).await?;
+
+        // The address the client sends is ignored, a client behind NAT only
+        // knows its private one.
+        let client_ip = client_ip.to_canonical();
+        let client = self.udp_port.map(|port| SocketAddr::new(client_ip, port));
+
+        Ok(UdpAssociation {
+            control: Box::new(self.io),
+            client_ip,
+            replier: UdpReplier {
+                socket: Rc::new(socket),
+                client: Rc::new(Cell::new(client)),
+            },
+        })
+    }
+}
+
+pub async fn handshake<I: Io>(mut io: I) -> Result<(Endpoint, Socks5PendingConnection<I>)> {
     // Read hello
     let mut buf = [0; 2];
     io.read_exact(&mut buf).await?;
@@ -41,10 +209,11 @@ pub async fn handshake(
 
     ensure!(buf[0] == 5, "Unsupported socks version: {}", buf[0]);
 
+    let command = buf[1];
     ensure!(
-        buf[1] == 1,
-        "Invalid socks5 command: {}, only 1 is supported",
-        buf[1]
+        command == COMMAND_CONNECT || command == COMMAND_UDP_ASSOCIATE,
+        "Invalid socks5 command: {}, only 1 and 3 are supported",
+        command
     );
 
     enum IpOrDomain {
@@ -83,16 +252,162 @@ pub async fn handshake(
         IpOrDomain::Ip(ip) => Endpoint::new_from_addr(SocketAddr::new(ip, port)),
     };
 
-    let response: &[u8] = match request_type {
-        1 | 3 => &[5, 0, 0, 1, 0, 0, 0, 0, 0, 0],
-        4 => &[
-            5, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        ],
-        _ => unreachable!(),
+    // All zeros means the client doesn't know where it will send from.
+    let udp_port =
+        Some(endpoint.port()).filter(|port| command == COMMAND_UDP_ASSOCIATE && *port != 0);
+
+    Ok((
+        endpoint,
+        Socks5PendingConnection {
+            io,
+            command,
+            request_type,
+            udp_port,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        connector::socks5::associate, datagram::Datagram, resolver::system::SystemResolver,
     };
+    use rstest::rstest;
+    use tokio::{io::duplex, net::TcpListener};
+
+    #[rstest]
+    #[case(Rejection::Socks5(5), 5)]
+    #[case(Rejection::Http { status: 403, body: String::new() }, REPLY_NOT_ALLOWED)]
+    #[tokio::test]
+    async fn test_reject(#[case] rejection: Rejection, #[case] code: u8) -> Result<()> {
+        let (mut client, server) = duplex(1024);
+
+        // Hello with no authentication, then connect to 127.0.0.1:80.
+        client.write_all(&[5, 1, 0]).await?;
+        client.write_all(&[5, 1, 0, 1, 127, 0, 0, 1, 0, 80]).await?;
+
+        let (endpoint, pending) = handshake(server).await?;
+        assert_eq!(endpoint.to_string(), "127.0.0.1:80");
+        pending.reject(rejection).await?;
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await?;
+        assert_eq!(response[..2], [5, 0]);
+        assert_eq!(response[2..4], [5, code]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_udp_associate() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server = Endpoint::new_from_addr(listener.local_addr()?);
+
+        // Echo packets back as if they came from their targets.
+        let relay = async move {
+            let (_, pending) = handshake(listener.accept().await?.0).await?;
+            assert!(pending.is_udp());
+
+            let mut association = pending
+                .associate([127, 0, 0, 1].into(), [127, 0, 0, 1].into())
+                .await?;
+            while let Some((payload, target)) = association.recv().await? {
+                association.replier().send(&payload, &target).await?;
+            }
+
+            anyhow::Ok(())
+        };
 
-    Ok((endpoint, async move {
-        io.write_all(response).await?;
-        Ok(io)
-    }))
+        let client = async move {
+            let datagram = associate(&server, SystemResolver::new()).await?;
+
+            for target in ["example.com:53", "127.0.0.1:53", "[::1]:53"] {
+                datagram.send_to(b"ping", &target.parse()?).await?;
+
+                let mut buf = [0; 1024];
+                let (len, from) = datagram.recv_from(&mut buf).await?;
+                assert_eq!(&buf[..len], b"ping");
+                assert_eq!(from.to_string(), target);
+            }
+
+            anyhow::Ok(())
+        };
+
+        let (relay, client) = tokio::join!(relay, client);
+        client?;
+        relay?;
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    #[tokio::test]
+    async fn test_udp_associate_other_source(#[case] announce: bool) -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let attacker = UdpSocket::bind("127.0.0.2:0").await?;
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        // Some other port on the client's IP.
+        let other = UdpSocket::bind("127.0.0.1:0").await?;
+
+        let relay = async move {
+            let (_, pending) = handshake(listener.accept().await?.0).await?;
+            let mut association = pending
+                .associate([127, 0, 0, 1].into(), [127, 0, 0, 1].into())
+                .await?;
+
+            let (payload, _) = association.recv().await?.unwrap();
+            association
+                .replier()
+                .send(&payload, &"127.0.0.1:53".parse()?)
+                .await?;
+
+            anyhow::Ok(payload)
+        };
+
+        let sender = async {
+            let mut control = tokio::net::TcpStream::connect(addr).await?;
+            let port = if announce {
+                client.local_addr()?.port()
+            } else {
+                0
+            };
+            control.write_all(&[5, 1, 0]).await?;
+            control.write_all(&[5, 3, 0, 1, 0, 0, 0, 0]).await?;
+            control.write_all(&port.to_be_bytes()).await?;
+
+            let mut reply = [0; 12];
+            control.read_exact(&mut reply).await?;
+            let relay = SocketAddr::new(
+                IpAddr::from([reply[6], reply[7], reply[8], reply[9]]),
+                u16::from_be_bytes([reply[10], reply[11]]),
+            );
+
+            // Only the client's own packet is relayed, even though the others
+            // arrive first.
+            let packet = |payload: &[u8]| [&[0, 0, 0, 1, 127, 0, 0, 1, 0, 53], payload].concat();
+            attacker.send_to(&packet(b"attacker"), relay).await?;
+            if announce {
+                other.send_to(&packet(b"other"), relay).await?;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            client.send_to(&packet(b"client"), relay).await?;
+
+            let mut buf = [0; 1024];
+            let (len, _) = client.recv_from(&mut buf).await?;
+            assert!(buf[..len].ends_with(b"client"));
+
+            anyhow::Ok(control)
+        };
+
+        let (relay, sender) = tokio::join!(relay, sender);
+        let _control = sender?;
+        assert_eq!(relay?, b"client");
+
+        Ok(())
+    }
 }
@@ -0,0 +1,317 @@
+use crate::Result;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{remove_file, rename, File, OpenOptions},
+    io::{BufWriter, ErrorKind, Write},
+    iter,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex, PoisonError, Weak,
+    },
+    thread::{self, JoinHandle},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    // Relayed until either side closed the connection.
+    Closed,
+    Rejected,
+    Failed,
+    // A UDP association relayed until the client closed its TCP connection.
+    Associated,
+}
+
+/// One line of the access log.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccessRecord {
+    pub id: u64,
+    pub client: SocketAddr,
+    pub acceptor: String,
+    // None when the handshake failed before the client asked for one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    // Set by the handler to describe the route it picked.
+    pub chain: Option<String>,
+    pub start: DateTime<Utc>,
+    pub duration_ms: u64,
+    pub up: u64,
+    pub down: u64,
+    pub outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessLogConfig {
+    pub path: PathBuf,
+    // Rotate once the log would grow beyond this many bytes.
+    pub max_size: u64,
+    // Rotated logs to keep, as `path.1` (the newest) to `path.<max_files>`.
+    pub max_files: usize,
+}
+
+impl AccessLogConfig {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            max_size: 64 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{index}"));
+
+    name.into()
+}
+
+fn ignore_not_found(result: std::io::Result<()>) -> std::io::Result<()> {
+    match result {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+// A line of a log along with its rotation settings, which may change when
+// the config is reloaded.
+struct Line {
+    bytes: Vec<u8>,
+    max_size: u64,
+    max_files: usize,
+}
+
+#[derive(Debug)]
+struct Writer {
+    path: PathBuf,
+    file: Option<BufWriter<File>>,
+    size: u64,
+}
+
+impl Writer {
+    fn run(mut self, lines: Receiver<Line>) {
+        while let Ok(line) = lines.recv() {
+            // Flushed once there is nothing more to write right away.
+            for line in iter::once(line).chain(lines.try_iter()) {
+                if let Err(e) = self.write(&line) {
+                    tracing::warn!("Failed to write access log: {:?}", e);
+                }
+            }
+
+            if let Some(Err(e)) = self.file.as_mut().map(Write::flush) {
+                tracing::warn!("Failed to write access log: {:?}", e);
+            }
+        }
+    }
+
+    fn write(&mut self, line: &Line) -> Result<()> {
+        let len = line.bytes.len() as u64;
+
+        if self.file.is_none() {
+            let opened = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            self.size = opened.metadata()?.len();
+            self.file = Some(BufWriter::new(opened));
+        }
+
+        if self.size > 0 && self.size + len > line.max_size {
+            if let Some(mut file) = self.file.take() {
+                file.flush()?;
+            }
+            self.rotate(line.max_files)?;
+
+            self.file = Some(BufWriter::new(File::create(&self.path)?));
+            self.size = 0;
+        }
+
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(&line.bytes)?;
+        }
+        self.size += len;
+
+        Ok(())
+    }
+
+    fn rotate(&self, max_files: usize) -> Result<()> {
+        let path = &self.path;
+
+        if max_files == 0 {
+            return Ok(ignore_not_found(remove_file(path))?);
+        }
+
+        for index in (1..max_files).rev() {
+            ignore_not_found(rename(rotated(path, index), rotated(path, index + 1)))?;
+        }
+        rename(path, rotated(path, 1))?;
+
+        Ok(())
+    }
+}
+
+// The thread writing the log on a path.
+#[derive(Debug)]
+struct Shared {
+    lines: Option<Sender<Line>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Shared {
+    // Waits for the lines sent so far to be written.
+    fn drop(&mut self) {
+        self.lines.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    // Workers logging to the same path share the writer, so their lines don't
+    // interleave and the log is rotated once.
+    static ref WRITERS: Mutex<HashMap<PathBuf, Weak<Shared>>> = Mutex::default();
+}
+
+/// Appends records as JSON lines. They are written and rotated by a thread of
+/// their own, so the connections never wait on the disk.
+#[derive(Debug)]
+pub struct AccessLog {
+    config: AccessLogConfig,
+    shared: Arc<Shared>,
+}
+
+impl AccessLog {
+    pub fn new(config: AccessLogConfig) -> Result<Self> {
+        let mut writers = WRITERS.lock().unwrap_or_else(PoisonError::into_inner);
+        writers.retain(|_, shared| shared.strong_count() > 0);
+
+        let shared = match writers.get(&config.path).and_then(Weak::upgrade) {
+            Some(shared) => shared,
+            None => {
+                let (lines, receiver) = channel();
+                let writer = Writer {
+                    path: config.path.clone(),
+                    file: None,
+                    size: 0,
+                };
+                let thread = thread::Builder::new()
+                    .name("access-log".to_owned())
+                    .spawn(move || writer.run(receiver))?;
+
+                let shared = Arc::new(Shared {
+                    lines: Some(lines),
+                    thread: Some(thread),
+                });
+                writers.insert(config.path.clone(), Arc::downgrade(&shared));
+                shared
+            }
+        };
+
+        Ok(Self { config, shared })
+    }
+
+    pub fn write(&self, record: &AccessRecord) -> Result<()> {
+        let mut bytes = serde_json::to_vec(record)?;
+        bytes.push(b'\n');
+
+        self.shared
+            .lines
+            .as_ref()
+            .and_then(|lines| {
+                lines
+                    .send(Line {
+                        bytes,
+                        max_size: self.config.max_size,
+                        max_files: self.config.max_files,
+                    })
+                    .ok()
+            })
+            .context("The access log writer has stopped")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use std::fs::read_to_string;
+
+    fn record(id: u64) -> AccessRecord {
+        AccessRecord {
+            id,
+            client: "127.0.0.1:50000".parse().unwrap(),
+            acceptor: "socks5://127.0.0.1:1080".to_owned(),
+            target: Some("example.com:443".to_owned()),
+            chain: Some("direct".to_owned()),
+            start: DateTime::from_timestamp(0, 0).unwrap(),
+            duration_ms: 10,
+            up: 100,
+            down: 200,
+            outcome: Outcome::Closed,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_record() -> Result<()> {
+        assert_eq!(
+            serde_json::to_string(&record(1))?,
+            r#"{"id":1,"client":"127.0.0.1:50000","acceptor":"socks5://127.0.0.1:1080","target":"example.com:443","chain":"direct","start":"1970-01-01T00:00:00Z","duration_ms":10,"up":100,"down":200,"outcome":"closed"}"#
+        );
+
+        Ok(())
+    }
+
+    // Logs on the same path, as every worker has, write as one.
+    #[rstest]
+    #[case(1)]
+    #[case(2)]
+    fn test_rotation(#[case] logs: usize) -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("access.log");
+        let line_len = serde_json::to_vec(&record(0))?.len() as u64 + 1;
+
+        // Two lines fit in a log.
+        let logs: Vec<AccessLog> = (0..logs)
+            .map(|_| {
+                AccessLog::new(AccessLogConfig {
+                    path: path.clone(),
+                    max_size: line_len * 2,
+                    max_files: 2,
+                })
+            })
+            .collect::<Result<_>>()?;
+        for id in 0..7 {
+            logs[id as usize % logs.len()].write(&record(id))?;
+        }
+        // Waits for the lines to be written.
+        drop(logs);
+
+        let ids = |path: &Path| -> Result<Vec<u64>> {
+            read_to_string(path)?
+                .lines()
+                .map(|line| {
+                    Ok(serde_json::from_str::<serde_json::Value>(line)?["id"]
+                        .as_u64()
+                        .unwrap())
+                })
+                .collect()
+        };
+
+        assert_eq!(ids(&path)?, [6]);
+        assert_eq!(ids(&rotated(&path, 1))?, [4, 5]);
+        assert_eq!(ids(&rotated(&path, 2))?, [2, 3]);
+        assert!(!rotated(&path, 3).exists());
+
+        Ok(())
+    }
+}
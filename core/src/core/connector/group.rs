@@ -0,0 +1,347 @@
+use super::Unreachable;
+use crate::core::endpoint::Endpoint;
+use anyhow::{ensure, Context, Result};
+use futures::Future;
+use rand::Rng;
+use std::{
+    cell::{Cell, RefCell},
+    hash::{DefaultHasher, Hash, Hasher},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    RoundRobin,
+    Random,
+    // Rendezvous hashing on the target host, so a host sticks to the same
+    // member and only the hosts of a down member get moved elsewhere.
+    ConsistentHash,
+    Failover,
+}
+
+#[derive(Debug, Default)]
+struct MemberState {
+    failures: u32,
+    down_until: Option<Instant>,
+}
+
+impl MemberState {
+    fn is_up(&self, now: Instant) -> bool {
+        self.down_until.is_none_or(|until| until <= now)
+    }
+}
+
+#[derive(Debug)]
+pub struct Group {
+    policy: Policy,
+    next: Cell<usize>,
+    members: RefCell<Vec<MemberState>>,
+}
+
+impl Group {
+    pub fn new(policy: Policy, size: usize) -> Result<Self> {
+        ensure!(size > 0, "An outbound group needs at least one member");
+
+        Ok(Self {
+            policy,
+            next: Cell::new(0),
+            members: RefCell::new((0..size).map(|_| MemberState::default()).collect()),
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.members.borrow().len()
+    }
+
+    pub fn is_up(&self, index: usize) -> bool {
+        self.members
+            .borrow()
+            .get(index)
+            .is_some_and(|m| m.is_up(Instant::now()))
+    }
+
+    pub fn mark_up(&self, index: usize) {
+        self.members.borrow_mut()[index] = MemberState::default();
+    }
+
+    // Each consecutive failure doubles the time the member is kept out.
+    pub fn mark_down(&self, index: usize) {
+        let mut members = self.members.borrow_mut();
+        let member = &mut members[index];
+
+        let backoff = INITIAL_BACKOFF
+            .saturating_mul(1 << member.failures.min(16))
+            .min(MAX_BACKOFF);
+        member.failures = member.failures.saturating_add(1);
+        member.down_until = Some(Instant::now() + backoff);
+    }
+
+    /// The members to try for `host`, in order. Members that are down are
+    /// skipped unless all of them are down.
+    pub fn candidates(&self, host: &str) -> Vec<usize> {
+        let len = self.len();
+
+        let order: Vec<usize> = match self.policy {
+            Policy::RoundRobin => {
+                let start = self.next.get();
+                self.next.set((start + 1) % len);
+                (0..len).map(|i| (start + i) % len).collect()
+            }
+            Policy::Random => {
+                let start = rand::rng().random_range(0..len);
+                (0..len).map(|i| (start + i) % len).collect()
+            }
+            Policy::ConsistentHash => {
+                let mut order: Vec<usize> = (0..len).collect();
+                order.sort_by_key(|&i| {
+                    let mut hasher = DefaultHasher::new();
+                    (host, i).hash(&mut hasher);
+                    std::cmp::Reverse(hasher.finish())
+                });
+                order
+            }
+            Policy::Failover => (0..len).collect(),
+        };
+
+        let now = Instant::now();
+        let members = self.members.borrow();
+        let up: Vec<usize> = order
+            .iter()
+            .copied()
+            .filter(|&i| members[i].is_up(now))
+            .collect();
+
+        if up.is_empty() {
+            order
+        } else {
+            up
+        }
+    }
+}
+
+// A member is only marked down when its own chain fails. When the target
+// itself is unreachable the error is returned as is, since trying other
+// members won't help and says nothing about their health.
+pub async fn connect<T, F: Future<Output = Result<T>>>(
+    group: &Group,
+    target: &Endpoint,
+    mut connector: impl FnMut(usize) -> F,
+) -> Result<T> {
+    let mut last_error = None;
+
+    for index in group.candidates(&target.hostname()) {
+        match connector(index).await {
+            Ok(result) => {
+                group.mark_up(index);
+                return Ok(result);
+            }
+            Err(e) if Unreachable::is(&e, target) => return Err(e),
+            Err(e) => {
+                tracing::warn!("Member {} of outbound group failed: {:?}", index, e);
+                group.mark_down(index);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error.expect("a group always has members"))
+        .context("All members of the outbound group failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::connector::{
+        happy_eyeballs::tests::LocalResolver,
+        tcp::{self, TcpOptions},
+    };
+    use anyhow::bail;
+    use rstest::rstest;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_round_robin() -> Result<()> {
+        let group = Group::new(Policy::RoundRobin, 3)?;
+
+        let firsts: Vec<usize> = (0..4).map(|_| group.candidates("a.com")[0]).collect();
+        assert_eq!(firsts, vec![0, 1, 2, 0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_consistent_hash() -> Result<()> {
+        let group = Group::new(Policy::ConsistentHash, 5)?;
+
+        let order = group.candidates("example.com");
+        assert_eq!(order, group.candidates("example.com"));
+
+        // Only the hosts assigned to the down member move.
+        group.mark_down(order[0]);
+        assert_eq!(group.candidates("example.com"), order[1..]);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case(Policy::RoundRobin)]
+    #[case(Policy::Random)]
+    #[case(Policy::ConsistentHash)]
+    #[case(Policy::Failover)]
+    fn test_down_members_skipped(#[case] policy: Policy) -> Result<()> {
+        let group = Group::new(policy, 3)?;
+
+        group.mark_down(1);
+        for _ in 0..10 {
+            let candidates = group.candidates("example.com");
+            assert_eq!(candidates.len(), 2);
+            assert!(!candidates.contains(&1));
+        }
+
+        group.mark_down(0);
+        group.mark_down(2);
+        assert_eq!(group.candidates("example.com").len(), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failover() -> Result<()> {
+        let group = Group::new(Policy::Failover, 3)?;
+
+        let result = connect(&group, &"example.com:443".parse()?, |index| async move {
+            if index == 0 {
+                bail!("failed")
+            }
+            Ok(index)
+        })
+        .await?;
+
+        assert_eq!(result, 1);
+        assert!(!group.is_up(0));
+        assert!(group.is_up(1));
+
+        // The failed member is retried once its backoff expires.
+        group.members.borrow_mut()[0].down_until = Some(Instant::now());
+        assert_eq!(group.candidates("example.com"), vec![0, 1, 2]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_all_members_fail() -> Result<()> {
+        let group = Group::new(Policy::RoundRobin, 2)?;
+
+        assert!(
+            connect::<(), _>(&group, &"example.com:443".parse()?, |_| async {
+                bail!("failed")
+            })
+            .await
+            .is_err()
+        );
+        assert!(!group.is_up(0));
+        assert!(!group.is_up(1));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_target() -> Result<()> {
+        let group = Group::new(Policy::Failover, 2)?;
+        let target: Endpoint = "example.com:443".parse()?;
+        let attempts = Cell::new(0);
+
+        let result = connect::<(), _>(&group, &target, |_| {
+            attempts.set(attempts.get() + 1);
+            let target = target.clone();
+            async move { Err(anyhow::anyhow!("refused").context(Unreachable(target))) }
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+        assert!(group.is_up(0));
+        assert!(group.is_up(1));
+
+        // The proxy in the chain being unreachable is the member's fault.
+        let proxy: Endpoint = "proxy.test:1080".parse()?;
+        let result = connect::<(), _>(&group, &target, |_| {
+            let proxy = proxy.clone();
+            async move { Err(anyhow::anyhow!("refused").context(Unreachable(proxy))) }
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert!(!group.is_up(0));
+        assert!(!group.is_up(1));
+
+        Ok(())
+    }
+
+    // A direct member failing on its own, e.g., on DNS or a timeout, is not
+    // the target being unreachable.
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    #[tokio::test]
+    async fn test_direct_failure_falls_back(#[case] timeout: bool) -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let target = Endpoint::new_from_domain(
+            &format!("fallback-{}.test", timeout),
+            listener.local_addr()?.port(),
+        );
+        let group = Group::new(Policy::Failover, 2)?;
+
+        let delay = if timeout {
+            Duration::from_secs(10)
+        } else {
+            Duration::ZERO
+        };
+        let broken = || LocalResolver {
+            ipv4_delay: delay,
+            ipv6_delay: delay,
+            fail: !timeout,
+            ..Default::default()
+        };
+        let options = TcpOptions {
+            connect_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+
+        let stream = connect(&group, &target, |index| {
+            let (target, options) = (&target, &options);
+            async move {
+                if index == 0 {
+                    tcp::connect_with_options(target, broken(), options).await
+                } else {
+                    tcp::connect(target, LocalResolver::default()).await
+                }
+            }
+        })
+        .await?;
+
+        assert_eq!(stream.peer_addr()?, listener.local_addr()?);
+        assert!(!group.is_up(0));
+        assert!(group.is_up(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_backoff_grows() -> Result<()> {
+        let group = Group::new(Policy::Failover, 1)?;
+
+        group.mark_down(0);
+        let first = group.members.borrow()[0].down_until.unwrap();
+        group.mark_down(0);
+        let second = group.members.borrow()[0].down_until.unwrap();
+
+        assert!(second - first >= INITIAL_BACKOFF);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,654 @@
+use crate::{core::resolver::Resolver, Result};
+use anyhow::bail;
+use futures::{
+    future::{BoxFuture, Fuse, FusedFuture, Ready},
+    Future, FutureExt,
+};
+use itertools::Itertools;
+use lru::LruCache;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    num::NonZeroUsize,
+    ops::Add,
+    pin::Pin,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+    vec::IntoIter,
+};
+use tokio::time::{sleep, sleep_until, Sleep};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FamilyPreference {
+    // Start connecting as soon as any of the DNS answers comes back.
+    #[default]
+    Any,
+    PreferIpv6,
+    PreferIpv4,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+impl FamilyPreference {
+    pub fn allows(&self, ip: &IpAddr) -> bool {
+        match self {
+            Self::Ipv4Only => ip.is_ipv4(),
+            Self::Ipv6Only => ip.is_ipv6(),
+            _ => true,
+        }
+    }
+}
+
+impl FromStr for FamilyPreference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "any" => Self::Any,
+            "prefer_ipv6" => Self::PreferIpv6,
+            "prefer_ipv4" => Self::PreferIpv4,
+            "ipv4_only" => Self::Ipv4Only,
+            "ipv6_only" => Self::Ipv6Only,
+            _ => bail!("Unknown address family preference {}", s),
+        })
+    }
+}
+
+// How Happy Eyeballs staggers the connection attempts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Staggering {
+    pub family: FamilyPreference,
+    // How long to wait for the preferred family after the other one resolves.
+    pub resolution_delay: Duration,
+    pub attempt_delay: Duration,
+}
+
+impl Default for Staggering {
+    fn default() -> Self {
+        Self {
+            family: FamilyPreference::Any,
+            resolution_delay: Duration::from_millis(50),
+            attempt_delay: Duration::from_millis(250),
+        }
+    }
+}
+
+// A connection the Happy Eyeballs connector can remember the address of.
+pub trait Connected {
+    fn peer_ip(&self) -> Option<IpAddr>;
+}
+
+const ADDRESS_CACHE_SIZE: usize = 1024;
+const ADDRESS_CACHE_TTL: Duration = Duration::from_secs(600);
+
+// How a connection is made, an address that works over one transport or from
+// one interface may not work for another.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Tcp {
+        interface: Option<String>,
+        bind_ip: Option<IpAddr>,
+        mark: Option<u32>,
+    },
+    Quic,
+}
+
+// The host, port, transport and the name of the resolver used.
+type AddressKey = (String, u16, Transport, String);
+
+lazy_static::lazy_static! {
+    // The address of each host and port we last connected to, which is tried
+    // first the next time without waiting for DNS.
+    static ref LAST_WORKING_ADDRESS: Mutex<LruCache<AddressKey, (IpAddr, Instant)>> = Mutex::new(
+        LruCache::new(NonZeroUsize::new(ADDRESS_CACHE_SIZE).unwrap())
+    );
+}
+
+fn last_working_address(key: &AddressKey) -> Option<IpAddr> {
+    let mut cache = LAST_WORKING_ADDRESS.lock().unwrap();
+
+    match cache.get(key) {
+        Some((ip, time)) if time.elapsed() < ADDRESS_CACHE_TTL => Some(*ip),
+        Some(_) => {
+            cache.pop(key);
+            None
+        }
+        None => None,
+    }
+}
+
+// The address expires a while after it's first remembered even if it keeps
+// working, so a change in DNS is picked up eventually.
+fn remember_working_address(key: &AddressKey, connection: &impl Connected) {
+    if let Some(ip) = connection.peer_ip() {
+        let mut cache = LAST_WORKING_ADDRESS.lock().unwrap();
+
+        if cache
+            .peek(key)
+            .is_none_or(|(cached, time)| *cached != ip || time.elapsed() >= ADDRESS_CACHE_TTL)
+        {
+            cache.put(key.clone(), (ip, Instant::now()));
+        }
+    }
+}
+
+/// The addresses remembered for each host, port, transport and resolver that
+/// haven't expired yet, along with how long ago they were remembered.
+pub fn remembered_addresses() -> Vec<(String, u16, Transport, String, IpAddr, Duration)> {
+    LAST_WORKING_ADDRESS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, (_, time))| time.elapsed() < ADDRESS_CACHE_TTL)
+        .map(|((host, port, transport, resolver), (ip, time))| {
+            (
+                host.clone(),
+                *port,
+                transport.clone(),
+                resolver.clone(),
+                *ip,
+                time.elapsed(),
+            )
+        })
+        .collect()
+}
+
+// Implementing https://datatracker.ietf.org/doc/html/rfc8305
+//
+// This is actually super complicated to implement so it's very unfortunate that
+// rust std does not provide support for this.
+//
+// Without a family preference we start connecting when we get the first DNS
+// response instead of waiting for the AAAA result. Given the current status of
+// IPv6 connectivity, it may be better not to prefer IPv6. With a preference,
+// the addresses of the preferred family go first and, if the other family
+// resolves first, we wait up to the resolution delay for the preferred one.
+//
+// The last working address of the host is tried before DNS comes back.
+//
+// The connector only schedules the attempts, `connect` makes the actual
+// connection so it works for both TCP and QUIC. When every attempt fails, the
+// error of the last one is returned so TLS or certificate errors are not lost.
+pub struct HappyEyeballConnector<'a, T> {
+    ipv4_future: Pin<Box<dyn FusedFuture<Output = Result<Vec<Ipv4Addr>>> + Send + 'a>>,
+    ipv6_future: Pin<Box<dyn FusedFuture<Output = Result<Vec<Ipv6Addr>>> + Send + 'a>>,
+    ips: IntoIter<IpAddr>,
+    ip_count: usize,
+    cached_ip: Option<IpAddr>,
+    connections: Vec<Fuse<BoxFuture<'static, Result<T>>>>,
+    // Why the last lookup or attempt failed, to explain the failure of all of
+    // them.
+    last_error: Option<anyhow::Error>,
+    next_connection_timer: Pin<Box<Sleep>>,
+    resolution_timer: Option<Pin<Box<Sleep>>>,
+    host: &'a str,
+    port: u16,
+    key: AddressKey,
+    staggering: Staggering,
+    connect: Box<dyn FnMut(SocketAddr) -> BoxFuture<'static, Result<T>> + Send + 'a>,
+}
+
+impl<'a, T: Connected> HappyEyeballConnector<'a, T> {
+    pub fn new(
+        resolver: &'a impl Resolver,
+        host: &'a str,
+        port: u16,
+        transport: Transport,
+        staggering: Staggering,
+        connect: impl FnMut(SocketAddr) -> BoxFuture<'static, Result<T>> + Send + 'a,
+    ) -> Self {
+        let ipv4_future: Pin<Box<dyn FusedFuture<Output = _> + Send>> =
+            if staggering.family == FamilyPreference::Ipv6Only {
+                Box::pin(Fuse::<Ready<_>>::terminated())
+            } else {
+                Box::pin(resolver.lookup_ipv4(host).fuse())
+            };
+
+        let ipv6_future: Pin<Box<dyn FusedFuture<Output = _> + Send>> =
+            if staggering.family == FamilyPreference::Ipv4Only {
+                Box::pin(Fuse::<Ready<_>>::terminated())
+            } else {
+                Box::pin(resolver.lookup_ipv6(host).fuse())
+            };
+
+        let key = (host.to_owned(), port, transport, resolver.name().to_owned());
+        let cached_ip = last_working_address(&key).filter(|ip| staggering.family.allows(ip));
+
+        Self {
+            ipv4_future,
+            ipv6_future,
+            ips: cached_ip.into_iter().collect_vec().into_iter(),
+            ip_count: cached_ip.iter().count(),
+            cached_ip,
+            connections: Vec::new(),
+            last_error: None,
+            next_connection_timer: Box::pin(sleep_until(Instant::now().into())),
+            resolution_timer: None,
+            host,
+            port,
+            key,
+            staggering,
+            connect: Box::new(connect),
+        }
+    }
+
+    fn error(&mut self, message: &str) -> anyhow::Error {
+        let message = format!("{} {}", message, self.host);
+
+        match self.last_error.take() {
+            Some(e) => e.context(message),
+            None => anyhow::anyhow!(message),
+        }
+    }
+
+    fn is_resolving(&self) -> bool {
+        !(self.ipv4_future.is_terminated() && self.ipv6_future.is_terminated())
+    }
+
+    fn add_ips(&mut self, ips: Vec<IpAddr>, preferred: bool) {
+        let ips = ips
+            .into_iter()
+            .filter(|ip| Some(*ip) != self.cached_ip)
+            .collect_vec();
+        self.ip_count += ips.len();
+
+        let remaining = std::mem::take(&mut self.ips);
+        self.ips = if preferred {
+            ips.into_iter().interleave(remaining).collect_vec()
+        } else {
+            remaining.interleave(ips).collect_vec()
+        }
+        .into_iter();
+    }
+
+    // Whether we should hold off new connections since only the family we
+    // don't prefer is resolved yet.
+    fn is_waiting_for_preferred(&mut self, cx: &mut std::task::Context<'_>) -> bool {
+        let (preferred_resolved, other_resolved) = match self.staggering.family {
+            FamilyPreference::PreferIpv6 => (
+                self.ipv6_future.is_terminated(),
+                self.ipv4_future.is_terminated(),
+            ),
+            FamilyPreference::PreferIpv4 => (
+                self.ipv4_future.is_terminated(),
+                self.ipv6_future.is_terminated(),
+            ),
+            _ => return false,
+        };
+
+        if preferred_resolved || !other_resolved {
+            return false;
+        }
+
+        let delay = self.staggering.resolution_delay;
+        self.resolution_timer
+            .get_or_insert_with(|| Box::pin(sleep(delay)))
+            .as_mut()
+            .poll(cx)
+            .is_pending()
+    }
+}
+
+impl<T: Connected> Future for HappyEyeballConnector<'_, T> {
+    type Output = Result<T>;
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        // First we poll the dns result. It doesn't matter in what we order we
+        // poll it since we are doing it at the same time.
+        if !self.ipv4_future.is_terminated() {
+            match self.ipv4_future.poll_unpin(cx) {
+                std::task::Poll::Ready(Ok(addrs)) => {
+                    let preferred = self.staggering.family == FamilyPreference::PreferIpv4;
+                    self.add_ips(addrs.into_iter().map(Into::into).collect(), preferred);
+                }
+                // The other family may still resolve.
+                std::task::Poll::Ready(Err(e)) => self.last_error = Some(e),
+                std::task::Poll::Pending => {}
+            }
+        }
+
+        if !self.ipv6_future.is_terminated() {
+            match self.ipv6_future.poll_unpin(cx) {
+                std::task::Poll::Ready(Ok(addrs)) => {
+                    let preferred = self.staggering.family == FamilyPreference::PreferIpv6;
+                    self.add_ips(addrs.into_iter().map(Into::into).collect(), preferred);
+                }
+                std::task::Poll::Ready(Err(e)) => self.last_error = Some(e),
+                std::task::Poll::Pending => {}
+            }
+        }
+
+        if !self.is_resolving() && self.ip_count == 0 {
+            return std::task::Poll::Ready(Err(self.error("Failed to resolve domain")));
+        }
+
+        // Now we poll all ongoing connections
+        let mut has_pending = false;
+        let mut has_error = false;
+        let mut maybe_stream = None;
+        let mut last_error = None;
+        for c in self.connections.iter_mut() {
+            if c.is_terminated() {
+                continue;
+            }
+
+            match c.poll_unpin(cx) {
+                std::task::Poll::Ready(Ok(stream)) => {
+                    maybe_stream = Some(stream);
+                    break;
+                }
+                std::task::Poll::Ready(Err(e)) => {
+                    has_error = true;
+                    last_error = Some(e);
+                }
+                std::task::Poll::Pending => has_pending = true,
+            }
+        }
+        if last_error.is_some() {
+            self.last_error = last_error;
+        }
+
+        if let Some(stream) = maybe_stream {
+            remember_working_address(&self.key, &stream);
+            return std::task::Poll::Ready(Ok(stream));
+        }
+
+        // Check if we should make new connection
+        if !self.is_waiting_for_preferred(cx)
+            && (!has_pending // No ongoing connection, create a new one now.
+            || has_error // One connection is ended, we should start a new one now.
+            || self.next_connection_timer.as_mut().poll(cx) == std::task::Poll::Ready(()))
+        {
+            // Loop until we successfully makes a connection.
+            loop {
+                match self.ips.next() {
+                    Some(addr) => {
+                        let port = self.port;
+                        let mut fut = (self.connect)((addr, port).into()).fuse();
+                        match fut.poll_unpin(cx) {
+                            std::task::Poll::Ready(result) => match result {
+                                // This should be unreachable actually.
+                                Ok(s) => {
+                                    remember_working_address(&self.key, &s);
+                                    return std::task::Poll::Ready(Ok(s));
+                                }
+                                // Try next IP.
+                                Err(e) => {
+                                    self.last_error = Some(e);
+                                    continue;
+                                }
+                            },
+                            // Good, we initiated an ongoing connection.
+                            std::task::Poll::Pending => {
+                                let attempt_delay = self.staggering.attempt_delay;
+                                self.next_connection_timer
+                                    .as_mut()
+                                    .reset(Instant::now().add(attempt_delay).into());
+                                // The result should always be pending.
+                                assert_eq!(
+                                    self.next_connection_timer.poll_unpin(cx),
+                                    std::task::Poll::Pending
+                                );
+                                self.connections.push(fut);
+                                break;
+                            }
+                        }
+                    }
+                    None => {
+                        // Keep waiting for the ongoing connections if any.
+                        if !self.is_resolving() && !has_pending {
+                            return std::task::Poll::Ready(Err(
+                                self.error("Failed to connect to domain")
+                            ));
+                        } else {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        std::task::Poll::Pending
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    // Resolves every host to the loopback addresses after the delays.
+    #[derive(Debug, Default)]
+    pub struct LocalResolver {
+        pub name: &'static str,
+        pub ipv4_delay: Duration,
+        pub ipv6_delay: Duration,
+        pub fail: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl Resolver for LocalResolver {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        async fn lookup_ip(&self, _name: &str) -> Result<Vec<IpAddr>> {
+            Ok(vec![Ipv6Addr::LOCALHOST.into(), Ipv4Addr::LOCALHOST.into()])
+        }
+
+        async fn lookup_ipv4(&self, _name: &str) -> Result<Vec<Ipv4Addr>> {
+            sleep(self.ipv4_delay).await;
+            if self.fail {
+                bail!("Failed to resolve");
+            }
+            Ok(vec![Ipv4Addr::LOCALHOST])
+        }
+
+        async fn lookup_ipv6(&self, _name: &str) -> Result<Vec<Ipv6Addr>> {
+            sleep(self.ipv6_delay).await;
+            if self.fail {
+                bail!("Failed to resolve");
+            }
+            Ok(vec![Ipv6Addr::LOCALHOST])
+        }
+
+        fn support_raw(&self) -> bool {
+            false
+        }
+    }
+
+    // Stands in for a connection, which is made as soon as it's attempted, so
+    // the first address tried wins.
+    struct Attempt(SocketAddr);
+
+    impl Connected for Attempt {
+        fn peer_ip(&self) -> Option<IpAddr> {
+            Some(self.0.ip())
+        }
+    }
+
+    async fn attempt(
+        resolver: &LocalResolver,
+        host: &str,
+        port: u16,
+        transport: Transport,
+        staggering: Staggering,
+    ) -> Result<SocketAddr> {
+        Ok(
+            HappyEyeballConnector::new(resolver, host, port, transport, staggering, |addr| {
+                async move { Ok(Attempt(addr)) }.boxed()
+            })
+            .await?
+            .0,
+        )
+    }
+
+    #[rstest]
+    #[case(FamilyPreference::Any, 0, 30, true)]
+    #[case(FamilyPreference::Any, 30, 0, false)]
+    #[case(FamilyPreference::PreferIpv6, 0, 20, false)]
+    #[case(FamilyPreference::PreferIpv6, 0, 300, true)]
+    #[case(FamilyPreference::PreferIpv4, 20, 0, true)]
+    #[case(FamilyPreference::PreferIpv4, 300, 0, false)]
+    #[case(FamilyPreference::Ipv4Only, 30, 0, true)]
+    #[case(FamilyPreference::Ipv6Only, 0, 30, false)]
+    #[tokio::test(start_paused = true)]
+    async fn test_family_preference(
+        #[case] family: FamilyPreference,
+        #[case] ipv4_delay: u64,
+        #[case] ipv6_delay: u64,
+        #[case] ipv4: bool,
+    ) -> Result<()> {
+        let addr = attempt(
+            &LocalResolver {
+                ipv4_delay: Duration::from_millis(ipv4_delay),
+                ipv6_delay: Duration::from_millis(ipv6_delay),
+                fail: false,
+                ..Default::default()
+            },
+            &format!("{:?}-{}-{}.test", family, ipv4_delay, ipv6_delay),
+            443,
+            Transport::Quic,
+            Staggering {
+                family,
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        assert_eq!(addr.is_ipv4(), ipv4);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_remembered_address_scope() -> Result<()> {
+        let working = LocalResolver {
+            ipv6_delay: Duration::from_millis(30),
+            ..Default::default()
+        };
+        let failing = LocalResolver {
+            fail: true,
+            ..Default::default()
+        };
+        let tcp = Transport::Tcp {
+            interface: None,
+            bind_ip: None,
+            mark: None,
+        };
+        let staggering = Staggering::default();
+
+        attempt(&working, "scope.test", 443, tcp.clone(), staggering).await?;
+        assert!(remembered_addresses()
+            .iter()
+            .any(|(host, port, transport, ..)| host == "scope.test"
+                && *port == 443
+                && *transport == tcp));
+        assert!(
+            attempt(&failing, "scope.test", 443, tcp.clone(), staggering)
+                .await?
+                .is_ipv4()
+        );
+
+        // Neither another port, another transport, another interface nor
+        // another resolver gets the remembered address.
+        assert!(
+            attempt(&failing, "scope.test", 8443, tcp.clone(), staggering)
+                .await
+                .is_err()
+        );
+        assert!(
+            attempt(&failing, "scope.test", 443, Transport::Quic, staggering)
+                .await
+                .is_err()
+        );
+        let bound = Transport::Tcp {
+            interface: Some("eth1".to_owned()),
+            bind_ip: None,
+            mark: None,
+        };
+        assert!(attempt(&failing, "scope.test", 443, bound, staggering)
+            .await
+            .is_err());
+        let other = LocalResolver {
+            name: "other",
+            fail: true,
+            ..Default::default()
+        };
+        assert!(attempt(&other, "scope.test", 443, tcp, staggering)
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remembered_address_not_extended() {
+        let key = (
+            "extend.test".to_owned(),
+            443,
+            Transport::Quic,
+            "local".to_owned(),
+        );
+        let ip = Ipv4Addr::LOCALHOST.into();
+        let remembered = Instant::now() - Duration::from_secs(60);
+        LAST_WORKING_ADDRESS
+            .lock()
+            .unwrap()
+            .put(key.clone(), (ip, remembered));
+
+        remember_working_address(&key, &Attempt(SocketAddr::new(ip, 443)));
+        assert_eq!(
+            LAST_WORKING_ADDRESS.lock().unwrap().peek(&key),
+            Some(&(ip, remembered))
+        );
+
+        // Another address starts over.
+        let other = Ipv6Addr::LOCALHOST.into();
+        remember_working_address(&key, &Attempt(SocketAddr::new(other, 443)));
+        assert!(LAST_WORKING_ADDRESS.lock().unwrap().peek(&key).unwrap().1 > remembered);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_last_error() -> Result<()> {
+        let resolver = LocalResolver::default();
+
+        let error = HappyEyeballConnector::new(
+            &resolver,
+            "error.test",
+            443,
+            Transport::Quic,
+            Staggering::default(),
+            |addr| async move { Err::<Attempt, _>(anyhow::anyhow!("refused by {}", addr)) }.boxed(),
+        )
+        .await
+        .err()
+        .unwrap();
+
+        let message = format!("{:#}", error);
+        assert!(message.starts_with("Failed to connect to domain error.test"));
+        assert!(message.contains("refused by"));
+
+        let error = HappyEyeballConnector::new(
+            &LocalResolver {
+                fail: true,
+                ..Default::default()
+            },
+            "unresolved.test",
+            443,
+            Transport::Quic,
+            Staggering::default(),
+            |addr| async move { Ok(Attempt(addr)) }.boxed(),
+        )
+        .await
+        .err()
+        .unwrap();
+
+        assert!(format!("{:#}", error).contains("Failed to resolve"));
+
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
+use super::Unreachable;
 use crate::{
     core::{endpoint::Endpoint, io::Io},
     Result,
@@ -35,14 +36,22 @@ pub async fn connect(endpoint: &Endpoint, mut nexthop: impl Io) -> Result<impl I
         let mut res = Response::new(&mut headers);
 
         if res.parse(&buf)?.is_complete() {
-            if res.code == Some(200) {
-                break;
-            } else {
-                bail!(
+            match res.code {
+                Some(200) => break,
+                // The proxy itself is fine, it just can't reach the target.
+                Some(502..=504) => {
+                    return Err(anyhow::anyhow!(
+                        "Failed to CONNECT to {}, got error response {}",
+                        endpoint,
+                        std::str::from_utf8(&buf)?
+                    )
+                    .context(Unreachable(endpoint.clone())))
+                }
+                _ => bail!(
                     "Failed to CONNECT to {}, got error response {}",
                     endpoint,
                     std::str::from_utf8(&buf)?
-                )
+                ),
             }
         }
     }
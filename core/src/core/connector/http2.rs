@@ -0,0 +1,239 @@
+use crate::{
+    core::{
+        connector::{tls::connect_with_alpn, Unreachable},
+        endpoint::Endpoint,
+        io::Io,
+    },
+    Result,
+};
+use anyhow::{bail, ensure, Context};
+use bytes::Bytes;
+use http::{Method, Request, StatusCode, Uri};
+use http_body_util::Empty;
+use hyper::{client::conn::http2::SendRequest, ext::Protocol, upgrade::Upgraded};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::{fmt::Debug, pin::Pin, task::Poll};
+use sync_wrapper::SyncWrapper;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::debug;
+
+#[derive(Debug)]
+pub struct Http2Connection {
+    server: Endpoint,
+    sender: SendRequest<Empty<Bytes>>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ConnectMethod {
+    Classic,
+    // RFC 8441 extended CONNECT, `{target_host}` and `{target_port}` in the
+    // path are replaced with the target endpoint.
+    Extended { protocol: String, path: String },
+}
+
+pub async fn create_http2_connection(
+    server: &Endpoint,
+    nexthop: impl Io,
+) -> Result<Http2Connection> {
+    let tls = connect_with_alpn(server, nexthop, &["h2"]).await?;
+
+    ensure!(
+        tls.get_ref().negotiated_alpn()?.as_deref() == Some(b"h2"),
+        "Server {} does not support HTTP/2",
+        server
+    );
+
+    handshake(server, tls).await
+}
+
+async fn handshake(server: &Endpoint, io: impl Io) -> Result<Http2Connection> {
+    let (sender, connection) =
+        hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(io))
+            .await
+            .with_context(|| format!("HTTP/2 handshake with {} failed", server))?;
+
+    let server_cloned = server.clone();
+    tokio::task::spawn(async move {
+        if let Err(err) = connection.await {
+            debug!("HTTP/2 connection to {} closed: {:?}", server_cloned, err);
+        }
+    });
+
+    Ok(Http2Connection {
+        server: server.clone(),
+        sender,
+    })
+}
+
+pub async fn connect(
+    connection: &Http2Connection,
+    endpoint: &Endpoint,
+    method: &ConnectMethod,
+) -> Result<Http2Stream> {
+    let mut sender = connection.sender.clone();
+    sender
+        .ready()
+        .await
+        .with_context(|| format!("HTTP/2 connection to {} is closed", connection.server))?;
+
+    let request = match method {
+        ConnectMethod::Classic => Request::builder()
+            .method(Method::CONNECT)
+            .uri(endpoint.to_string())
+            .body(Empty::new())?,
+        ConnectMethod::Extended { protocol, path } => {
+            let uri = Uri::builder()
+                .scheme("https")
+                .authority(connection.server.to_string())
+                .path_and_query(
+                    path.replace("{target_host}", &endpoint.hostname())
+                        .replace("{target_port}", &endpoint.port().to_string()),
+                )
+                .build()?;
+
+            let mut request = Request::builder()
+                .method(Method::CONNECT)
+                .uri(uri)
+                .body(Empty::new())?;
+            request
+                .extensions_mut()
+                .insert(Protocol::from(protocol.as_str()));
+            request
+        }
+    };
+
+    let response = sender
+        .send_request(request)
+        .await
+        .with_context(|| format!("Failed to send CONNECT request to connect to {}", endpoint))?;
+
+    match response.status() {
+        status if status.is_success() => {}
+        // The proxy itself is fine, it just can't reach the target.
+        status @ (StatusCode::BAD_GATEWAY
+        | StatusCode::SERVICE_UNAVAILABLE
+        | StatusCode::GATEWAY_TIMEOUT) => {
+            return Err(anyhow::anyhow!(
+                "Failed to CONNECT to {}, got error response {}",
+                endpoint,
+                status
+            )
+            .context(Unreachable(endpoint.clone())))
+        }
+        status => bail!(
+            "Failed to CONNECT to {}, got error response {}",
+            endpoint,
+            status
+        ),
+    }
+
+    Ok(Http2Stream {
+        inner: SyncWrapper::new(TokioIo::new(hyper::upgrade::on(response).await?)),
+    })
+}
+
+// `Upgraded` is not `Sync`, but we only ever access it through `&mut`.
+pub struct Http2Stream {
+    inner: SyncWrapper<TokioIo<Upgraded>>,
+}
+
+impl Debug for Http2Stream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Http2Stream").finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for Http2Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(self.get_mut().inner.get_mut()).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Http2Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(self.get_mut().inner.get_mut()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(self.get_mut().inner.get_mut()).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(self.get_mut().inner.get_mut()).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{body::Incoming, service::service_fn, Response};
+    use rstest::rstest;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    async fn echo(request: Request<Incoming>) -> Result<Response<Empty<Bytes>>> {
+        let authority = request.uri().authority().map(|a| a.to_string());
+        let path = request.uri().path().to_owned();
+        let protocol = request
+            .extensions()
+            .get::<Protocol>()
+            .map(|p| p.as_str().to_owned());
+
+        tokio::task::spawn(async move {
+            let mut io = TokioIo::new(hyper::upgrade::on(request).await.unwrap());
+            let mut buf = [0; 5];
+            io.read_exact(&mut buf).await.unwrap();
+            io.write_all(&buf).await.unwrap();
+        });
+
+        let valid = match protocol {
+            None => authority.as_deref() == Some("example.com:443"),
+            Some(p) => p == "connect-tcp" && path == "/tcp/example.com/443/",
+        };
+
+        Ok(Response::builder()
+            .status(if valid { 200 } else { 400 })
+            .body(Empty::new())?)
+    }
+
+    #[rstest]
+    #[case(ConnectMethod::Classic)]
+    #[case(ConnectMethod::Extended {
+        protocol: "connect-tcp".to_owned(),
+        path: "/tcp/{target_host}/{target_port}/".to_owned()
+    })]
+    #[tokio::test]
+    async fn test_connect(#[case] method: ConnectMethod) -> Result<()> {
+        let (client, server) = duplex(65536);
+
+        tokio::task::spawn(
+            hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                .enable_connect_protocol()
+                .serve_connection(TokioIo::new(server), service_fn(echo)),
+        );
+
+        let connection = handshake(&"proxy.test:443".parse()?, client).await?;
+
+        let mut stream = connect(&connection, &"example.com:443".parse()?, &method).await?;
+        stream.write_all(b"hello").await?;
+
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+
+        Ok(())
+    }
+}
@@ -1,8 +1,37 @@
 pub mod block;
+pub mod group;
+pub mod happy_eyeballs;
 pub mod http;
+pub mod http2;
 pub mod quic;
 pub mod simplex;
 pub mod socks5;
 pub mod speed;
+#[cfg(unix)]
+pub mod ssh;
 pub mod tcp;
 pub mod tls;
+pub mod udp;
+
+use crate::core::endpoint::Endpoint;
+
+/// Attached as context when the endpoint itself can't be reached, e.g., the
+/// connection to it is refused or a proxy replies that it's unreachable, so
+/// callers can tell a dead target apart from a broken connector chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unreachable(pub Endpoint);
+
+impl std::fmt::Display for Unreachable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is unreachable", self.0)
+    }
+}
+
+impl Unreachable {
+    /// Whether `error` says `endpoint` is unreachable.
+    pub fn is(error: &anyhow::Error, endpoint: &Endpoint) -> bool {
+        error
+            .downcast_ref::<Self>()
+            .is_some_and(|unreachable| &unreachable.0 == endpoint)
+    }
+}
@@ -1,30 +1,526 @@
 use crate::{
     core::{
+        datagram::{decode_address, encode_address, Datagram},
         endpoint::Endpoint,
-        quic::{client::create_quic_connection as client_connect, QuicStream},
+        quic::{
+            client::{create_quic_connection as client_connect, QuicOptions},
+            QuicStream,
+        },
         resolver::Resolver,
     },
     Result,
 };
+use anyhow::{bail, Context};
+use bytes::Bytes;
 use quinn::Connection;
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    Mutex,
+};
+use tracing::debug;
+
+// Datagram flows sharing a connection, told apart by the id in front of each
+// datagram.
+#[derive(Debug, Default)]
+struct Flows {
+    senders: RefCell<HashMap<u32, UnboundedSender<Bytes>>>,
+    next_id: Cell<u32>,
+    // The stable id of the connection whose datagrams are being dispatched.
+    dispatching: Cell<Option<usize>>,
+}
 
+// Transparently re-establishes the connection when it's lost, so it can be
+// created once and shared by all streams.
 #[derive(Debug)]
 pub struct QuicConnection {
-    inner: Connection,
+    server: Endpoint,
+    resolver: Rc<dyn Resolver + Sync>,
+    alpn_protocols: Vec<Vec<u8>>,
+    options: QuicOptions,
+    // The lock makes sure there is only one reconnect in flight.
+    inner: Mutex<Connection>,
+    flows: Rc<Flows>,
+}
+
+impl QuicConnection {
+    async fn establish(&self) -> Result<Connection> {
+        client_connect(
+            self.server.clone(),
+            self.resolver.clone(),
+            self.alpn_protocols.clone(),
+            &self.options,
+        )
+        .await
+        .with_context(|| format!("Failed to reconnect to QUIC server {}", self.server))
+    }
+
+    async fn current(&self) -> Result<Connection> {
+        let mut inner = self.inner.lock().await;
+
+        if let Some(reason) = inner.close_reason() {
+            debug!(
+                "QUIC connection to {} was lost: {}, reconnecting",
+                self.server, reason
+            );
+            *inner = self.establish().await?;
+        }
+
+        Ok(inner.clone())
+    }
+
+    // Reconnect unless someone else already replaced the stale connection.
+    async fn replace(&self, stale: &Connection) -> Result<Connection> {
+        let mut inner = self.inner.lock().await;
+
+        if inner.stable_id() == stale.stable_id() {
+            *inner = self.establish().await?;
+        }
+
+        Ok(inner.clone())
+    }
 }
 
-pub async fn create_quic_connection<R: Resolver>(
+pub async fn create_quic_connection(
     server: Endpoint,
-    resolver: R,
-    apln_protocols: Vec<Vec<u8>>,
+    resolver: Rc<dyn Resolver + Sync>,
+    alpn_protocols: Vec<Vec<u8>>,
+    options: QuicOptions,
 ) -> Result<QuicConnection> {
+    let inner = client_connect(
+        server.clone(),
+        resolver.clone(),
+        alpn_protocols.clone(),
+        &options,
+    )
+    .await?;
+
     Ok(QuicConnection {
-        inner: client_connect(server, resolver, apln_protocols).await?,
+        server,
+        resolver,
+        alpn_protocols,
+        options,
+        inner: Mutex::new(inner),
+        flows: Rc::default(),
     })
 }
 
 pub async fn connect(connection: &QuicConnection) -> Result<QuicStream> {
-    let (send, recv) = connection.inner.open_bi().await?;
+    let current = connection.current().await?;
+
+    let (send, recv) = match current.open_bi().await {
+        Ok(stream) => stream,
+        Err(e) => {
+            debug!(
+                "Failed to open stream to QUIC server {}: {}, reconnecting",
+                connection.server, e
+            );
+
+            connection.replace(&current).await?.open_bi().await?
+        }
+    };
 
     Ok(QuicStream::new(send, recv))
 }
+
+// Each datagram is the big endian u32 id of its flow, the target or source in
+// the SOCKS5 address format, then the payload. The server replies with the id
+// it received. See the QUIC datagram format in the README for the full spec.
+#[derive(Debug)]
+pub struct QuicDatagram {
+    connection: Connection,
+    id: u32,
+    packets: Mutex<UnboundedReceiver<Bytes>>,
+    flows: Rc<Flows>,
+}
+
+impl Drop for QuicDatagram {
+    fn drop(&mut self) {
+        self.flows.senders.borrow_mut().remove(&self.id);
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Datagram for QuicDatagram {
+    async fn send_to(&self, payload: &[u8], target: &Endpoint) -> Result<()> {
+        let mut packet = self.id.to_be_bytes().to_vec();
+        encode_address(target, &mut packet)?;
+        packet.extend_from_slice(payload);
+
+        self.connection
+            .send_datagram(packet.into())
+            .context("Failed to send QUIC datagram")?;
+
+        Ok(())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, Endpoint)> {
+        let mut packets = self.packets.lock().await;
+
+        loop {
+            let packet = tokio::select! {
+                packet = packets.recv() => packet.context("QUIC datagram flow is closed")?,
+                reason = self.connection.closed() => bail!("QUIC connection is closed: {}", reason),
+            };
+
+            let (from, header_len) = match decode_address(&packet) {
+                Ok(address) => address,
+                Err(e) => {
+                    debug!("Dropped malformed QUIC datagram: {}", e);
+                    continue;
+                }
+            };
+
+            let payload = &packet[header_len..];
+            let len = payload.len().min(buf.len());
+            buf[..len].copy_from_slice(&payload[..len]);
+
+            return Ok((len, from));
+        }
+    }
+}
+
+async fn dispatch(flows: Rc<Flows>, connection: Connection) {
+    while let Ok(packet) = connection.read_datagram().await {
+        let Some(id) = packet.get(..4).and_then(|id| id.try_into().ok()) else {
+            continue;
+        };
+        let id = u32::from_be_bytes(id);
+
+        if let Some(sender) = flows.senders.borrow().get(&id) {
+            let _ = sender.send(packet.slice(4..));
+        }
+    }
+
+    if flows.dispatching.get() == Some(connection.stable_id()) {
+        flows.dispatching.set(None);
+    }
+}
+
+// Start a new datagram flow, it's bound to the current connection and ends
+// when the connection is lost.
+pub async fn datagram(connection: &QuicConnection) -> Result<QuicDatagram> {
+    let current = connection.current().await?;
+    let flows = connection.flows.clone();
+
+    if flows.dispatching.get() != Some(current.stable_id()) {
+        flows.dispatching.set(Some(current.stable_id()));
+        tokio::task::spawn_local(dispatch(flows.clone(), current.clone()));
+    }
+
+    let id = flows.next_id.get();
+    flows.next_id.set(id.wrapping_add(1));
+
+    let (sender, packets) = unbounded_channel();
+    flows.senders.borrow_mut().insert(id, sender);
+
+    Ok(QuicDatagram {
+        connection: current,
+        id,
+        packets: Mutex::new(packets),
+        flows,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        connector::happy_eyeballs::FamilyPreference, resolver::system::SystemResolver,
+    };
+    use quinn::{crypto::rustls::QuicServerConfig, Endpoint as QuicEndpoint, ServerConfig};
+    use rstest::rstest;
+    use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+        path::PathBuf,
+        sync::Arc,
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::mpsc::{unbounded_channel, UnboundedReceiver},
+    };
+
+    // Only the IPv4 address has a server listening.
+    #[derive(Debug)]
+    struct LocalResolver;
+
+    #[async_trait::async_trait]
+    impl Resolver for LocalResolver {
+        fn name(&self) -> &str {
+            "local"
+        }
+
+        async fn lookup_ip(&self, _name: &str) -> Result<Vec<IpAddr>> {
+            Ok(vec![Ipv6Addr::LOCALHOST.into(), Ipv4Addr::LOCALHOST.into()])
+        }
+
+        async fn lookup_ipv4(&self, _name: &str) -> Result<Vec<Ipv4Addr>> {
+            Ok(vec![Ipv4Addr::LOCALHOST])
+        }
+
+        async fn lookup_ipv6(&self, _name: &str) -> Result<Vec<Ipv6Addr>> {
+            Ok(vec![Ipv6Addr::LOCALHOST])
+        }
+
+        fn support_raw(&self) -> bool {
+            false
+        }
+    }
+
+    const PIN: &str = "sha256//0F2cqUr1QFo9mm/qSHOXbkRpQhG1C9f8ou8D0o09niQ=";
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/quic")
+            .join(name)
+    }
+
+    // Echo server with a certificate for quic.test and 127.0.0.1, echoing
+    // datagrams as well. Returns the server side of every accepted connection.
+    fn serve() -> Result<(SocketAddr, UnboundedReceiver<Connection>)> {
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+
+        let mut crypto = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                CertificateDer::pem_file_iter(fixture("server.pem"))?.collect::<Result<_, _>>()?,
+                PrivateKeyDer::from_pem_file(fixture("server.key"))?,
+            )?;
+        crypto.alpn_protocols = vec![b"test".to_vec()];
+
+        let endpoint = QuicEndpoint::server(
+            ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?)),
+            "127.0.0.1:0".parse()?,
+        )?;
+        let addr = endpoint.local_addr()?;
+
+        let (tx, rx) = unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                let Ok(connection) = incoming.await else {
+                    continue;
+                };
+                let _ = tx.send(connection.clone());
+
+                let datagrams = connection.clone();
+                tokio::spawn(async move {
+                    while let Ok(packet) = datagrams.read_datagram().await {
+                        let _ = datagrams.send_datagram(packet);
+                    }
+                });
+
+                tokio::spawn(async move {
+                    while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+                        tokio::spawn(async move {
+                            let _ = tokio::io::copy(&mut recv, &mut send).await;
+                            let _ = send.finish();
+                        });
+                    }
+                });
+            }
+        });
+
+        Ok((addr, rx))
+    }
+
+    async fn echo(connection: &QuicConnection) -> Result<()> {
+        let mut stream = connect(connection).await?;
+        stream.write_all(b"ping").await?;
+        stream.shutdown().await?;
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await?;
+        assert_eq!(buf, b"ping");
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case(true, None, None, true)]
+    #[case(true, Some("quic.test"), None, true)]
+    #[case(true, Some("other.test"), None, false)]
+    #[case(false, None, None, false)]
+    #[case(true, None, Some(PIN), true)]
+    #[case(
+        true,
+        None,
+        Some("sha256//AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="),
+        false
+    )]
+    #[tokio::test]
+    async fn test_options(
+        #[case] with_ca: bool,
+        #[case] sni: Option<&str>,
+        #[case] pin: Option<&str>,
+        #[case] succeed: bool,
+    ) -> Result<()> {
+        let (addr, _connections) = serve()?;
+
+        let mut options = QuicOptions {
+            ca_file: with_ca.then(|| fixture("ca.pem")),
+            sni: sni.map(str::to_owned),
+            ..Default::default()
+        };
+        if let Some(pin) = pin {
+            options.add_pin(pin)?;
+        }
+
+        let result = create_quic_connection(
+            Endpoint::new_from_addr(addr),
+            Rc::new(SystemResolver::new()),
+            vec![b"test".to_vec()],
+            options,
+        )
+        .await;
+
+        assert_eq!(result.is_ok(), succeed, "{:?}", result);
+        if let Ok(connection) = result {
+            echo(&connection).await?;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() -> Result<()> {
+        let (addr, mut connections) = serve()?;
+
+        let connection = create_quic_connection(
+            Endpoint::new_from_addr(addr),
+            Rc::new(SystemResolver::new()),
+            vec![b"test".to_vec()],
+            QuicOptions {
+                ca_file: Some(fixture("ca.pem")),
+                ..Default::default()
+            },
+        )
+        .await?;
+        echo(&connection).await?;
+
+        connections
+            .recv()
+            .await
+            .unwrap()
+            .close(0u32.into(), b"restart");
+        let lost = connection.inner.lock().await.clone();
+        lost.closed().await;
+
+        echo(&connection).await?;
+        assert!(connections.try_recv().is_ok());
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case(FamilyPreference::Any, true)]
+    #[case(FamilyPreference::PreferIpv6, true)]
+    #[case(FamilyPreference::Ipv4Only, true)]
+    #[case(FamilyPreference::Ipv6Only, false)]
+    #[tokio::test]
+    async fn test_happy_eyeballs(
+        #[case] family: FamilyPreference,
+        #[case] succeed: bool,
+    ) -> Result<()> {
+        let (addr, _connections) = serve()?;
+
+        let mut options = QuicOptions {
+            ca_file: Some(fixture("ca.pem")),
+            // Nothing answers on IPv6, give up on it quickly.
+            idle_timeout: Duration::from_millis(500),
+            ..Default::default()
+        };
+        options.staggering.family = family;
+        options.staggering.attempt_delay = Duration::from_millis(50);
+
+        let result = create_quic_connection(
+            Endpoint::new_from_domain("quic.test", addr.port()),
+            Rc::new(LocalResolver),
+            vec![b"test".to_vec()],
+            options,
+        )
+        .await;
+
+        assert_eq!(result.is_ok(), succeed, "{:?}", result);
+        if let Ok(connection) = result {
+            echo(&connection).await?;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs_error() -> Result<()> {
+        let (addr, _connections) = serve()?;
+
+        let mut options = QuicOptions {
+            ca_file: Some(fixture("ca.pem")),
+            ..Default::default()
+        };
+        options.add_pin("sha256//AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=")?;
+        options.staggering.family = FamilyPreference::Ipv4Only;
+
+        let error = create_quic_connection(
+            Endpoint::new_from_domain("quic.test", addr.port()),
+            Rc::new(LocalResolver),
+            vec![b"test".to_vec()],
+            options,
+        )
+        .await
+        .unwrap_err();
+
+        // Why the attempt failed is kept.
+        assert!(
+            format!("{:#}", error).contains("is not pinned"),
+            "{:#}",
+            error
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_datagram() -> Result<()> {
+        let (addr, _connections) = serve()?;
+
+        let connection = create_quic_connection(
+            Endpoint::new_from_addr(addr),
+            Rc::new(SystemResolver::new()),
+            vec![b"test".to_vec()],
+            QuicOptions {
+                ca_file: Some(fixture("ca.pem")),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        tokio::task::LocalSet::new()
+            .run_until(async move {
+                let flows = [datagram(&connection).await?, datagram(&connection).await?];
+                let targets: [Endpoint; 2] = ["example.com:53".parse()?, "127.0.0.1:53".parse()?];
+
+                for (flow, target) in flows.iter().zip(&targets) {
+                    flow.send_to(target.to_string().as_bytes(), target).await?;
+                }
+
+                // Every flow only sees its own replies.
+                for (flow, target) in flows.iter().zip(&targets) {
+                    let mut buf = [0; 1024];
+                    let (len, from) = flow.recv_from(&mut buf).await?;
+                    assert_eq!(&buf[..len], target.to_string().as_bytes());
+                    assert_eq!(&from, target);
+                }
+
+                drop(flows);
+                assert!(connection.flows.senders.borrow().is_empty());
+
+                anyhow::Ok(())
+            })
+            .await
+    }
+}
@@ -1,11 +1,28 @@
 use crate::{
-    core::{endpoint::Endpoint, io::Io},
+    core::{
+        connector::{tcp::connect as tcp_connect, Unreachable},
+        datagram::{decode_address, encode_address, Datagram},
+        endpoint::Endpoint,
+        io::Io,
+        resolver::Resolver,
+    },
     Result,
 };
 use anyhow::{bail, ensure, Context};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+use tracing::debug;
 
-pub async fn connect(endpoint: &Endpoint, mut nexthop: impl Io) -> Result<impl Io> {
+// RSV, FRAG, then the longest address, a 255 bytes domain.
+const MAX_UDP_HEADER_SIZE: usize = 3 + 2 + 255 + 2;
+
+async fn hello(nexthop: &mut impl Io) -> Result<()> {
     nexthop.write_all(&[5, 1, 0]).await?;
 
     let mut buf = [0; 2];
@@ -18,42 +35,158 @@ pub async fn connect(endpoint: &Endpoint, mut nexthop: impl Io) -> Result<impl I
         buf[1]
     );
 
-    let len = endpoint
-        .hostname()
-        .len()
-        .try_into()
-        .with_context(|| "The socks5 protocol cannot support domain longer than 255 bytes.")?;
-    nexthop.write_all(&[5, 1, 0, 3, len]).await?;
-    nexthop.write_all(endpoint.hostname().as_bytes()).await?;
-    nexthop.write_all(&endpoint.port().to_be_bytes()).await?;
+    Ok(())
+}
 
+// Returns the address bound by the server.
+async fn read_reply(nexthop: &mut impl Io, target: &Endpoint) -> Result<Endpoint> {
     let mut buf = [0; 4];
     nexthop.read_exact(&mut buf).await?;
     ensure!(buf[0] == 5, "Unsupported socks version: {}", buf[0]);
-    ensure!(
-        buf[1] == 0,
-        "Socks5 connection failed with status {}",
-        buf[1]
-    );
+    match buf[1] {
+        0 => {}
+        // Network or host unreachable, connection refused and TTL expired are
+        // about the target, not the server.
+        3..=6 => {
+            return Err(
+                anyhow::anyhow!("Socks5 connection failed with status {}", buf[1])
+                    .context(Unreachable(target.clone())),
+            )
+        }
+        status => bail!("Socks5 connection failed with status {}", status),
+    }
     ensure!(buf[2] == 0, "Not recognized reserved field");
-    match buf[3] {
+    let ip: IpAddr = match buf[3] {
         1 => {
-            let mut buf = [0; 6];
+            let mut buf = [0; 4];
             nexthop.read_exact(&mut buf).await?;
+            buf.into()
         }
         3 => {
             let len: usize = nexthop.read_u8().await?.into();
-            let mut buf = vec![0; len + 2];
+            let mut buf = vec![0; len];
             nexthop.read_exact(&mut buf).await?;
+            let port = nexthop.read_u16().await?;
+            return Ok(Endpoint::new_from_domain(
+                &String::from_utf8(buf).context("Server replied an invalid domain")?,
+                port,
+            ));
         }
         4 => {
-            let mut buf = [0; 18];
+            let mut buf = [0; 16];
             nexthop.read_exact(&mut buf).await?;
+            buf.into()
         }
         _ => {
             bail!("Not recognized address type {}", buf[3]);
         }
-    }
+    };
+    let port = nexthop.read_u16().await?;
+
+    Ok(Endpoint::new_from_addr(SocketAddr::new(ip, port)))
+}
+
+pub async fn connect(endpoint: &Endpoint, mut nexthop: impl Io) -> Result<impl Io> {
+    hello(&mut nexthop).await?;
+
+    let len = endpoint
+        .hostname()
+        .len()
+        .try_into()
+        .with_context(|| "The socks5 protocol cannot support domain longer than 255 bytes.")?;
+    nexthop.write_all(&[5, 1, 0, 3, len]).await?;
+    nexthop.write_all(endpoint.hostname().as_bytes()).await?;
+    nexthop.write_all(&endpoint.port().to_be_bytes()).await?;
+
+    read_reply(&mut nexthop, endpoint).await?;
 
     Ok(nexthop)
 }
+
+// Relays packets through the UDP relay of a SOCKS5 server. The association
+// lasts as long as the TCP control connection.
+#[derive(Debug)]
+pub struct Socks5Datagram {
+    socket: UdpSocket,
+    control: TcpStream,
+}
+
+#[async_trait::async_trait(?Send)]
+impl Datagram for Socks5Datagram {
+    async fn send_to(&self, payload: &[u8], target: &Endpoint) -> Result<()> {
+        let mut packet = vec![0, 0, 0];
+        encode_address(target, &mut packet)?;
+        packet.extend_from_slice(payload);
+
+        self.socket.send(&packet).await?;
+
+        Ok(())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, Endpoint)> {
+        let mut packet = vec![0; buf.len() + MAX_UDP_HEADER_SIZE];
+
+        loop {
+            tokio::select! {
+                len = self.socket.recv(&mut packet) => {
+                    let packet = &packet[..len?];
+
+                    // Fragmentation is optional and we don't support it.
+                    if packet.len() < 3 || packet[2] != 0 {
+                        continue;
+                    }
+
+                    let (from, header_len) = match decode_address(&packet[3..]) {
+                        Ok(address) => address,
+                        Err(e) => {
+                            debug!("Dropped malformed packet from socks5 relay: {}", e);
+                            continue;
+                        }
+                    };
+
+                    let payload = &packet[3 + header_len..];
+                    let len = payload.len().min(buf.len());
+                    buf[..len].copy_from_slice(&payload[..len]);
+
+                    return Ok((len, from));
+                }
+                readable = self.control.readable() => {
+                    readable?;
+
+                    match self.control.try_read(&mut [0; 1]) {
+                        Ok(0) => bail!("The socks5 server closed the UDP association"),
+                        Ok(_) => {}
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub async fn associate(server: &Endpoint, resolver: impl Resolver) -> Result<Socks5Datagram> {
+    let mut control = tcp_connect(server, resolver).await?;
+    hello(&mut control).await?;
+
+    // We don't know the address we'll send from until the socket is bound.
+    control.write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+
+    let relay = match read_reply(&mut control, server).await? {
+        // The relay is on the server itself.
+        Endpoint::Addr(addr) if addr.ip().is_unspecified() => {
+            SocketAddr::new(control.peer_addr()?.ip(), addr.port())
+        }
+        Endpoint::Addr(addr) => addr,
+        Endpoint::Domain(..) => bail!("Socks5 server replied a domain as the UDP relay"),
+    };
+
+    let local: IpAddr = match relay {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((local, 0)).await?;
+    socket.connect(relay).await?;
+
+    Ok(Socks5Datagram { socket, control })
+}
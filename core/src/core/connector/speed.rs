@@ -1,5 +1,5 @@
 use crate::core::{endpoint::Endpoint, io::Io};
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use futures::{
     future::{select_ok, FutureExt},
     Future,
@@ -25,3 +25,65 @@ pub async fn connect<
     .await
     .map(|r| r.0)
 }
+
+// Same as `connect` but for attempts that are not `Send`, e.g., the ones
+// driving a Rune VM. The attempts still pending are dropped as soon as one
+// succeeds.
+pub async fn race<T, F: Future<Output = Result<T>>>(attempts: Vec<(Duration, F)>) -> Result<T> {
+    ensure!(!attempts.is_empty(), "Nothing to race");
+
+    select_ok(attempts.into_iter().map(|(delay, attempt)| {
+        async move {
+            sleep(delay).await;
+
+            attempt.await
+        }
+        .boxed_local()
+    }))
+    .await
+    .map(|r| r.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use std::{cell::Cell, rc::Rc};
+
+    #[tokio::test]
+    async fn test_race_cancels_losers() -> Result<()> {
+        let dropped = Rc::new(Cell::new(false));
+
+        let guard = scopeguard::guard(dropped.clone(), |d| d.set(true));
+        let slow = async move {
+            let _guard = guard;
+            sleep(Duration::from_secs(10)).await;
+            Ok(1)
+        }
+        .boxed_local();
+
+        let result = race(vec![
+            (Duration::ZERO, async { bail!("failed") }.boxed_local()),
+            (Duration::ZERO, slow),
+            (Duration::from_millis(50), async { Ok(2) }.boxed_local()),
+        ])
+        .await?;
+
+        assert_eq!(result, 2);
+        assert!(dropped.get());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_race_fails_when_all_fail() {
+        assert!(
+            race::<(), _>(vec![(Duration::ZERO, async { bail!("failed") })])
+                .await
+                .is_err()
+        );
+        assert!(race::<(), futures::future::Ready<Result<()>>>(vec![])
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use crate::{
+    core::{
+        endpoint::Endpoint,
+        resolver::Resolver,
+        ssh::{
+            client::{create_ssh_session, Auth},
+            SshSession, SshStream,
+        },
+    },
+    Result,
+};
+use anyhow::Context;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub struct SshConnection {
+    inner: Arc<SshSession>,
+}
+
+pub async fn create_ssh_connection<R: Resolver>(
+    server: Endpoint,
+    resolver: R,
+    auth: Auth,
+) -> Result<SshConnection> {
+    Ok(SshConnection {
+        inner: create_ssh_session(server, resolver, auth).await?,
+    })
+}
+
+pub async fn connect(connection: &SshConnection, endpoint: &Endpoint) -> Result<SshStream> {
+    let channel = connection
+        .inner
+        .run(|s| s.channel_direct_tcpip(&endpoint.hostname(), endpoint.port(), None))
+        .await
+        .with_context(|| format!("Failed to open SSH tunnel to {}", endpoint))?;
+
+    Ok(SshStream::new(channel, connection.inner.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{resolver::system::SystemResolver, ssh::client::Credential};
+    use std::{
+        net::TcpListener as StdTcpListener,
+        path::{Path, PathBuf},
+        process::{Command, Stdio},
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    fn find_sshd() -> Option<PathBuf> {
+        ["/usr/sbin/sshd", "/usr/local/sbin/sshd", "/sbin/sshd"]
+            .into_iter()
+            .map(PathBuf::from)
+            .find(|path| path.is_file())
+    }
+
+    fn keygen(path: &Path) -> Result<()> {
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+            .arg(path)
+            .status()?;
+        anyhow::ensure!(status.success(), "ssh-keygen failed");
+
+        Ok(())
+    }
+
+    // Runs against a throwaway sshd.
+    #[tokio::test]
+    #[ignore = "needs sshd and ssh-keygen installed"]
+    async fn test_connect() -> Result<()> {
+        let sshd = find_sshd().context("sshd is not installed")?;
+
+        let dir = tempfile::tempdir()?;
+        let host_key = dir.path().join("host_key");
+        let user_key = dir.path().join("user_key");
+        keygen(&host_key)?;
+        keygen(&user_key)?;
+
+        let port = StdTcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let config = dir.path().join("sshd_config");
+        std::fs::write(
+            &config,
+            format!(
+                "Port {port}\nListenAddress 127.0.0.1\nHostKey {}\nAuthorizedKeysFile {}.pub\n\
+                 PidFile none\nStrictModes no\nUsePAM no\nPasswordAuthentication no\n\
+                 AllowTcpForwarding yes\n",
+                host_key.display(),
+                user_key.display(),
+            ),
+        )?;
+
+        let known_hosts = dir.path().join("known_hosts");
+        std::fs::write(
+            &known_hosts,
+            format!(
+                "[127.0.0.1]:{port} {}",
+                std::fs::read_to_string(host_key.with_extension("pub"))?
+            ),
+        )?;
+
+        let mut server = Command::new(sshd)
+            .args(["-D", "-e", "-f"])
+            .arg(&config)
+            .stderr(Stdio::null())
+            .spawn()?;
+        let _guard = scopeguard::guard((), |_| {
+            let _ = server.kill();
+        });
+
+        let echo = TcpListener::bind("127.0.0.1:0").await?;
+        let target = Endpoint::new_from_addr(echo.local_addr()?);
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let username = std::env::var("USER").unwrap_or_else(|_| "root".to_owned());
+        let auth = Auth::new(
+            username,
+            Credential::PrivateKey {
+                path: user_key,
+                passphrase: None,
+            },
+            known_hosts,
+        );
+
+        // Give sshd some time to start listening.
+        let mut connection = None;
+        for _ in 0..50 {
+            match create_ssh_connection(
+                format!("127.0.0.1:{port}").parse()?,
+                SystemResolver::new(),
+                auth.clone(),
+            )
+            .await
+            {
+                Ok(c) => {
+                    connection = Some(c);
+                    break;
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+            }
+        }
+        let connection = connection.context("Failed to connect to sshd")?;
+
+        let mut stream = connect(&connection, &target).await?;
+        stream.write_all(b"hello").await?;
+
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+
+        Ok(())
+    }
+}
@@ -1,204 +1,345 @@
+use super::{
+    happy_eyeballs::{Connected, HappyEyeballConnector, Staggering, Transport},
+    Unreachable,
+};
 use crate::{
     core::{endpoint::Endpoint, resolver::Resolver},
     Result,
 };
-use futures::{future::FusedFuture, Future, FutureExt, TryFutureExt};
-use itertools::Itertools;
-use socket2::{Socket, TcpKeepalive};
+use anyhow::{ensure, Context};
+use futures::FutureExt;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
 use std::{
-    net::{IpAddr, Ipv4Addr, Ipv6Addr},
-    ops::Add,
-    pin::Pin,
-    time::{Duration, Instant},
-    vec::IntoIter,
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
 };
 use tokio::{
-    net::TcpStream,
-    time::{sleep_until, Sleep},
+    net::{TcpSocket, TcpStream},
+    time::timeout,
 };
 
-// TODO: Test the connector and add support for select IP versions.
-
-pub async fn connect(endpoint: &Endpoint, resolver: impl Resolver) -> Result<TcpStream> {
-    match endpoint {
-        Endpoint::Addr(addr) => Ok(TcpStream::connect(addr).await?),
-        Endpoint::Domain(host, port) => Ok(HappyEyeballConnector::new(&resolver, host, *port)
-            .await
-            .map(|s| {
-                let s: Socket = s.into_std().unwrap().into();
-                let _ = s.set_tcp_keepalive(
-                    &TcpKeepalive::new()
-                        .with_time(Duration::from_secs(60))
-                        .with_interval(Duration::from_secs(60)),
-                );
-                let s: std::net::TcpStream = s.into();
-                TcpStream::from_std(s).unwrap()
-            })?),
+impl Connected for TcpStream {
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_addr().ok().map(|addr| addr.ip())
     }
 }
 
-// Implementing https://datatracker.ietf.org/doc/html/rfc8305
-//
-// This is actually super complicated to implement so it's very unfortunate that
-// rust std does not provide support for this.
-//
-// Anyway, this implementation is implemented based on RFC8305 without
-// preference for IPv6, i.e., we will start connecting when we get the first DNS
-// response instead of waiting for AAAA result. Given the current status of IPv6
-// connectivity, it may be better if we prefer IPv4 connection.
-#[pin_project::pin_project]
-struct HappyEyeballConnector<'a> {
-    ipv4_future: Pin<Box<dyn FusedFuture<Output = Result<Vec<Ipv4Addr>>> + Send + 'a>>,
-    ipv6_future: Pin<Box<dyn FusedFuture<Output = Result<Vec<Ipv6Addr>>> + Send + 'a>>,
-    ips: IntoIter<IpAddr>,
-    ip_count: usize,
-    connections: Vec<Pin<Box<dyn FusedFuture<Output = Result<TcpStream>> + Send + 'static>>>,
-    next_connection_timer: Pin<Box<Sleep>>,
-    host: &'a str,
-    port: u16,
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keepalive {
+    pub time: Duration,
+    pub interval: Duration,
 }
 
-impl<'a> HappyEyeballConnector<'a> {
-    fn new(resolver: &'a impl Resolver, host: &'a str, port: u16) -> Self {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TcpOptions {
+    // `SO_BINDTODEVICE`, Linux only.
+    pub interface: Option<String>,
+    pub bind_ip: Option<IpAddr>,
+    // `SO_MARK`, Linux only.
+    pub mark: Option<u32>,
+    pub nodelay: bool,
+    pub keepalive: Option<Keepalive>,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    pub staggering: Staggering,
+    pub connect_timeout: Option<Duration>,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
         Self {
-            ipv4_future: Box::pin(resolver.lookup_ipv4(host).fuse()),
-            ipv6_future: Box::pin(resolver.lookup_ipv6(host).fuse()),
-            ips: Vec::new().into_iter(),
-            ip_count: 0,
-            connections: Vec::new(),
-            next_connection_timer: Box::pin(sleep_until(Instant::now().into())),
-            host,
-            port,
+            interface: None,
+            bind_ip: None,
+            mark: None,
+            nodelay: false,
+            keepalive: Some(Keepalive {
+                time: Duration::from_secs(60),
+                interval: Duration::from_secs(60),
+            }),
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            staggering: Staggering::default(),
+            connect_timeout: None,
         }
     }
-
-    fn is_resolving(&self) -> bool {
-        !(self.ipv4_future.is_terminated() && self.ipv6_future.is_terminated())
-    }
 }
 
-const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
-
-impl Future for HappyEyeballConnector<'_> {
-    type Output = Result<TcpStream>;
-
-    fn poll(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Self::Output> {
-        {
-            // Only need this for swapping IP iterators
-            let this = self.as_mut().project();
-            // First we poll the dns result. It doesn't matter in what we order we
-            // poll it since we are doing it at the same time.
-            if !this.ipv4_future.is_terminated() {
-                if let std::task::Poll::Ready(Ok(addrs)) = this.ipv4_future.poll_unpin(cx) {
-                    *this.ip_count += addrs.len();
-                    *this.ips = this
-                        .ips
-                        .interleave(addrs.into_iter().map(Into::into))
-                        .collect_vec()
-                        .into_iter();
-                };
-                // Ignore error
+impl TcpOptions {
+    fn socket(&self, addr: &SocketAddr) -> Result<Socket> {
+        let socket = Socket::new(
+            Domain::for_address(*addr),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        socket.set_nonblocking(true)?;
+
+        if let Some(interface) = &self.interface {
+            cfg_if::cfg_if! {
+                if #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))] {
+                    socket
+                        .bind_device(Some(interface.as_bytes()))
+                        .with_context(|| format!("Failed to bind to interface {}", interface))?;
+                } else {
+                    anyhow::bail!("Binding to interface {} is not supported on this platform", interface);
+                }
             }
+        }
 
-            if !this.ipv6_future.is_terminated() {
-                if let std::task::Poll::Ready(Ok(addrs)) = this.ipv6_future.poll_unpin(cx) {
-                    *this.ip_count += addrs.len();
-                    *this.ips = this
-                        .ips
-                        .interleave(addrs.into_iter().map(Into::into))
-                        .collect_vec()
-                        .into_iter();
-                };
-                // Ignore error
+        if let Some(mark) = self.mark {
+            cfg_if::cfg_if! {
+                if #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))] {
+                    socket
+                        .set_mark(mark)
+                        .with_context(|| format!("Failed to set mark {}", mark))?;
+                } else {
+                    anyhow::bail!("Setting mark {} is not supported on this platform", mark);
+                }
             }
         }
 
-        if !self.is_resolving() && self.ip_count == 0 {
-            return std::task::Poll::Ready(Err(anyhow::anyhow!(
-                "Failed to resolve domain {}",
-                self.host
-            )));
+        if let Some(ip) = self.bind_ip {
+            ensure!(
+                ip.is_ipv4() == addr.is_ipv4(),
+                "Can't connect to {} from {}",
+                addr,
+                ip
+            );
+            socket
+                .bind(&SocketAddr::new(ip, 0).into())
+                .with_context(|| format!("Failed to bind to {}", ip))?;
         }
 
-        // Now we poll all ongoing connections
-        let (has_pending, has_error, maybe_stream) =
-            self.connections
-                .iter_mut()
-                .fold((false, false, None), |state, c| {
-                    if state.2.is_some() {
-                        return state;
-                    }
-
-                    if c.is_terminated() {
-                        return state;
-                    }
-
-                    match c.poll_unpin(cx) {
-                        std::task::Poll::Ready(result) => match result {
-                            Ok(stream) => (state.0, state.1, Some(stream)),
-                            Err(_) => (state.0, true, None),
-                        },
-                        std::task::Poll::Pending => (true, state.1, None),
-                    }
-                });
-
-        if let Some(stream) = maybe_stream {
-            return std::task::Poll::Ready(Ok(stream));
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
         }
 
-        // Check if we should make new connection
-        if !has_pending // No ongoing connection, create a new one now.
-            || has_error // One connection is ended, we should start a new one now.
-            || self.next_connection_timer.as_mut().poll(cx) == std::task::Poll::Ready(())
-        {
-            // Loop until we successfully makes a connection.
-            loop {
-                match self.ips.next() {
-                    Some(addr) => {
-                        let mut fut = Box::pin(
-                            TcpStream::connect((addr, self.port))
-                                .map_err(|e| e.into())
-                                .fuse(),
-                        );
-                        match fut.poll_unpin(cx) {
-                            std::task::Poll::Ready(result) => match result {
-                                // This should be unreachable actually.
-                                Ok(s) => return std::task::Poll::Ready(Ok(s)),
-                                // Try next IP.
-                                Err(_) => continue,
-                            },
-                            // Good, we initiated an ongoing connection.
-                            std::task::Poll::Pending => {
-                                self.next_connection_timer
-                                    .as_mut()
-                                    .reset(Instant::now().add(CONNECTION_ATTEMPT_DELAY).into());
-                                // The result should always be pending.
-                                assert_eq!(
-                                    self.next_connection_timer.poll_unpin(cx),
-                                    std::task::Poll::Pending
-                                );
-                                self.connections.push(fut);
-                                break;
-                            }
-                        }
-                    }
-                    None => {
-                        if !self.is_resolving() {
-                            return std::task::Poll::Ready(Err(anyhow::anyhow!(
-                                "Failed to connect to domain {}",
-                                self.host
-                            )));
-                        } else {
-                            break;
-                        }
-                    }
-                }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+
+        Ok(socket)
+    }
+
+    // Options that only make sense on a connected socket.
+    fn apply(&self, stream: &TcpStream) -> Result<()> {
+        let socket = SockRef::from(stream);
+
+        socket.set_tcp_nodelay(self.nodelay)?;
+
+        if let Some(keepalive) = &self.keepalive {
+            socket.set_tcp_keepalive(
+                &TcpKeepalive::new()
+                    .with_time(keepalive.time)
+                    .with_interval(keepalive.interval),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn connect_addr(addr: SocketAddr, options: TcpOptions) -> Result<TcpStream> {
+    let socket = TcpSocket::from_std_stream(options.socket(&addr)?.into());
+    // Only a refusal says anything about the target, any other failure may
+    // be down to this host, e.g., its network or socket options.
+    let stream = socket.connect(addr).await.map_err(|e| {
+        if e.kind() == ErrorKind::ConnectionRefused {
+            anyhow::Error::from(e).context(Unreachable(Endpoint::Addr(addr)))
+        } else {
+            e.into()
+        }
+    })?;
+
+    options.apply(&stream)?;
+
+    Ok(stream)
+}
+
+pub async fn connect(endpoint: &Endpoint, resolver: impl Resolver) -> Result<TcpStream> {
+    connect_with_options(endpoint, resolver, &TcpOptions::default()).await
+}
+
+pub async fn connect_with_options(
+    endpoint: &Endpoint,
+    resolver: impl Resolver,
+    options: &TcpOptions,
+) -> Result<TcpStream> {
+    let connect = async {
+        match endpoint {
+            Endpoint::Addr(addr) => {
+                ensure!(
+                    options.staggering.family.allows(&addr.ip()),
+                    "Connecting to {} is not allowed by {:?}",
+                    addr,
+                    options.staggering.family
+                );
+
+                connect_addr(*addr, options.clone()).await
+            }
+            Endpoint::Domain(host, port) => {
+                HappyEyeballConnector::new(
+                    &resolver,
+                    host,
+                    *port,
+                    options.into(),
+                    options.staggering,
+                    |addr| connect_addr(addr, options.clone()).boxed(),
+                )
+                .await
             }
         }
+    };
+
+    match options.connect_timeout {
+        Some(duration) => timeout(duration, connect)
+            .await
+            .with_context(|| format!("Connecting to {} timed out", endpoint))?,
+        None => connect.await,
+    }
+    .map_err(|e| {
+        // A refused address of a domain means the domain is unreachable.
+        if e.downcast_ref::<Unreachable>().is_some() && !Unreachable::is(&e, endpoint) {
+            e.context(Unreachable(endpoint.clone()))
+        } else {
+            e
+        }
+    })
+}
+
+impl From<&TcpOptions> for Transport {
+    fn from(options: &TcpOptions) -> Self {
+        Self::Tcp {
+            interface: options.interface.clone(),
+            bind_ip: options.bind_ip,
+            mark: options.mark,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::connector::happy_eyeballs::tests::LocalResolver;
+    use rstest::rstest;
+    use std::{net::Ipv4Addr, time::Instant};
+    use tokio::net::TcpListener;
+
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    #[tokio::test]
+    async fn test_happy_eyeballs_with_options(#[case] nodelay: bool) -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+
+        let options = TcpOptions {
+            bind_ip: Some(Ipv4Addr::LOCALHOST.into()),
+            nodelay,
+            ..Default::default()
+        };
+
+        let stream = connect_with_options(
+            &Endpoint::new_from_domain(&format!("options-{}.test", nodelay), port),
+            LocalResolver::default(),
+            &options,
+        )
+        .await?;
+
+        assert_eq!(stream.local_addr()?.ip(), IpAddr::from(Ipv4Addr::LOCALHOST));
+        assert_eq!(stream.nodelay()?, nodelay);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remember_working_address() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let endpoint = Endpoint::new_from_domain("remember.test", listener.local_addr()?.port());
+
+        let failing = || LocalResolver {
+            fail: true,
+            ..Default::default()
+        };
+
+        assert!(connect(&endpoint, failing()).await.is_err());
+        connect(&endpoint, LocalResolver::default()).await?;
+
+        // DNS is not needed anymore.
+        let stream = connect(&endpoint, failing()).await?;
+        assert_eq!(stream.peer_addr()?, listener.local_addr()?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() -> Result<()> {
+        let options = TcpOptions {
+            connect_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        let result = connect_with_options(
+            &Endpoint::new_from_domain("timeout.test", 80),
+            LocalResolver {
+                ipv4_delay: Duration::from_secs(10),
+                ipv6_delay: Duration::from_secs(10),
+                fail: false,
+                ..Default::default()
+            },
+            &options,
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::refused(false, false, true)]
+    #[case::dns_failure(true, false, false)]
+    #[case::timeout(false, true, false)]
+    #[tokio::test]
+    async fn test_unreachable(
+        #[case] dns_failure: bool,
+        #[case] timeout: bool,
+        #[case] unreachable: bool,
+    ) -> Result<()> {
+        // Nothing listens on the port once the listener is dropped.
+        let port = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+        let endpoint = Endpoint::new_from_domain(
+            &format!("unreachable-{}-{}.test", dns_failure, timeout),
+            port,
+        );
+
+        let delay = if timeout {
+            Duration::from_secs(10)
+        } else {
+            Duration::ZERO
+        };
+        let result = connect_with_options(
+            &endpoint,
+            LocalResolver {
+                ipv4_delay: delay,
+                ipv6_delay: delay,
+                fail: dns_failure,
+                ..Default::default()
+            },
+            &TcpOptions {
+                connect_timeout: Some(Duration::from_millis(50)),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        assert_eq!(
+            Unreachable::is(&result.unwrap_err(), &endpoint),
+            unreachable
+        );
 
-        std::task::Poll::Pending
+        Ok(())
     }
 }
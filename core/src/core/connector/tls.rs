@@ -3,11 +3,24 @@ use crate::{
     Result,
 };
 use anyhow::Context;
+use tokio_native_tls::TlsStream;
 
 pub async fn connect(endpoint: &Endpoint, nexthop: impl Io) -> Result<impl Io> {
+    connect_with_alpn(endpoint, nexthop, &[]).await
+}
+
+pub async fn connect_with_alpn<I: Io>(
+    endpoint: &Endpoint,
+    nexthop: I,
+    alpn_protocols: &[&str],
+) -> Result<TlsStream<I>> {
+    let mut builder = tokio_native_tls::native_tls::TlsConnector::builder();
+    if !alpn_protocols.is_empty() {
+        builder.request_alpns(alpn_protocols);
+    }
+
     let s = tokio_native_tls::TlsConnector::from(
-        tokio_native_tls::native_tls::TlsConnector::new()
-            .context("Failed to create TLS connector")?,
+        builder.build().context("Failed to create TLS connector")?,
     )
     .connect(&endpoint.hostname(), nexthop)
     .await
@@ -0,0 +1,109 @@
+use crate::{
+    core::{datagram::Datagram, endpoint::Endpoint, resolver::Resolver},
+    Result,
+};
+use anyhow::{anyhow, Context};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    rc::Rc,
+};
+use tokio::net::UdpSocket;
+
+// Sends packets straight to the targets, from one socket which is dual stack
+// when the system allows it.
+#[derive(Debug)]
+pub struct DirectDatagram {
+    socket: UdpSocket,
+    resolver: Rc<dyn Resolver + Sync>,
+    ipv6: bool,
+}
+
+impl DirectDatagram {
+    async fn resolve(&self, target: &Endpoint) -> Result<SocketAddr> {
+        let addr = match target {
+            Endpoint::Addr(addr) => *addr,
+            Endpoint::Domain(domain, port) => {
+                let ips = self.resolver.lookup_ip(domain).await?;
+                let ip = ips
+                    .iter()
+                    .find(|ip| self.ipv6 || ip.is_ipv4())
+                    .ok_or_else(|| anyhow!("No usable address found for {}", domain))?;
+
+                SocketAddr::new(*ip, *port)
+            }
+        };
+
+        match addr {
+            SocketAddr::V4(v4) if self.ipv6 => {
+                Ok(SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()))
+            }
+            addr => Ok(addr),
+        }
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl Datagram for DirectDatagram {
+    async fn send_to(&self, payload: &[u8], target: &Endpoint) -> Result<()> {
+        let addr = self.resolve(target).await?;
+        self.socket
+            .send_to(payload, addr)
+            .await
+            .with_context(|| format!("Failed to send UDP packet to {}", target))?;
+
+        Ok(())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, Endpoint)> {
+        let (len, addr) = self.socket.recv_from(buf).await?;
+
+        Ok((
+            len,
+            Endpoint::new_from_addr(SocketAddr::new(addr.ip().to_canonical(), addr.port())),
+        ))
+    }
+}
+
+pub async fn bind(resolver: Rc<dyn Resolver + Sync>) -> Result<DirectDatagram> {
+    let (socket, ipv6) = match UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await {
+        Ok(socket) => (socket, true),
+        Err(_) => (UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?, false),
+    };
+
+    Ok(DirectDatagram {
+        socket,
+        resolver,
+        ipv6,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::resolver::system::SystemResolver;
+
+    #[tokio::test]
+    async fn test_direct() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").await?;
+        let port = server.local_addr()?.port();
+
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            while let Ok((len, from)) = server.recv_from(&mut buf).await {
+                let _ = server.send_to(&buf[..len], from).await;
+            }
+        });
+
+        let datagram = bind(Rc::new(SystemResolver::new())).await?;
+        datagram
+            .send_to(b"ping", &format!("127.0.0.1:{port}").parse()?)
+            .await?;
+
+        let mut buf = [0; 1024];
+        let (len, from) = datagram.recv_from(&mut buf).await?;
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(from.to_string(), format!("127.0.0.1:{port}"));
+
+        Ok(())
+    }
+}
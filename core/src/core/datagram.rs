@@ -0,0 +1,109 @@
+use crate::{core::endpoint::Endpoint, Result};
+use anyhow::{bail, ensure, Context};
+use std::{
+    fmt::Debug,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+// Large enough for any UDP payload.
+pub const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// The datagram counterpart of `Io`, carrying UDP packets to and from any
+/// endpoint.
+#[async_trait::async_trait(?Send)]
+pub trait Datagram: Debug {
+    async fn send_to(&self, payload: &[u8], target: &Endpoint) -> Result<()>;
+
+    /// Receive a packet into `buf`, returning its length and where it came
+    /// from.
+    async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, Endpoint)>;
+}
+
+// Endpoints in UDP packet headers use the SOCKS5 address format, ATYP
+// followed by the address and a big endian port.
+pub fn encode_address(endpoint: &Endpoint, buf: &mut Vec<u8>) -> Result<()> {
+    match endpoint {
+        Endpoint::Addr(SocketAddr::V4(addr)) => {
+            buf.push(1);
+            buf.extend_from_slice(&addr.ip().octets());
+        }
+        Endpoint::Addr(SocketAddr::V6(addr)) => {
+            buf.push(4);
+            buf.extend_from_slice(&addr.ip().octets());
+        }
+        Endpoint::Domain(domain, _) => {
+            let len: u8 = domain
+                .len()
+                .try_into()
+                .context("The socks5 protocol cannot support domain longer than 255 bytes.")?;
+            buf.push(3);
+            buf.push(len);
+            buf.extend_from_slice(domain.as_bytes());
+        }
+    }
+    buf.extend_from_slice(&endpoint.port().to_be_bytes());
+
+    Ok(())
+}
+
+// Returns the endpoint and the length of the encoded address.
+pub fn decode_address(buf: &[u8]) -> Result<(Endpoint, usize)> {
+    ensure!(!buf.is_empty(), "Missing address in UDP packet");
+
+    let (host, len) = match buf[0] {
+        1 => {
+            ensure!(buf.len() >= 7, "Truncated address in UDP packet");
+            let ip: [u8; 4] = buf[1..5].try_into()?;
+            (Ok(IpAddr::from(Ipv4Addr::from(ip))), 5)
+        }
+        3 => {
+            ensure!(buf.len() >= 2, "Truncated address in UDP packet");
+            let domain_len: usize = buf[1].into();
+            ensure!(
+                buf.len() >= domain_len + 4,
+                "Truncated address in UDP packet"
+            );
+            let domain = std::str::from_utf8(&buf[2..2 + domain_len])
+                .context("Not a valid domain in UDP packet")?;
+            (Err(domain.to_owned()), domain_len + 2)
+        }
+        4 => {
+            ensure!(buf.len() >= 19, "Truncated address in UDP packet");
+            let ip: [u8; 16] = buf[1..17].try_into()?;
+            (Ok(IpAddr::from(Ipv6Addr::from(ip))), 17)
+        }
+        t => bail!("Unsupported address type {}", t),
+    };
+
+    let port = u16::from_be_bytes([buf[len], buf[len + 1]]);
+    let endpoint = match host {
+        Ok(ip) => Endpoint::new_from_addr(SocketAddr::new(ip, port)),
+        Err(domain) => Endpoint::new_from_domain(&domain, port),
+    };
+
+    Ok((endpoint, len + 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("127.0.0.1:53", 7)]
+    #[case("[::1]:53", 19)]
+    #[case("example.com:443", 15)]
+    fn test_address(#[case] endpoint: &str, #[case] len: usize) -> Result<()> {
+        let endpoint: Endpoint = endpoint.parse()?;
+
+        let mut buf = Vec::new();
+        encode_address(&endpoint, &mut buf)?;
+        assert_eq!(buf.len(), len);
+
+        buf.extend_from_slice(b"payload");
+        assert_eq!(decode_address(&buf)?, (endpoint, len));
+        assert!(decode_address(&buf[..len - 1]).is_err());
+
+        Ok(())
+    }
+}
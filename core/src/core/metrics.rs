@@ -0,0 +1,354 @@
+use crate::Result;
+use anyhow::ensure;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{
+    body::Incoming, header::CONTENT_TYPE, server::conn::http1::Builder, service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use prometheus_client::{
+    encoding::text::encode,
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+use std::{
+    collections::HashMap,
+    rc::Rc,
+    sync::{Mutex, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::net::TcpListener;
+
+type Labels = Vec<(String, String)>;
+type HistogramFamily = Family<Labels, Histogram, fn() -> Histogram>;
+
+const CONTENT_TYPE_OPENMETRICS: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+// Names reserved for the built-in metrics.
+const RESERVED_PREFIX: &str = "dandelion_";
+
+lazy_static::lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+fn labels<const N: usize>(pairs: [(&str, &str); N]) -> Labels {
+    pairs
+        .into_iter()
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .collect()
+}
+
+// From 1ms to about 30s.
+fn latency_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(0.001, 2.0, 16))
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Everything exported on the metrics endpoint.
+#[derive(Debug)]
+pub struct Metrics {
+    active_connections: Family<Labels, Gauge>,
+    connections: Family<Labels, Counter>,
+    failures: Family<Labels, Counter>,
+    transferred_bytes: Family<Labels, Counter>,
+    handler_duration: HistogramFamily,
+    dns_lookup_duration: HistogramFamily,
+    geoip_age: Family<Labels, Gauge>,
+    // Build time of each loaded GeoIP database, the age is computed on scrape.
+    geoip_builds: Mutex<HashMap<String, u64>>,
+    counters: Mutex<HashMap<String, Family<Labels, Counter>>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            active_connections: Family::default(),
+            connections: Family::default(),
+            failures: Family::default(),
+            transferred_bytes: Family::default(),
+            handler_duration: Family::new_with_constructor(latency_histogram),
+            dns_lookup_duration: Family::new_with_constructor(latency_histogram),
+            geoip_age: Family::default(),
+            geoip_builds: Mutex::new(HashMap::new()),
+            counters: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Counts as an active connection of the acceptor until dropped.
+#[derive(Debug)]
+pub struct ActiveConnection<'a> {
+    metrics: &'a Metrics,
+    acceptor: Labels,
+}
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.metrics
+            .active_connections
+            .get_or_create(&self.acceptor)
+            .dec();
+    }
+}
+
+impl Metrics {
+    pub fn connection(&self, acceptor: &str) -> ActiveConnection<'_> {
+        let acceptor = labels([("acceptor", acceptor)]);
+
+        self.connections.get_or_create(&acceptor).inc();
+        self.active_connections.get_or_create(&acceptor).inc();
+
+        ActiveConnection {
+            metrics: self,
+            acceptor,
+        }
+    }
+
+    // `class` is the stage where the connection failed.
+    pub fn connection_failed(&self, acceptor: &str, class: &str) {
+        self.failures
+            .get_or_create(&labels([("acceptor", acceptor), ("class", class)]))
+            .inc();
+    }
+
+    pub fn transferred(&self, acceptor: &str, up: u64, down: u64) {
+        self.transferred_bytes
+            .get_or_create(&labels([("acceptor", acceptor), ("direction", "up")]))
+            .inc_by(up);
+        self.transferred_bytes
+            .get_or_create(&labels([("acceptor", acceptor), ("direction", "down")]))
+            .inc_by(down);
+    }
+
+    pub fn handler_evaluated(&self, handler: &str, duration: Duration) {
+        self.handler_duration
+            .get_or_create(&labels([("handler", handler)]))
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn dns_lookup(&self, resolver: &str, duration: Duration) {
+        self.dns_lookup_duration
+            .get_or_create(&labels([("resolver", resolver)]))
+            .observe(duration.as_secs_f64());
+    }
+
+    // `build_epoch` is the build time of the database in seconds since epoch.
+    pub fn geoip_loaded(&self, database: &str, build_epoch: u64) {
+        self.geoip_builds
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(database.to_owned(), build_epoch);
+    }
+
+    pub fn increment_counter(&self, name: &str, labels: Labels, value: u64) -> Result<()> {
+        ensure!(is_valid_name(name), "Invalid metric name {}", name);
+        ensure!(
+            !name.starts_with(RESERVED_PREFIX),
+            "Metric names starting with {} are reserved",
+            RESERVED_PREFIX
+        );
+        for (label, _) in &labels {
+            ensure!(is_valid_name(label), "Invalid label name {}", label);
+        }
+
+        self.counters
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(name.to_owned())
+            .or_default()
+            .get_or_create(&labels)
+            .inc_by(value);
+
+        Ok(())
+    }
+
+    /// Encodes all the metrics in the OpenMetrics text format.
+    pub fn encode(&self) -> Result<String> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        for (database, build_epoch) in self
+            .geoip_builds
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+        {
+            self.geoip_age
+                .get_or_create(&labels([("database", database)]))
+                .set(
+                    now.saturating_sub(*build_epoch)
+                        .try_into()
+                        .unwrap_or(i64::MAX),
+                );
+        }
+
+        // Families share their values with their clones, so a new registry is
+        // built for every scrape to pick up the counters created by scripts.
+        let mut registry = Registry::default();
+        registry.register(
+            "dandelion_active_connections",
+            "Connections being handled by each acceptor",
+            self.active_connections.clone(),
+        );
+        registry.register(
+            "dandelion_connections",
+            "Connections accepted by each acceptor",
+            self.connections.clone(),
+        );
+        registry.register(
+            "dandelion_connection_failures",
+            "Failed connections by the stage they failed in",
+            self.failures.clone(),
+        );
+        registry.register(
+            "dandelion_transferred_bytes",
+            "Bytes relayed by each acceptor",
+            self.transferred_bytes.clone(),
+        );
+        registry.register(
+            "dandelion_handler_duration_seconds",
+            "Time spent evaluating handlers",
+            self.handler_duration.clone(),
+        );
+        registry.register(
+            "dandelion_dns_lookup_duration_seconds",
+            "Time spent on DNS lookups by each resolver",
+            self.dns_lookup_duration.clone(),
+        );
+        registry.register(
+            "dandelion_geoip_age_seconds",
+            "Time since each GeoIP database was built",
+            self.geoip_age.clone(),
+        );
+        for (name, counter) in self
+            .counters
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+        {
+            registry.register(name, "Counter incremented by the config", counter.clone());
+        }
+
+        let mut output = String::new();
+        encode(&mut output, &registry)?;
+
+        Ok(output)
+    }
+}
+
+async fn handle(request: Request<Incoming>) -> Result<Response<Full<Bytes>>> {
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::default())?);
+    }
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, CONTENT_TYPE_OPENMETRICS)
+        .body(Full::new(Bytes::from(METRICS.encode()?)))?)
+}
+
+/// Serves the metrics at `/metrics` for Prometheus to scrape.
+pub async fn serve(listener: Rc<TcpListener>) -> Result<()> {
+    loop {
+        let (io, _) = listener.accept().await?;
+
+        tokio::task::spawn_local(async move {
+            if let Err(e) = Builder::new()
+                .serve_connection(TokioIo::new(io), service_fn(handle))
+                .await
+            {
+                tracing::debug!("Failed to serve metrics: {:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[test]
+    fn test_metrics() -> Result<()> {
+        let metrics = Metrics::default();
+
+        let connection = metrics.connection("socks5://127.0.0.1:1080");
+        metrics.connection("socks5://127.0.0.1:1080");
+        metrics.connection_failed("socks5://127.0.0.1:1080", "handler");
+        metrics.transferred("socks5://127.0.0.1:1080", 10, 20);
+        metrics.handler_evaluated("handler", Duration::from_millis(5));
+        metrics.dns_lookup("system", Duration::from_millis(5));
+        metrics.increment_counter("rule_hits", labels([("rule", "direct")]), 2)?;
+
+        let output = metrics.encode()?;
+        for line in [
+            r#"dandelion_active_connections{acceptor="socks5://127.0.0.1:1080"} 1"#,
+            r#"dandelion_connections_total{acceptor="socks5://127.0.0.1:1080"} 2"#,
+            r#"dandelion_connection_failures_total{acceptor="socks5://127.0.0.1:1080",class="handler"} 1"#,
+            r#"dandelion_transferred_bytes_total{acceptor="socks5://127.0.0.1:1080",direction="down"} 20"#,
+            r#"dandelion_handler_duration_seconds_count{handler="handler"} 1"#,
+            r#"dandelion_dns_lookup_duration_seconds_count{resolver="system"} 1"#,
+            r#"rule_hits_total{rule="direct"} 2"#,
+        ] {
+            assert!(output.contains(line), "{} not in {}", line, output);
+        }
+
+        drop(connection);
+        assert!(metrics
+            .encode()?
+            .contains(r#"dandelion_active_connections{acceptor="socks5://127.0.0.1:1080"} 0"#));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_geoip_age() -> Result<()> {
+        let metrics = Metrics::default();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        metrics.geoip_loaded("country.mmdb", now - 3600);
+
+        let output = metrics.encode()?;
+        let age: i64 = output
+            .lines()
+            .find_map(|line| {
+                line.strip_prefix(r#"dandelion_geoip_age_seconds{database="country.mmdb"} "#)
+            })
+            .unwrap()
+            .parse()?;
+        assert!((3600..3610).contains(&age));
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[case("rule_hits", "rule", true)]
+    #[case("rule-hits", "rule", false)]
+    #[case("1rule", "rule", false)]
+    #[case("dandelion_rules", "rule", false)]
+    #[case("rule_hits", "rule name", false)]
+    fn test_counter_name(#[case] name: &str, #[case] label: &str, #[case] valid: bool) {
+        assert_eq!(
+            Metrics::default()
+                .increment_counter(name, labels([(label, "value")]), 1)
+                .is_ok(),
+            valid
+        );
+    }
+}
@@ -1,8 +1,17 @@
 pub mod acceptor;
+pub mod access_log;
 pub mod connector;
+pub mod datagram;
 pub mod endpoint;
 pub mod io;
+pub mod metrics;
+pub mod mux;
+pub mod nat;
 pub mod quic;
+pub mod relay;
 pub mod resolver;
 pub mod simplex;
+#[cfg(unix)]
+pub mod ssh;
+pub mod throttle;
 pub mod tun;
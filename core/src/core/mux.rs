@@ -0,0 +1,318 @@
+use crate::{
+    core::{endpoint::Endpoint, io::Io},
+    Result,
+};
+use anyhow::{anyhow, Context};
+use futures::{
+    channel::{
+        mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    future::{poll_fn, ready},
+    Stream, StreamExt,
+};
+use std::{task::Poll, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::timeout,
+};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tracing::debug;
+use yamux::{Config, Connection, Mode};
+
+// Simplex clients ask for this endpoint to start a mux session instead of a
+// single tunnel. The `.invalid` TLD can never be a real target.
+pub const SESSION_ENDPOINT: &str = "mux.invalid:0";
+
+// Streams being read before they are handed to the acceptor.
+const MAX_PENDING_STREAMS: usize = 64;
+// How long a new stream may take to send its endpoint.
+const ENDPOINT_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Opener = oneshot::Sender<yamux::Result<yamux::Stream>>;
+
+// One logical connection of a session. Each stream starts with the target
+// endpoint, a big endian u16 length followed by the endpoint as a string.
+#[derive(Debug)]
+#[pin_project::pin_project]
+pub struct MuxStream {
+    #[pin]
+    inner: Compat<yamux::Stream>,
+    // Keeps the client session running while the stream is in use.
+    _session: Option<UnboundedSender<Opener>>,
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+// Drives the yamux connection until it's closed. The client session closes the
+// connection once the session and all its streams are dropped, the server
+// keeps it until the client closes it.
+async fn drive<I: Io>(
+    mut connection: Connection<Compat<I>>,
+    mut openers: UnboundedReceiver<Opener>,
+    inbound: Option<UnboundedSender<yamux::Stream>>,
+) {
+    let mut pending: Option<Opener> = None;
+    let mut closing = false;
+
+    let result = poll_fn(|cx| loop {
+        if closing {
+            return connection.poll_close(cx);
+        }
+
+        if pending.is_none() {
+            match openers.poll_next_unpin(cx) {
+                Poll::Ready(Some(opener)) => pending = Some(opener),
+                Poll::Ready(None) if inbound.is_none() => {
+                    closing = true;
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(opener) = pending.take() {
+            match connection.poll_new_outbound(cx) {
+                Poll::Ready(result) => {
+                    let _ = opener.send(result);
+                    continue;
+                }
+                Poll::Pending => pending = Some(opener),
+            }
+        }
+
+        match connection.poll_next_inbound(cx) {
+            Poll::Ready(Some(Ok(stream))) => {
+                // The client never accepts streams, drop them.
+                if let Some(inbound) = &inbound {
+                    let _ = inbound.unbounded_send(stream);
+                }
+            }
+            Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+            Poll::Ready(None) => return Poll::Ready(Ok(())),
+            Poll::Pending => return Poll::Pending,
+        }
+    })
+    .await;
+
+    if let Err(e) = result {
+        debug!("Mux session closed with error: {}", e);
+    }
+}
+
+#[derive(Debug)]
+pub struct Session {
+    openers: UnboundedSender<Opener>,
+}
+
+impl Session {
+    pub fn client(io: impl Io) -> Self {
+        let (openers, openers_rx) = unbounded();
+
+        tokio::task::spawn_local(drive(
+            Connection::new(io.compat(), Config::default(), Mode::Client),
+            openers_rx,
+            None,
+        ));
+
+        Self { openers }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.openers.is_closed()
+    }
+
+    async fn open_stream(&self) -> Result<MuxStream> {
+        let (opener, stream) = oneshot::channel();
+        self.openers
+            .unbounded_send(opener)
+            .map_err(|_| anyhow!("Mux session is closed"))?;
+
+        Ok(MuxStream {
+            inner: stream
+                .await
+                .context("Mux session is closed")?
+                .context("Failed to open mux stream")?
+                .compat(),
+            _session: Some(self.openers.clone()),
+        })
+    }
+
+    pub async fn open(&self, endpoint: &Endpoint) -> Result<MuxStream> {
+        let mut stream = self.open_stream().await?;
+
+        let endpoint = endpoint.to_string();
+        stream.write_u16(endpoint.len().try_into()?).await?;
+        stream.write_all(endpoint.as_bytes()).await?;
+
+        Ok(stream)
+    }
+}
+
+async fn read_endpoint(mut stream: MuxStream) -> Result<(Endpoint, MuxStream)> {
+    let len = stream.read_u16().await?;
+    let mut endpoint = vec![0; len.into()];
+    stream.read_exact(&mut endpoint).await?;
+
+    Ok((String::from_utf8(endpoint)?.parse()?, stream))
+}
+
+// Streams that don't send a valid endpoint in time are dropped, so they can't
+// hold up the streams behind them.
+async fn read_endpoint_in_time(stream: MuxStream) -> Option<Result<(Endpoint, MuxStream)>> {
+    match timeout(ENDPOINT_TIMEOUT, read_endpoint(stream)).await {
+        Ok(Ok(result)) => Some(Ok(result)),
+        Ok(Err(e)) => {
+            debug!("Dropped mux stream with invalid endpoint: {:?}", e);
+            None
+        }
+        Err(_) => {
+            debug!("Dropped mux stream that didn't send its endpoint in time");
+            None
+        }
+    }
+}
+
+// Serve a mux session, yielding each stream along with its target endpoint.
+pub fn accept(io: impl Io) -> impl Stream<Item = Result<(Endpoint, MuxStream)>> {
+    let (inbound, inbound_rx) = unbounded();
+    // The server never opens streams.
+    let (_, openers_rx) = unbounded();
+
+    tokio::task::spawn_local(drive(
+        Connection::new(io.compat(), Config::default(), Mode::Server),
+        openers_rx,
+        Some(inbound),
+    ));
+
+    inbound_rx
+        .map(|stream: yamux::Stream| {
+            read_endpoint_in_time(MuxStream {
+                inner: stream.compat(),
+                _session: None,
+            })
+        })
+        .buffer_unordered(MAX_PENDING_STREAMS)
+        .filter_map(ready)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+    use tokio::{io::duplex, task::LocalSet};
+
+    #[tokio::test]
+    async fn test_session() -> Result<()> {
+        LocalSet::new()
+            .run_until(async move {
+                let (client, server) = duplex(65536);
+
+                let mut streams = accept(server);
+                tokio::task::spawn_local(async move {
+                    while let Ok(Some((endpoint, mut stream))) = streams.try_next().await {
+                        tokio::task::spawn_local(async move {
+                            stream.write_all(endpoint.to_string().as_bytes()).await?;
+                            stream.shutdown().await?;
+
+                            anyhow::Ok(())
+                        });
+                    }
+                });
+
+                let session = Session::client(client);
+                for endpoint in ["example.com:443", "127.0.0.1:80"] {
+                    let mut stream = session.open(&endpoint.parse()?).await?;
+
+                    let mut response = String::new();
+                    stream.read_to_string(&mut response).await?;
+                    assert_eq!(response, endpoint);
+                }
+
+                anyhow::Ok(())
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_close_after_drop() -> Result<()> {
+        LocalSet::new()
+            .run_until(async move {
+                let (client, server) = duplex(65536);
+
+                let mut streams = accept(server);
+                let session = Session::client(client);
+                let stream = session.open(&"example.com:443".parse()?).await?;
+                assert!(streams.try_next().await?.is_some());
+
+                // Streams keep the session running.
+                drop(session);
+                drop(stream);
+
+                assert!(streams.try_next().await?.is_none());
+
+                anyhow::Ok(())
+            })
+            .await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_streams() -> Result<()> {
+        LocalSet::new()
+            .run_until(async move {
+                let (client, server) = duplex(65536);
+
+                let mut streams = accept(server);
+                let session = Session::client(client);
+
+                // Enough streams that never finish their endpoint to take every slot.
+                let mut stalled = Vec::new();
+                for _ in 0..MAX_PENDING_STREAMS {
+                    let mut stream = session.open_stream().await?;
+                    stream.write_u8(0).await?;
+                    stalled.push(stream);
+                }
+                let _stream = session.open(&"example.com:443".parse()?).await?;
+
+                let (endpoint, _) = timeout(ENDPOINT_TIMEOUT * 2, streams.try_next())
+                    .await??
+                    .unwrap();
+                assert_eq!(endpoint.to_string(), "example.com:443");
+
+                anyhow::Ok(())
+            })
+            .await
+    }
+}
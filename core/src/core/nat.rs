@@ -0,0 +1,351 @@
+use crate::{
+    core::{
+        datagram::{Datagram, MAX_DATAGRAM_SIZE},
+        endpoint::Endpoint,
+    },
+    Result,
+};
+use futures::Future;
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    hash::Hash,
+    rc::Rc,
+    time::Duration,
+};
+use tokio::{
+    task::{spawn_local, AbortHandle},
+    time::{sleep_until, Instant},
+};
+use tracing::debug;
+
+#[derive(Debug)]
+struct Flow {
+    datagram: Rc<dyn Datagram>,
+    last_active: Cell<Instant>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    flow: Rc<Flow>,
+    // Forwards the replies of the flow.
+    task: AbortHandle,
+}
+
+// A flow that is still being created, with the packets waiting for it.
+#[derive(Debug)]
+struct Pending {
+    packets: Vec<(Vec<u8>, Endpoint)>,
+    task: AbortHandle,
+}
+
+// Packets beyond this are dropped while the flow is created.
+const MAX_PENDING_PACKETS: usize = 64;
+
+// Tracks UDP flows like a NAT does. Packets with the same key share the flow
+// created for the first of them, flows idle for longer than the timeout are
+// closed. Flows live on the local task set and are closed with the table.
+#[derive(Debug)]
+pub struct Nat<K: Eq + Hash> {
+    idle_timeout: Duration,
+    flows: Rc<RefCell<HashMap<K, Entry>>>,
+    pending: Rc<RefCell<HashMap<K, Pending>>>,
+}
+
+impl<K: Eq + Hash + Clone + 'static> Nat<K> {
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            idle_timeout,
+            flows: Rc::default(),
+            pending: Rc::default(),
+        }
+    }
+
+    pub fn active_flows(&self) -> usize {
+        self.flows.borrow().len()
+    }
+
+    // Send the packet through the flow of `key`, which is created by `create`
+    // if there is none. Replies of the flow are handed to `reply`.
+    //
+    // Creating a flow may take a while, so it's done in the background with
+    // the packets of the flow queued until then, and packets of other flows
+    // aren't held up.
+    pub async fn send<C, R, RF>(
+        &self,
+        key: K,
+        payload: &[u8],
+        target: &Endpoint,
+        create: impl FnOnce() -> C + 'static,
+        reply: R,
+    ) -> Result<()>
+    where
+        C: Future<Output = Result<Rc<dyn Datagram>>> + 'static,
+        R: Fn(Vec<u8>, Endpoint) -> RF + 'static,
+        RF: Future<Output = Result<()>> + 'static,
+    {
+        let existing = self
+            .flows
+            .borrow()
+            .get(&key)
+            .map(|entry| entry.flow.clone());
+
+        if let Some(flow) = existing {
+            flow.last_active.set(Instant::now());
+            return flow.datagram.send_to(payload, target).await;
+        }
+
+        if let Some(pending) = self.pending.borrow_mut().get_mut(&key) {
+            if pending.packets.len() < MAX_PENDING_PACKETS {
+                pending.packets.push((payload.to_vec(), target.clone()));
+            }
+            return Ok(());
+        }
+
+        let task = spawn_local(create_flow(
+            key.clone(),
+            create,
+            self.flows.clone(),
+            self.pending.clone(),
+            self.idle_timeout,
+            reply,
+        ))
+        .abort_handle();
+
+        self.pending.borrow_mut().insert(
+            key,
+            Pending {
+                packets: vec![(payload.to_vec(), target.clone())],
+                task,
+            },
+        );
+
+        Ok(())
+    }
+}
+
+async fn create_flow<K, C, R, RF>(
+    key: K,
+    create: impl FnOnce() -> C,
+    flows: Rc<RefCell<HashMap<K, Entry>>>,
+    pending: Rc<RefCell<HashMap<K, Pending>>>,
+    idle_timeout: Duration,
+    reply: R,
+) where
+    K: Eq + Hash + Clone + 'static,
+    C: Future<Output = Result<Rc<dyn Datagram>>> + 'static,
+    R: Fn(Vec<u8>, Endpoint) -> RF + 'static,
+    RF: Future<Output = Result<()>> + 'static,
+{
+    let result = create().await;
+    let packets = pending
+        .borrow_mut()
+        .remove(&key)
+        .map(|pending| pending.packets)
+        .unwrap_or_default();
+
+    let datagram = match result {
+        Ok(datagram) => datagram,
+        Err(e) => {
+            debug!("Dropped {} UDP packets: {:?}", packets.len(), e);
+            return;
+        }
+    };
+
+    let flow = Rc::new(Flow {
+        datagram,
+        last_active: Cell::new(Instant::now()),
+    });
+
+    let task = spawn_local(forward_replies(
+        key.clone(),
+        flow.clone(),
+        flows.clone(),
+        idle_timeout,
+        reply,
+    ))
+    .abort_handle();
+
+    let replaced = flows.borrow_mut().insert(
+        key,
+        Entry {
+            flow: flow.clone(),
+            task,
+        },
+    );
+    if let Some(replaced) = replaced {
+        replaced.task.abort();
+    }
+
+    for (payload, target) in packets {
+        if let Err(e) = flow.datagram.send_to(&payload, &target).await {
+            debug!("Dropped UDP packet to {}: {:?}", target, e);
+        }
+    }
+}
+
+impl<K: Eq + Hash> Drop for Nat<K> {
+    fn drop(&mut self) {
+        for (_, pending) in self.pending.borrow_mut().drain() {
+            pending.task.abort();
+        }
+        for (_, entry) in self.flows.borrow_mut().drain() {
+            entry.task.abort();
+        }
+    }
+}
+
+async fn forward_replies<K: Eq + Hash, RF: Future<Output = Result<()>>>(
+    key: K,
+    flow: Rc<Flow>,
+    flows: Rc<RefCell<HashMap<K, Entry>>>,
+    idle_timeout: Duration,
+    reply: impl Fn(Vec<u8>, Endpoint) -> RF,
+) {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+
+    loop {
+        tokio::select! {
+            result = flow.datagram.recv_from(&mut buf) => {
+                let result = match result {
+                    Ok((len, from)) => {
+                        flow.last_active.set(Instant::now());
+                        reply(buf[..len].to_vec(), from).await
+                    }
+                    Err(e) => Err(e),
+                };
+
+                if let Err(e) = result {
+                    debug!("UDP flow closed with error: {:?}", e);
+                    break;
+                }
+            }
+            _ = sleep_until(flow.last_active.get() + idle_timeout) => {
+                if flow.last_active.get().elapsed() >= idle_timeout {
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut flows = flows.borrow_mut();
+    if flows
+        .get(&key)
+        .is_some_and(|entry| Rc::ptr_eq(&entry.flow, &flow))
+    {
+        flows.remove(&key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{connector::udp::bind, resolver::system::SystemResolver};
+    use tokio::{net::UdpSocket, sync::mpsc::unbounded_channel, task::LocalSet, time::sleep};
+
+    #[tokio::test]
+    async fn test_nat() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").await?;
+        let target = Endpoint::new_from_addr(server.local_addr()?);
+
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            while let Ok((len, from)) = server.recv_from(&mut buf).await {
+                let _ = server.send_to(&buf[..len], from).await;
+            }
+        });
+
+        LocalSet::new()
+            .run_until(async move {
+                let nat = Nat::new(Duration::from_millis(200));
+                let (replies, mut received) = unbounded_channel();
+                let created = Rc::new(Cell::new(0));
+
+                for (key, payload) in [(1, "a"), (2, "b"), (1, "c")] {
+                    let replies = replies.clone();
+                    let created = created.clone();
+                    nat.send(
+                        key,
+                        payload.as_bytes(),
+                        &target,
+                        move || async move {
+                            created.set(created.get() + 1);
+                            let datagram = bind(Rc::new(SystemResolver::new())).await?;
+                            Ok(Rc::new(datagram) as Rc<dyn Datagram>)
+                        },
+                        move |payload, _| {
+                            let _ = replies.send((key, payload));
+                            async { Ok(()) }
+                        },
+                    )
+                    .await?;
+                }
+
+                let mut got = Vec::new();
+                for _ in 0..3 {
+                    got.push(received.recv().await.unwrap());
+                }
+                got.sort();
+                assert_eq!(
+                    got,
+                    [(1, b"a".to_vec()), (1, b"c".to_vec()), (2, b"b".to_vec())]
+                );
+                assert_eq!(created.get(), 2);
+                assert_eq!(nat.active_flows(), 2);
+
+                sleep(Duration::from_millis(400)).await;
+                assert_eq!(nat.active_flows(), 0);
+
+                anyhow::Ok(())
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_slow_flow() -> Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").await?;
+        let target = Endpoint::new_from_addr(server.local_addr()?);
+
+        tokio::spawn(async move {
+            let mut buf = [0; 1024];
+            while let Ok((len, from)) = server.recv_from(&mut buf).await {
+                let _ = server.send_to(&buf[..len], from).await;
+            }
+        });
+
+        LocalSet::new()
+            .run_until(async move {
+                let nat = Nat::new(Duration::from_secs(10));
+                let (replies, mut received) = unbounded_channel();
+
+                // The flow of key 1 is never created, which must not hold up
+                // the packets of key 2.
+                for (key, payload) in [(1, "a"), (1, "b"), (2, "c")] {
+                    let replies = replies.clone();
+                    nat.send(
+                        key,
+                        payload.as_bytes(),
+                        &target,
+                        move || async move {
+                            if key == 1 {
+                                std::future::pending::<()>().await;
+                            }
+                            let datagram = bind(Rc::new(SystemResolver::new())).await?;
+                            Ok(Rc::new(datagram) as Rc<dyn Datagram>)
+                        },
+                        move |payload, _| {
+                            let _ = replies.send((key, payload));
+                            async { Ok(()) }
+                        },
+                    )
+                    .await?;
+                }
+
+                assert_eq!(received.recv().await, Some((2, b"c".to_vec())));
+                assert_eq!(nat.active_flows(), 1);
+
+                anyhow::Ok(())
+            })
+            .await
+    }
+}
@@ -1,59 +1,345 @@
-use std::sync::Arc;
-
 use crate::{
-    core::{endpoint::Endpoint, resolver::Resolver},
+    core::{
+        connector::happy_eyeballs::{Connected, HappyEyeballConnector, Staggering, Transport},
+        endpoint::Endpoint,
+        resolver::Resolver,
+    },
     Result,
 };
-use anyhow::bail;
-use futures::{future::select_ok, FutureExt};
-use quinn::{crypto::rustls::QuicClientConfig, ClientConfig, Connection, Endpoint as QuicEndpoint};
-use rustls_platform_verifier::ConfigVerifierExt;
+use anyhow::{bail, ensure, Context};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use futures::FutureExt;
+use quinn::{
+    congestion::{BbrConfig, ControllerFactory, CubicConfig, NewRenoConfig},
+    crypto::rustls::QuicClientConfig,
+    ClientConfig, Connection, Endpoint as QuicEndpoint, IdleTimeout, TransportConfig, VarInt,
+};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    pki_types::{pem::PemObject, CertificateDer, ServerName, UnixTime},
+    DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use rustls_platform_verifier::Verifier;
+use sha2::{Digest, Sha256};
+use std::{
+    cell::RefCell,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use webpki::EndEntityCert;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Congestion {
+    #[default]
+    Cubic,
+    Bbr,
+    NewReno,
+}
+
+impl FromStr for Congestion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "cubic" => Self::Cubic,
+            "bbr" => Self::Bbr,
+            "newreno" => Self::NewReno,
+            _ => bail!("Unknown congestion controller: {}", s),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuicOptions {
+    // Trust only the CAs in this PEM file instead of the system roots.
+    pub ca_file: Option<PathBuf>,
+    // SHA-256 digests of the accepted server public keys (SPKI). Checked in
+    // addition to the certificate chain.
+    pub pins: Vec<[u8; 32]>,
+    // Server name to send and verify the certificate against, defaults to the
+    // host of the server, or its IP.
+    pub sni: Option<String>,
+    // Resolved addresses are raced with Happy Eyeballs, same as TCP.
+    pub staggering: Staggering,
+    pub congestion: Congestion,
+    pub idle_timeout: Duration,
+    // Keep idle connections alive so they are ready for the next stream.
+    pub keep_alive_interval: Option<Duration>,
+    pub max_concurrent_streams: Option<u32>,
+    pub stream_receive_window: Option<u32>,
+    pub receive_window: Option<u32>,
+    pub send_window: Option<u64>,
+}
+
+impl Default for QuicOptions {
+    fn default() -> Self {
+        Self {
+            ca_file: None,
+            pins: Vec::new(),
+            sni: None,
+            staggering: Staggering::default(),
+            congestion: Congestion::default(),
+            idle_timeout: Duration::from_secs(60),
+            keep_alive_interval: Some(Duration::from_secs(15)),
+            max_concurrent_streams: None,
+            stream_receive_window: None,
+            receive_window: None,
+            send_window: None,
+        }
+    }
+}
+
+impl QuicOptions {
+    // Same format as `curl --pinnedpubkey`, `sha256//` followed by the base64
+    // encoded digest, the prefix is optional.
+    pub fn add_pin(&mut self, pin: &str) -> Result<()> {
+        let encoded = pin.strip_prefix("sha256//").unwrap_or(pin);
+        let digest = BASE64_STANDARD
+            .decode(encoded)
+            .with_context(|| format!("Invalid public key pin: {}", pin))?;
+
+        self.pins.push(
+            digest
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Public key pin must be a SHA-256 digest: {}", pin))?,
+        );
+
+        Ok(())
+    }
+
+    fn verifier(&self) -> Result<Arc<dyn ServerCertVerifier>> {
+        let provider = rustls::ClientConfig::builder().crypto_provider().clone();
+
+        let verifier: Arc<dyn ServerCertVerifier> = match &self.ca_file {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(path)
+                    .with_context(|| format!("Failed to read CA file {}", path.display()))?
+                {
+                    roots.add(cert?)?;
+                }
+                ensure!(
+                    !roots.is_empty(),
+                    "No certificate found in CA file {}",
+                    path.display()
+                );
+
+                rustls::client::WebPkiServerVerifier::builder_with_provider(
+                    Arc::new(roots),
+                    provider,
+                )
+                .build()?
+            }
+            None => Arc::new(Verifier::new(provider)?),
+        };
+
+        if self.pins.is_empty() {
+            Ok(verifier)
+        } else {
+            Ok(Arc::new(PinnedVerifier {
+                inner: verifier,
+                pins: self.pins.clone(),
+            }))
+        }
+    }
+
+    fn transport(&self) -> Result<TransportConfig> {
+        let mut transport = TransportConfig::default();
+
+        transport
+            .max_idle_timeout(Some(IdleTimeout::try_from(self.idle_timeout)?))
+            .keep_alive_interval(self.keep_alive_interval)
+            .congestion_controller_factory(match self.congestion {
+                Congestion::Cubic => {
+                    Arc::new(CubicConfig::default()) as Arc<dyn ControllerFactory + Send + Sync>
+                }
+                Congestion::Bbr => Arc::new(BbrConfig::default()),
+                Congestion::NewReno => Arc::new(NewRenoConfig::default()),
+            });
+
+        if let Some(streams) = self.max_concurrent_streams {
+            transport.max_concurrent_bidi_streams(streams.into());
+        }
+        if let Some(window) = self.stream_receive_window {
+            transport.stream_receive_window(VarInt::from_u32(window));
+        }
+        if let Some(window) = self.receive_window {
+            transport.receive_window(VarInt::from_u32(window));
+        }
+        if let Some(window) = self.send_window {
+            transport.send_window(window);
+        }
+
+        Ok(transport)
+    }
+
+    fn client_config(&self, alpn_protocols: Vec<Vec<u8>>) -> Result<ClientConfig> {
+        let crypto_config = {
+            let mut config = rustls::ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(self.verifier()?)
+                .with_no_client_auth();
+            config.alpn_protocols = alpn_protocols;
+            Arc::new(QuicClientConfig::try_from(config)?)
+        };
+
+        let mut config = ClientConfig::new(crypto_config);
+        config.transport_config(Arc::new(self.transport()?));
+
+        Ok(config)
+    }
+}
+
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        let cert = EndEntityCert::try_from(end_entity)
+            .map_err(|e| rustls::Error::General(format!("Failed to parse certificate: {}", e)))?;
+        let digest: [u8; 32] = Sha256::digest(cert.subject_public_key_info()).into();
+
+        if self.pins.contains(&digest) {
+            Ok(verified)
+        } else {
+            Err(rustls::Error::General(format!(
+                "Public key sha256//{} is not pinned",
+                BASE64_STANDARD.encode(digest)
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+thread_local! {
+    static ENDPOINT: RefCell<Option<QuicEndpoint>> = const { RefCell::new(None) };
+}
+
+// All client connections share one UDP socket, which is dual stack when the
+// system allows it.
+pub fn shared_endpoint() -> Result<QuicEndpoint> {
+    ENDPOINT.with(|endpoint| {
+        let mut endpoint = endpoint.borrow_mut();
+
+        if let Some(endpoint) = endpoint.as_ref() {
+            return Ok(endpoint.clone());
+        }
+
+        let created = QuicEndpoint::client((Ipv6Addr::UNSPECIFIED, 0).into())
+            .or_else(|_| QuicEndpoint::client((Ipv4Addr::UNSPECIFIED, 0).into()))?;
+        *endpoint = Some(created.clone());
+
+        Ok(created)
+    })
+}
+
+impl Connected for Connection {
+    // IPv4 addresses are mapped to IPv6 on the dual stack endpoint.
+    fn peer_ip(&self) -> Option<IpAddr> {
+        Some(self.remote_address().ip().to_canonical())
+    }
+}
 
 pub async fn create_quic_connection<R: Resolver>(
     server: Endpoint,
     resolver: R,
     alpn_protocols: Vec<Vec<u8>>,
+    options: &QuicOptions,
 ) -> Result<Connection> {
-    match server {
-        // It's too unlikely there will be a ip cert, plus we need to verify if
-        // ring will correctly validate this.
-        Endpoint::Addr(addr) => bail!(
-            "Cannot connect to remote with ip {}, domain is required for certificate validation",
-            addr
-        ),
-        Endpoint::Domain(host, port) => {
-            let addrs = resolver.lookup_ip(&host).await?;
-            let host_ref = &host;
-
-            let crypto_config = {
-                let mut config = rustls::ClientConfig::with_platform_verifier()?;
-                config.alpn_protocols = alpn_protocols;
-                Arc::new(QuicClientConfig::try_from(config)?)
-            };
-
-            let connection = select_ok(addrs.into_iter().map(|addr| {
-                let config = ClientConfig::new(crypto_config.clone());
-
-                async move {
-                    match addr {
-                        std::net::IpAddr::V4(addr_v4) => Ok::<_, anyhow::Error>(
-                            QuicEndpoint::client((std::net::Ipv4Addr::UNSPECIFIED, 0).into())?
-                                .connect_with(config, (addr_v4, port).into(), host_ref)?
-                                .await?,
-                        ),
-                        std::net::IpAddr::V6(addr_v6) => Ok(QuicEndpoint::client(
-                            (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
-                        )?
-                        .connect_with(config, (addr_v6, port).into(), host_ref)?
-                        .await?),
-                    }
-                }
-                .boxed()
-            }))
-            .await?
-            .0;
+    let endpoint = shared_endpoint()?;
+    let config = options.client_config(alpn_protocols)?;
+
+    let connect = |server_name: String| {
+        move |addr: SocketAddr| {
+            let endpoint = endpoint.clone();
+            let config = config.clone();
+            let server_name = server_name.clone();
+
+            async move { Ok(endpoint.connect_with(config, addr, &server_name)?.await?) }.boxed()
+        }
+    };
+
+    match &server {
+        Endpoint::Addr(addr) => {
+            ensure!(
+                options.staggering.family.allows(&addr.ip()),
+                "Connecting to {} is not allowed by {:?}",
+                addr,
+                options.staggering.family
+            );
 
-            Ok(connection)
+            let server_name = options.sni.clone().unwrap_or_else(|| addr.ip().to_string());
+            connect(server_name)(*addr).await
         }
+        Endpoint::Domain(host, port) => {
+            let server_name = options.sni.clone().unwrap_or_else(|| host.clone());
+            HappyEyeballConnector::new(
+                &resolver,
+                host,
+                *port,
+                Transport::Quic,
+                options.staggering,
+                connect(server_name),
+            )
+            .await
+        }
+    }
+    .with_context(|| format!("Failed to connect to QUIC server {}", server))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shared_endpoint() -> Result<()> {
+        let first = shared_endpoint()?;
+        let second = shared_endpoint()?;
+
+        assert_eq!(first.local_addr()?, second.local_addr()?);
+
+        Ok(())
     }
 }
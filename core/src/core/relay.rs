@@ -0,0 +1,296 @@
+use anyhow::{bail, Context, Result};
+use futures::FutureExt;
+use std::{cell::Cell, time::Duration};
+use tokio::{
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::{sleep_until, timeout, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+const BUFFER_SIZE: usize = 16 * 1024;
+
+// How long to wait for each side to take the close when the relay is closed.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RelayOptions {
+    // Close the connection if no data is sent in either direction for this long.
+    pub idle_timeout: Option<Duration>,
+    // Close the connection if the other direction is still open this long
+    // after one direction finishes.
+    pub half_close_timeout: Option<Duration>,
+}
+
+/// Bytes forwarded by a relay so far, up is from local to remote.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    up: Cell<u64>,
+    down: Cell<u64>,
+}
+
+impl Traffic {
+    pub fn up(&self) -> u64 {
+        self.up.get()
+    }
+
+    pub fn down(&self) -> u64 {
+        self.down.get()
+    }
+
+    // For traffic that isn't relayed by `relay`, e.g. UDP.
+    pub fn add_up(&self, len: u64) {
+        self.up.set(self.up.get() + len);
+    }
+
+    pub fn add_down(&self, len: u64) {
+        self.down.set(self.down.get() + len);
+    }
+}
+
+async fn pipe(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    last_activity: &Cell<Instant>,
+    forwarded: &Cell<u64>,
+) -> std::io::Result<()> {
+    let mut buf = vec![0; BUFFER_SIZE];
+
+    loop {
+        let len = reader.read(&mut buf).await?;
+        if len == 0 {
+            return writer.shutdown().await;
+        }
+        last_activity.set(Instant::now());
+
+        writer.write_all(&buf[..len]).await?;
+        writer.flush().await?;
+        last_activity.set(Instant::now());
+        forwarded.set(forwarded.get() + len as u64);
+    }
+}
+
+/// Like `copy_bidirectional`, but closes the connection when it stays idle or
+/// half closed for too long. Bytes are counted in `traffic` even if it fails.
+///
+/// Once `closing` is cancelled, both sides are shut down for writing so they
+/// see the end of the stream, and the relay fails.
+pub async fn relay(
+    local: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    remote: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    options: &RelayOptions,
+    traffic: &Traffic,
+    closing: &CancellationToken,
+) -> Result<()> {
+    let (mut local_reader, mut local_writer) = split(local);
+    let (mut remote_reader, mut remote_writer) = split(remote);
+
+    let last_activity = Cell::new(Instant::now());
+    let mut uplink = pipe(
+        &mut local_reader,
+        &mut remote_writer,
+        &last_activity,
+        &traffic.up,
+    )
+    .fuse()
+    .boxed_local();
+    let mut downlink = pipe(
+        &mut remote_reader,
+        &mut local_writer,
+        &last_activity,
+        &traffic.down,
+    )
+    .fuse()
+    .boxed_local();
+
+    let mut uplink_done = false;
+    let mut downlink_done = false;
+    let mut half_closed_at = None;
+
+    while !(uplink_done && downlink_done) {
+        let idle_deadline = options
+            .idle_timeout
+            .map(|timeout| last_activity.get() + timeout);
+        let half_close_deadline = half_closed_at
+            .zip(options.half_close_timeout)
+            .map(|(at, timeout)| at + timeout);
+        let deadline = idle_deadline.into_iter().chain(half_close_deadline).min();
+
+        tokio::select! {
+            result = &mut uplink, if !uplink_done => {
+                result.context("Failed to forward data to remote")?;
+                uplink_done = true;
+                half_closed_at.get_or_insert_with(Instant::now);
+            }
+            result = &mut downlink, if !downlink_done => {
+                result.context("Failed to forward data to local")?;
+                downlink_done = true;
+                half_closed_at.get_or_insert_with(Instant::now);
+            }
+            _ = closing.cancelled() => {
+                drop(uplink);
+                drop(downlink);
+
+                let _ = tokio::join!(
+                    timeout(CLOSE_TIMEOUT, local_writer.shutdown()),
+                    timeout(CLOSE_TIMEOUT, remote_writer.shutdown())
+                );
+
+                bail!("Connection is closed by the proxy");
+            }
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                let now = Instant::now();
+
+                if let Some(timeout) = options.half_close_timeout {
+                    if half_close_deadline.is_some_and(|d| d <= now) {
+                        bail!("Connection stayed half closed for {:?}", timeout);
+                    }
+                }
+
+                if let Some(timeout) = options.idle_timeout {
+                    if last_activity.get() + timeout <= now {
+                        bail!("Connection was idle for {:?}", timeout);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn test_relay() -> Result<()> {
+        let (mut local, mut local_peer) = duplex(1024);
+        let (mut remote, mut remote_peer) = duplex(1024);
+
+        let client = async {
+            local.write_all(b"ping").await?;
+            let mut buf = [0; 4];
+            remote.read_exact(&mut buf).await?;
+            assert_eq!(&buf, b"ping");
+
+            remote.write_all(b"pong!").await?;
+            let mut buf = [0; 5];
+            local.read_exact(&mut buf).await?;
+            assert_eq!(&buf, b"pong!");
+
+            local.shutdown().await?;
+            remote.shutdown().await?;
+
+            anyhow::Ok(())
+        };
+
+        let options = RelayOptions::default();
+        let traffic = Traffic::default();
+        let closing = CancellationToken::new();
+        let (relayed, client) = tokio::join!(
+            relay(
+                &mut local_peer,
+                &mut remote_peer,
+                &options,
+                &traffic,
+                &closing
+            ),
+            client
+        );
+        relayed?;
+        client?;
+
+        assert_eq!((traffic.up(), traffic.down()), (4, 5));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() -> Result<()> {
+        let (_local, mut local_peer) = duplex(1024);
+        let (_remote, mut remote_peer) = duplex(1024);
+
+        let options = RelayOptions {
+            idle_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+
+        let error = relay(
+            &mut local_peer,
+            &mut remote_peer,
+            &options,
+            &Traffic::default(),
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("idle"), "{:?}", error);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_half_close_timeout() -> Result<()> {
+        let (mut local, mut local_peer) = duplex(1024);
+        let (_remote, mut remote_peer) = duplex(1024);
+
+        let options = RelayOptions {
+            half_close_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+
+        local.shutdown().await?;
+
+        let error = relay(
+            &mut local_peer,
+            &mut remote_peer,
+            &options,
+            &Traffic::default(),
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("half closed"), "{:?}", error);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_closing() -> Result<()> {
+        let (mut local, mut local_peer) = duplex(1024);
+        let (mut remote, mut remote_peer) = duplex(1024);
+
+        let options = RelayOptions::default();
+        let traffic = Traffic::default();
+        let closing = CancellationToken::new();
+        let client = async {
+            local.write_all(b"ping").await?;
+            let mut buf = [0; 4];
+            remote.read_exact(&mut buf).await?;
+
+            closing.cancel();
+
+            // Both sides see the end of the stream.
+            assert_eq!(local.read(&mut buf).await?, 0);
+            assert_eq!(remote.read(&mut buf).await?, 0);
+
+            anyhow::Ok(())
+        };
+
+        let (relayed, client) = tokio::join!(
+            relay(
+                &mut local_peer,
+                &mut remote_peer,
+                &options,
+                &traffic,
+                &closing
+            ),
+            client
+        );
+        assert!(relayed.is_err());
+        client?;
+
+        Ok(())
+    }
+}
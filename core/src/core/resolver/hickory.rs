@@ -1,10 +1,13 @@
 use super::Resolver;
 use crate::Result;
 use anyhow::bail;
-use hickory_proto::op::{Message, MessageType};
+use hickory_proto::{
+    op::{Message, MessageType},
+    rr::RData,
+};
 use hickory_resolver::{
     config::{NameServerConfig, ResolverConfig, ResolverOpts},
-    name_server::TokioConnectionProvider,
+    net::runtime::TokioRuntimeProvider,
     TokioResolver,
 };
 use std::{
@@ -14,11 +17,16 @@ use std::{
 
 #[derive(Debug)]
 pub struct HickoryResolver {
+    name: String,
     client: TokioResolver,
 }
 
 impl HickoryResolver {
-    pub fn new(nameservers: Vec<NameServerConfig>, timeout: Duration) -> Result<Self> {
+    pub fn new(
+        name: impl Into<String>,
+        nameservers: Vec<NameServerConfig>,
+        timeout: Duration,
+    ) -> Result<Self> {
         let mut options = ResolverOpts::default();
         options.timeout = timeout;
 
@@ -28,17 +36,22 @@ impl HickoryResolver {
         }
 
         Ok(Self {
-            client: TokioResolver::builder_with_config(config, TokioConnectionProvider::default())
+            name: name.into(),
+            client: TokioResolver::builder_with_config(config, TokioRuntimeProvider::default())
                 .with_options(options)
-                .build(),
+                .build()?,
         })
     }
 }
 
 #[async_trait::async_trait]
 impl Resolver for HickoryResolver {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
     async fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>> {
-        Ok(self.client.lookup_ip(name).await?.into_iter().collect()).and_then(|r: Vec<IpAddr>| {
+        Ok(self.client.lookup_ip(name).await?.iter().collect()).and_then(|r: Vec<IpAddr>| {
             if r.is_empty() {
                 bail!("Failed to find result for domain {}", name)
             } else {
@@ -52,8 +65,12 @@ impl Resolver for HickoryResolver {
             .client
             .ipv4_lookup(name)
             .await?
-            .into_iter()
-            .map(Into::into)
+            .answers()
+            .iter()
+            .filter_map(|r| match r.data {
+                RData::A(a) => Some(a.0),
+                _ => None,
+            })
             .collect())
         .and_then(|r: Vec<Ipv4Addr>| {
             if r.is_empty() {
@@ -69,8 +86,12 @@ impl Resolver for HickoryResolver {
             .client
             .ipv6_lookup(name)
             .await?
-            .into_iter()
-            .map(Into::into)
+            .answers()
+            .iter()
+            .filter_map(|r| match r.data {
+                RData::AAAA(a) => Some(a.0),
+                _ => None,
+            })
             .collect())
         .and_then(|r: Vec<Ipv6Addr>| {
             if r.is_empty() {
@@ -87,7 +108,7 @@ impl Resolver for HickoryResolver {
 
     async fn lookup_raw(&self, mut message: Message) -> Result<Message> {
         let query = message
-            .queries()
+            .queries
             .first()
             .to_owned()
             .ok_or_else(|| anyhow::anyhow!("Receive DNS request with no query item"))?;
@@ -97,9 +118,8 @@ impl Resolver for HickoryResolver {
             .lookup(query.name().clone(), query.query_type())
             .await?;
 
-        message
-            .add_answers(result.record_iter().cloned())
-            .set_message_type(MessageType::Response);
+        message.add_answers(result.answers().iter().cloned());
+        message.metadata.message_type = MessageType::Response;
 
         Ok(message)
     }
@@ -108,25 +128,18 @@ impl Resolver for HickoryResolver {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use hickory_proto::xfer::Protocol;
+    use hickory_proto::rr::Name;
     use hickory_proto::{
         op::{MessageType, OpCode, Query},
         rr::RecordType,
     };
-    use hickory_resolver::Name;
     use std::str::FromStr;
 
     #[tokio::test]
     async fn resolve() -> Result<()> {
         let resolver = HickoryResolver::new(
-            vec![NameServerConfig {
-                socket_addr: "8.8.8.8:53".parse().unwrap(),
-                protocol: Protocol::Udp,
-                tls_dns_name: None,
-                http_endpoint: None,
-                trust_negative_responses: true,
-                bind_addr: None,
-            }],
+            "google",
+            vec![NameServerConfig::udp("8.8.8.8".parse().unwrap())],
             Duration::from_secs(5),
         )?;
 
@@ -134,19 +147,19 @@ mod tests {
         assert!(!resolver.lookup_ipv4("apple.com").await?.is_empty());
         assert!(!resolver.lookup_ipv6("facebook.com").await?.is_empty());
 
-        let mut message = Message::new();
-        message.set_op_code(OpCode::Query);
-        message.set_message_type(MessageType::Query);
+        let mut message = Message::new(0, MessageType::Query, OpCode::Query);
+        message.metadata.op_code = OpCode::Query;
+        message.metadata.message_type = MessageType::Query;
         let query = Query::query(Name::from_str("apple.com").unwrap(), RecordType::A);
         message.add_query(query);
-        assert!(!resolver.lookup_raw(message).await?.answers().is_empty());
+        assert!(!resolver.lookup_raw(message).await?.answers.is_empty());
 
-        let mut message = Message::new();
-        message.set_op_code(OpCode::Query);
-        message.set_message_type(MessageType::Query);
+        let mut message = Message::new(0, MessageType::Query, OpCode::Query);
+        message.metadata.op_code = OpCode::Query;
+        message.metadata.message_type = MessageType::Query;
         let query = Query::query(Name::from_str("gmail.com").unwrap(), RecordType::MX);
         message.add_query(query);
-        assert!(!resolver.lookup_raw(message).await?.answers().is_empty());
+        assert!(!resolver.lookup_raw(message).await?.answers.is_empty());
 
         Ok(())
     }
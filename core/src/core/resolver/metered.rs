@@ -0,0 +1,78 @@
+use super::Resolver;
+use crate::{core::metrics::METRICS, Result};
+use futures::Future;
+use hickory_proto::op::Message;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use tokio::time::Instant;
+
+/// Records how long the lookups of the inner resolver take under `name`.
+#[derive(Debug)]
+pub struct Metered<R: Resolver> {
+    name: String,
+    inner: R,
+}
+
+impl<R: Resolver> Metered<R> {
+    pub fn new(name: impl Into<String>, inner: R) -> Self {
+        Self {
+            name: name.into(),
+            inner,
+        }
+    }
+
+    async fn measure<T>(&self, lookup: impl Future<Output = Result<T>>) -> Result<T> {
+        let start = Instant::now();
+        let result = lookup.await;
+        METRICS.dns_lookup(&self.name, start.elapsed());
+
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl<R: Resolver + Send + Sync> Resolver for Metered<R> {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    async fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>> {
+        self.measure(self.inner.lookup_ip(name)).await
+    }
+
+    async fn lookup_ipv4(&self, name: &str) -> Result<Vec<Ipv4Addr>> {
+        self.measure(self.inner.lookup_ipv4(name)).await
+    }
+
+    async fn lookup_ipv6(&self, name: &str) -> Result<Vec<Ipv6Addr>> {
+        self.measure(self.inner.lookup_ipv6(name)).await
+    }
+
+    fn support_raw(&self) -> bool {
+        self.inner.support_raw()
+    }
+
+    async fn lookup_raw(&self, message: Message) -> Result<Message> {
+        self.measure(self.inner.lookup_raw(message)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::resolver::system::SystemResolver;
+
+    #[tokio::test]
+    async fn test_metered() -> Result<()> {
+        let resolver = Metered::new("test_metered", SystemResolver::new());
+
+        assert_eq!(
+            resolver.lookup_ip("127.0.0.1").await?,
+            vec![IpAddr::from([127, 0, 0, 1])]
+        );
+        assert!(METRICS
+            .encode()?
+            .contains(r#"dandelion_dns_lookup_duration_seconds_count{resolver="test_metered"} 1"#));
+
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
 pub mod hickory;
+pub mod metered;
 pub mod system;
 
 use crate::Result;
@@ -17,6 +18,9 @@ pub trait Resolver: Debug {
     async fn lookup_ipv4(&self, name: &str) -> Result<Vec<Ipv4Addr>>;
     async fn lookup_ipv6(&self, name: &str) -> Result<Vec<Ipv6Addr>>;
 
+    // Resolvers with the same name are expected to give the same answers.
+    fn name(&self) -> &str;
+
     fn support_raw(&self) -> bool;
     async fn lookup_raw(&self, _message: Message) -> Result<Message> {
         bail!("Raw queries are not supported by this resolver")
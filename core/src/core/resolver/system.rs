@@ -8,6 +8,10 @@ pub struct SystemResolver {}
 
 #[async_trait::async_trait]
 impl Resolver for SystemResolver {
+    fn name(&self) -> &str {
+        "system"
+    }
+
     async fn lookup_ip(&self, name: &str) -> Result<Vec<IpAddr>> {
         let name = name.to_owned();
         Ok(tokio::task::spawn_blocking(move || lookup_host(&name))
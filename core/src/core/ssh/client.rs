@@ -0,0 +1,118 @@
+use super::SshSession;
+use crate::{
+    core::{connector::tcp::connect as tcp_connect, endpoint::Endpoint, resolver::Resolver},
+    Result,
+};
+use anyhow::{bail, ensure, Context};
+use ssh2::{CheckResult, KnownHostFileKind, Session};
+use std::{path::PathBuf, sync::Arc};
+
+#[derive(Debug, Clone)]
+pub enum Credential {
+    Password(String),
+    PrivateKey {
+        path: PathBuf,
+        passphrase: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Auth {
+    username: String,
+    credential: Credential,
+    known_hosts: PathBuf,
+}
+
+impl Auth {
+    pub fn new(username: String, credential: Credential, known_hosts: PathBuf) -> Self {
+        Self {
+            username,
+            credential,
+            known_hosts,
+        }
+    }
+}
+
+pub async fn create_ssh_session<R: Resolver>(
+    server: Endpoint,
+    resolver: R,
+    auth: Auth,
+) -> Result<Arc<SshSession>> {
+    let stream = tcp_connect(&server, resolver)
+        .await
+        .with_context(|| format!("Failed to connect to SSH server {}", server))?
+        .into_std()?;
+
+    let mut session = Session::new().context("Failed to create SSH session")?;
+    session.set_tcp_stream(stream.try_clone()?);
+    session.set_blocking(false);
+
+    let session = Arc::new(SshSession::new(session, stream)?);
+
+    // `handshake` needs `&mut Session`, but `Session` is only a handle to the
+    // shared libssh2 session, so a clone drives the same one.
+    session
+        .run(|s| {
+            let mut s = s.clone();
+            s.handshake()
+        })
+        .await
+        .with_context(|| format!("SSH handshake with {} failed", server))?;
+
+    verify_host_key(&session, &server, &auth)?;
+
+    match &auth.credential {
+        Credential::Password(password) => {
+            session
+                .run(|s| s.userauth_password(&auth.username, password))
+                .await
+        }
+        Credential::PrivateKey { path, passphrase } => {
+            session
+                .run(|s| s.userauth_pubkey_file(&auth.username, None, path, passphrase.as_deref()))
+                .await
+        }
+    }
+    .with_context(|| format!("SSH authentication as {} failed", auth.username))?;
+
+    ensure!(
+        session.session().authenticated(),
+        "SSH server {} did not accept authentication for {}",
+        server,
+        auth.username
+    );
+
+    Ok(session)
+}
+
+fn verify_host_key(session: &SshSession, server: &Endpoint, auth: &Auth) -> Result<()> {
+    let mut known_hosts = session.session().known_hosts()?;
+    known_hosts
+        .read_file(&auth.known_hosts, KnownHostFileKind::OpenSSH)
+        .with_context(|| {
+            format!(
+                "Failed to read known hosts file {}",
+                auth.known_hosts.display()
+            )
+        })?;
+
+    let (key, _) = session
+        .session()
+        .host_key()
+        .ok_or_else(|| anyhow::anyhow!("SSH server {} didn't provide a host key", server))?;
+
+    match known_hosts.check_port(&server.hostname(), server.port(), key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound => bail!(
+            "Host key of SSH server {} is not found in {}",
+            server,
+            auth.known_hosts.display()
+        ),
+        CheckResult::Mismatch => bail!(
+            "Host key of SSH server {} does not match the one in {}, someone could be eavesdropping",
+            server,
+            auth.known_hosts.display()
+        ),
+        CheckResult::Failure => bail!("Failed to check host key of SSH server {}", server),
+    }
+}
@@ -0,0 +1,228 @@
+pub mod client;
+
+use futures::{future::BoxFuture, FutureExt};
+use ssh2::{BlockDirections, Channel, ErrorCode, Session};
+use std::{
+    fmt::Debug,
+    io::{Read, Write},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::Poll,
+};
+use sync_wrapper::SyncWrapper;
+use tokio::{
+    io::{unix::AsyncFd, AsyncRead, AsyncWrite},
+    sync::Notify,
+};
+
+// libssh2 is driven in non-blocking mode and every channel shares the same
+// socket. When one channel reads from the socket, libssh2 may buffer packets
+// that belong to other channels, which then will never see the socket become
+// readable again. So whoever consumes a readiness event bumps the generation
+// and wakes everyone else up to retry.
+pub struct SshSession {
+    session: Session,
+    fd: AsyncFd<TcpStream>,
+    generation: AtomicU64,
+    notify: Notify,
+}
+
+impl Debug for SshSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SshSession")
+            .field("fd", &self.fd)
+            .field("authenticated", &self.session.authenticated())
+            .finish()
+    }
+}
+
+// ssh2 doesn't re-export libssh2-sys, this is `LIBSSH2_ERROR_EAGAIN`.
+const LIBSSH2_ERROR_EAGAIN: i32 = -37;
+
+fn would_block(error: &ssh2::Error) -> bool {
+    error.code() == ErrorCode::Session(LIBSSH2_ERROR_EAGAIN)
+}
+
+impl SshSession {
+    fn new(session: Session, socket: TcpStream) -> std::io::Result<Self> {
+        Ok(Self {
+            session,
+            fd: AsyncFd::new(socket)?,
+            generation: AtomicU64::new(0),
+            notify: Notify::new(),
+        })
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    async fn wait(self: Arc<Self>, seen: u64) -> std::io::Result<()> {
+        let notified = self.notify.notified();
+
+        // Some other operation already made progress since we tried.
+        if self.generation() != seen {
+            return Ok(());
+        }
+
+        let readiness = async {
+            match self.session.block_directions() {
+                BlockDirections::Outbound => self.fd.writable().await?.clear_ready(),
+                BlockDirections::Both => {
+                    tokio::select! {
+                        guard = self.fd.readable() => guard?.clear_ready(),
+                        guard = self.fd.writable() => guard?.clear_ready(),
+                    }
+                }
+                // When libssh2 doesn't tell us, it's waiting for the server,
+                // e.g., a window adjust message.
+                BlockDirections::Inbound | BlockDirections::None => {
+                    self.fd.readable().await?.clear_ready()
+                }
+            }
+
+            std::io::Result::Ok(())
+        };
+
+        tokio::select! {
+            _ = notified => Ok(()),
+            result = readiness => {
+                result?;
+                self.generation.fetch_add(1, Ordering::AcqRel);
+                self.notify.notify_waiters();
+                Ok(())
+            }
+        }
+    }
+
+    /// Run a libssh2 operation until it no longer blocks.
+    pub async fn run<T>(
+        self: &Arc<Self>,
+        mut op: impl FnMut(&Session) -> Result<T, ssh2::Error>,
+    ) -> crate::Result<T> {
+        loop {
+            let seen = self.generation();
+
+            match op(&self.session) {
+                Err(e) if would_block(&e) => self.clone().wait(seen).await?,
+                result => return Ok(result?),
+            }
+        }
+    }
+}
+
+pub struct SshStream {
+    channel: Channel,
+    session: Arc<SshSession>,
+    read_wait: Option<SyncWrapper<BoxFuture<'static, std::io::Result<()>>>>,
+    write_wait: Option<SyncWrapper<BoxFuture<'static, std::io::Result<()>>>>,
+}
+
+impl Debug for SshStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SshStream")
+            .field("session", &self.session)
+            .finish()
+    }
+}
+
+impl SshStream {
+    pub fn new(channel: Channel, session: Arc<SshSession>) -> Self {
+        Self {
+            channel,
+            session,
+            read_wait: None,
+            write_wait: None,
+        }
+    }
+
+    // Poll a non-blocking channel operation, parking on the session when it
+    // would block.
+    fn poll_op<T>(
+        session: &Arc<SshSession>,
+        wait: &mut Option<SyncWrapper<BoxFuture<'static, std::io::Result<()>>>>,
+        cx: &mut std::task::Context<'_>,
+        mut op: impl FnMut() -> std::io::Result<T>,
+    ) -> Poll<std::io::Result<T>> {
+        loop {
+            if let Some(fut) = wait.as_mut() {
+                futures::ready!(fut.get_mut().poll_unpin(cx))?;
+                *wait = None;
+            }
+
+            let seen = session.generation();
+
+            match op() {
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    *wait = Some(SyncWrapper::new(session.clone().wait(seen).boxed()));
+                }
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+}
+
+impl AsyncRead for SshStream {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let channel = &mut this.channel;
+
+        let len = futures::ready!(Self::poll_op(
+            &this.session,
+            &mut this.read_wait,
+            cx,
+            || channel.read(buf.initialize_unfilled())
+        ))?;
+        buf.advance(len);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for SshStream {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let channel = &mut this.channel;
+
+        Self::poll_op(&this.session, &mut this.write_wait, cx, || {
+            channel.write(buf)
+        })
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let channel = &mut this.channel;
+
+        Self::poll_op(&this.session, &mut this.write_wait, cx, || channel.flush())
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let channel = &mut this.channel;
+
+        Self::poll_op(&this.session, &mut this.write_wait, cx, || {
+            channel.send_eof().map_err(Into::into)
+        })
+    }
+}
@@ -0,0 +1,217 @@
+use crate::core::io::Io;
+use futures::{ready, Future};
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Instant, Sleep},
+};
+
+// Don't wake up for less than this, unless the bucket is smaller.
+const MIN_CHUNK: f64 = 4096.0;
+
+// Token bucket holding at most one second worth of bytes.
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    // Bytes that may pass now, or how long to wait for them. Tokens go
+    // negative when connections sharing the bucket take more than there is,
+    // which they pay back by waiting longer.
+    fn available(&mut self) -> Result<usize, Duration> {
+        let now = Instant::now();
+        self.tokens = (self.tokens + (now - self.updated).as_secs_f64() * self.rate).min(self.rate);
+        self.updated = now;
+
+        let chunk = MIN_CHUNK.min(self.rate);
+        if self.tokens >= chunk {
+            Ok(self.tokens as usize)
+        } else {
+            Err(Duration::from_secs_f64((chunk - self.tokens) / self.rate))
+        }
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// Caps the rates of all the connections throttled by it together, in bytes
+/// per second. Up is what's written to the connections, down is what's read
+/// from them.
+#[derive(Debug)]
+pub struct Limiter {
+    up: Option<Mutex<Bucket>>,
+    down: Option<Mutex<Bucket>>,
+}
+
+impl Limiter {
+    pub fn new(up: Option<u64>, down: Option<u64>) -> Self {
+        Self {
+            up: up.map(|rate| Mutex::new(Bucket::new(rate))),
+            down: down.map(|rate| Mutex::new(Bucket::new(rate))),
+        }
+    }
+}
+
+// Wait until the bucket has tokens, returning how many bytes may pass.
+fn poll_tokens(
+    bucket: &Mutex<Bucket>,
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    cx: &mut Context<'_>,
+) -> Poll<usize> {
+    loop {
+        if let Some(sleep) = delay {
+            ready!(sleep.as_mut().poll(cx));
+            *delay = None;
+        }
+
+        match bucket
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .available()
+        {
+            Ok(bytes) => return Poll::Ready(bytes),
+            Err(wait) => *delay = Some(Box::pin(sleep(wait))),
+        }
+    }
+}
+
+fn consume(bucket: &Mutex<Bucket>, bytes: usize) {
+    bucket
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .consume(bytes);
+}
+
+#[derive(Debug)]
+#[pin_project::pin_project]
+pub struct Throttled<I: Io> {
+    #[pin]
+    inner: I,
+    limiter: Arc<Limiter>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<I: Io> Throttled<I> {
+    pub fn new(inner: I, limiter: Arc<Limiter>) -> Self {
+        Self {
+            inner,
+            limiter,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+}
+
+impl<I: Io> AsyncRead for Throttled<I> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        let Some(bucket) = &this.limiter.down else {
+            return this.inner.poll_read(cx, buf);
+        };
+
+        let allowed = ready!(poll_tokens(bucket, this.read_delay, cx)).min(buf.remaining());
+
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(allowed));
+        ready!(this.inner.poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+
+        buf.advance(read);
+        consume(bucket, read);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<I: Io> AsyncWrite for Throttled<I> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        let Some(bucket) = &this.limiter.up else {
+            return this.inner.poll_write(cx, buf);
+        };
+
+        let allowed = ready!(poll_tokens(bucket, this.write_delay, cx)).min(buf.len());
+
+        let written = ready!(this.inner.poll_write(cx, &buf[..allowed]))?;
+        consume(bucket, written);
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Result;
+    use rstest::rstest;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    const RATE: u64 = 100_000;
+
+    // The first second worth is sent right away, the rest at the rate.
+    #[rstest]
+    #[case(Some(RATE), None)]
+    #[case(None, Some(RATE))]
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle(#[case] up: Option<u64>, #[case] down: Option<u64>) -> Result<()> {
+        let limiter = Arc::new(Limiter::new(up, down));
+        let data = vec![0; (RATE * 3 / 4) as usize];
+
+        let start = Instant::now();
+
+        // Two connections share the limiter.
+        for _ in 0..2 {
+            let (client, mut server) = duplex(1 << 20);
+            let mut client = Throttled::new(client, limiter.clone());
+
+            if up.is_some() {
+                client.write_all(&data).await?;
+            } else {
+                server.write_all(&data).await?;
+                client.read_exact(&mut vec![0; data.len()]).await?;
+            }
+        }
+
+        let elapsed = start.elapsed();
+        // The clock only moves when everything waits on it, so this is exact
+        // but for the rounding of timers.
+        assert!(elapsed >= Duration::from_millis(500), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(600), "{:?}", elapsed);
+
+        Ok(())
+    }
+}
@@ -76,6 +76,10 @@ where
     pub fn get_reverse(&mut self, value: &ValueItem) -> Option<KeyItem> {
         self.reverse_mapping.get(value).cloned()
     }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&KeyItem, &ValueItem)> {
+        self.mapping.iter()
+    }
 }
 
 enum IpType {
@@ -124,6 +128,19 @@ impl FakeDnsResolver {
         })
     }
 
+    // All the names with a fake IP assigned, without refreshing them.
+    pub fn mappings(&self) -> Vec<(String, IpAddr)> {
+        self.ipv4_mapping
+            .iter()
+            .map(|(name, ip)| (name.clone(), (*ip).into()))
+            .chain(
+                self.ipv6_mapping
+                    .iter()
+                    .map(|(name, ip)| (name.clone(), (*ip).into())),
+            )
+            .collect()
+    }
+
     pub fn lookup_ptr<T: Into<IpAddr>>(&mut self, addr: T) -> Option<String> {
         match addr.into() {
             IpAddr::V4(addr) => self.ipv4_mapping.get_reverse(&addr),
@@ -155,6 +172,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_mappings() {
+        let mut resolver = FakeDnsResolver::new(
+            LinkedList::from(["10.0.0.1".parse().unwrap()]),
+            LinkedList::from(["fd00::1".parse().unwrap()]),
+        );
+
+        resolver.lookup_ipv4("example.com");
+        resolver.lookup_ipv6("example.com");
+
+        let mut mappings = resolver.mappings();
+        mappings.sort();
+        assert_eq!(
+            mappings,
+            [
+                ("example.com".to_owned(), "10.0.0.1".parse().unwrap()),
+                ("example.com".to_owned(), "fd00::1".parse().unwrap()),
+            ]
+        );
+    }
+
     #[test]
     fn test_pool_with_bidirectional_mapping() {
         let network = Ipv4Network::try_from("10.0.0.1/24").unwrap();